@@ -0,0 +1,20 @@
+/* The flash layout has to match ../memory.x */
+MEMORY {
+    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 24K - 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    ACTIVE : ORIGIN = 0x10007000, LENGTH = 896K
+    DFU : ORIGIN = 0x100E7000, LENGTH = 900K
+    /* Left to the application's crash dump */
+    RAM : ORIGIN = 0x20000100, LENGTH = 256K - 256
+}
+
+/* Offsets from the start of the flash, as embassy-boot expects them */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(BOOT2);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
@@ -0,0 +1,51 @@
+//! Starts the application from the active partition. If the application has received a firmware update, it is swapped
+//! in first. If a new firmware restarts before it confirmed that it works, the previous one is swapped back in.
+//! The partitions are defined in memory.x.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_rp::{BootLoader, BootLoaderConfig, WatchdogFlash};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+// Swapping a sector takes far less. The application keeps the watchdog running with the same timeout.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_rp::init(Default::default());
+
+    // A power loss in the middle of a swap is fine, it continues on the next start. If the swap hangs, the watchdog
+    // resets us and it continues as well.
+    let flash = WatchdogFlash::<FLASH_SIZE>::start(p.FLASH, p.WATCHDOG, WATCHDOG_TIMEOUT);
+    let flash = Mutex::new(RefCell::new(flash));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+    let bootloader: BootLoader = BootLoader::prepare(config);
+
+    unsafe { bootloader.load(embassy_rp::flash::FLASH_BASE as u32 + active_offset) }
+}
+
+// Restarting is the only sensible thing to do, if anything goes wrong. The swap continues where it left off.
+
+#[no_mangle]
+#[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
@@ -1,5 +1,50 @@
+/* The flash layout has to match bootloader/memory.x */
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
-}
\ No newline at end of file
+    /* The bootloader comes first, followed by the state partition it takes its instructions from */
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    /* The application, which the bootloader calls the active partition */
+    FLASH : ORIGIN = 0x10007000, LENGTH = 896K
+    /* A firmware update received over the air is kept here until the bootloader swaps it in. The bootloader needs
+       one sector more than the application takes. */
+    DFU : ORIGIN = 0x100E7000, LENGTH = 900K
+    /* The load profile, saved in turns to the 8 K slots of this region (see src/load_profile.rs) */
+    LOAD_PROFILE : ORIGIN = 0x101C8000, LENGTH = 64K
+    /* The reserved uplink frame counter, saved in turns to the 4 K slots of this region (see src/network.rs) */
+    FRAME_COUNTER : ORIGIN = 0x101D8000, LENGTH = 16K
+    /* The readings not known to have arrived, saved in turns to the 4 K slots of this region (see src/backlog.rs) */
+    READINGS : ORIGIN = 0x101DC000, LENGTH = 32K
+    /* The persistent state, saved in turns to the 4 K slots of this region (see src/main.rs) */
+    STATE : ORIGIN = 0x101E4000, LENGTH = 32K
+    /* The rest of the flash is where firmwares before the state region kept the persistent state, it is only read to
+       move it to the state region */
+    RAM   : ORIGIN = 0x20000100, LENGTH = 256K - 256
+    /* Where a crash is recorded (see src/crash.rs). Not initialized on startup and not used by the bootloader, so it
+       survives the reset. */
+    CRASH_DUMP : ORIGIN = 0x20000000, LENGTH = 256
+}
+
+SECTIONS {
+    .crash_dump (NOLOAD) : ALIGN(4) {
+        KEEP(*(.crash_dump .crash_dump.*));
+    } > CRASH_DUMP
+} INSERT AFTER .bss;
+
+/* Offsets from the start of the flash, as embassy-boot expects them */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
+
+__load_profile_start = ORIGIN(LOAD_PROFILE) - ORIGIN(BOOT2);
+__load_profile_end = ORIGIN(LOAD_PROFILE) + LENGTH(LOAD_PROFILE) - ORIGIN(BOOT2);
+
+__frame_counter_start = ORIGIN(FRAME_COUNTER) - ORIGIN(BOOT2);
+__frame_counter_end = ORIGIN(FRAME_COUNTER) + LENGTH(FRAME_COUNTER) - ORIGIN(BOOT2);
+
+__readings_start = ORIGIN(READINGS) - ORIGIN(BOOT2);
+__readings_end = ORIGIN(READINGS) + LENGTH(READINGS) - ORIGIN(BOOT2);
+
+__state_start = ORIGIN(STATE) - ORIGIN(BOOT2);
+__state_end = ORIGIN(STATE) + LENGTH(STATE) - ORIGIN(BOOT2);
@@ -0,0 +1,187 @@
+use crate::codec::{signed_varint_size, varint_size, Reader, Writer};
+use crate::{Error, FORMAT_VERSION, S0_CHANNEL_COUNT};
+
+/// The FPort alarms are sent on, right away instead of with the next measurement
+pub const ALARM_FPORT: u8 = 20;
+
+/// The most events an alarm message holds, one per alarm the device checks
+pub const MAX_ALARM_EVENTS: usize = 4 + 2 * S0_CHANNEL_COUNT;
+
+const TEMPERATURE_HIGH: u8 = 0x01;
+const TEMPERATURE_LOW: u8 = 0x02;
+const METER_UNREACHABLE: u8 = 0x03;
+const BATTERY_LOW: u8 = 0x04;
+const S0_STUCK: u8 = 0x10;
+const S0_RATE_HIGH: u8 = 0x18;
+const CLEARED: u8 = 0x80;
+
+/// What an alarm is about. The value of its events is given for each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AlarmKind {
+    /// 0x01: the temperature, in 0.1 °C
+    TemperatureHigh,
+    /// 0x02: the temperature, in 0.1 °C
+    TemperatureLow,
+    /// 0x03: the meter reads that failed in a row
+    MeterUnreachable,
+    /// 0x04: the battery level, in %
+    BatteryLow,
+    /// 0x10 to 0x15: S0 channel 0 to 5 had no impulse for too long. The hours since the last impulse.
+    S0Stuck(usize),
+    /// 0x18 to 0x1D: S0 channel 0 to 5 counts more impulses than expected. The impulses per hour.
+    S0RateHigh(usize),
+}
+
+impl AlarmKind {
+    fn to_byte(self) -> Result<u8, Error> {
+        match self {
+            AlarmKind::TemperatureHigh => Ok(TEMPERATURE_HIGH),
+            AlarmKind::TemperatureLow => Ok(TEMPERATURE_LOW),
+            AlarmKind::MeterUnreachable => Ok(METER_UNREACHABLE),
+            AlarmKind::BatteryLow => Ok(BATTERY_LOW),
+            AlarmKind::S0Stuck(channel) if channel < S0_CHANNEL_COUNT => {
+                Ok(S0_STUCK + channel as u8)
+            }
+            AlarmKind::S0RateHigh(channel) if channel < S0_CHANNEL_COUNT => {
+                Ok(S0_RATE_HIGH + channel as u8)
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    fn from_byte(kind: u8) -> Result<Self, Error> {
+        match kind {
+            TEMPERATURE_HIGH => Ok(AlarmKind::TemperatureHigh),
+            TEMPERATURE_LOW => Ok(AlarmKind::TemperatureLow),
+            METER_UNREACHABLE => Ok(AlarmKind::MeterUnreachable),
+            BATTERY_LOW => Ok(AlarmKind::BatteryLow),
+            kind if (S0_STUCK..S0_STUCK + S0_CHANNEL_COUNT as u8).contains(&kind) => {
+                Ok(AlarmKind::S0Stuck((kind - S0_STUCK) as usize))
+            }
+            kind if (S0_RATE_HIGH..S0_RATE_HIGH + S0_CHANNEL_COUNT as u8).contains(&kind) => {
+                Ok(AlarmKind::S0RateHigh((kind - S0_RATE_HIGH) as usize))
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// An alarm that was raised or cleared, with the value that caused it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AlarmEvent {
+    pub kind: AlarmKind,
+    pub active: bool, // false once the value is back in range
+    pub value: i64,
+}
+
+impl AlarmEvent {
+    fn size(&self) -> usize {
+        1 + signed_varint_size(self.value)
+    }
+}
+
+/// The alarms whose state changed since the last alarm message was acknowledged. Sent on [`ALARM_FPORT`].
+///
+/// Encoding:
+///   header      u8, the format version in the upper nibble
+///   timestamp   varint, when the alarms were checked in seconds since the unix epoch (UTC), 0 if unknown
+///   events      until the end of the message:
+///     kind        u8, see [`AlarmKind`], bit 7 set if the alarm cleared
+///     value       zigzag varint
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Alarms {
+    pub timestamp: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::serialize_present"))]
+    pub events: [Option<AlarmEvent>; MAX_ALARM_EVENTS],
+}
+
+impl Alarms {
+    pub fn new(timestamp: Option<u32>) -> Self {
+        Self {
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    /// Adds an event after the ones added before. Returns false if the message is full.
+    pub fn push(&mut self, event: AlarmEvent) -> bool {
+        match self.events.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(event);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Leaves out the last event, e.g. to fit a smaller payload. Returns false if there is none.
+    pub fn pop(&mut self) -> bool {
+        match self.events.iter_mut().rev().find(|slot| slot.is_some()) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.iter().all(Option::is_none)
+    }
+
+    /// The size of the message, including the header byte
+    pub fn size(&self) -> usize {
+        1 + varint_size(self.timestamp.unwrap_or(0) as u64)
+            + self
+                .events
+                .iter()
+                .flatten()
+                .map(AlarmEvent::size)
+                .sum::<usize>()
+    }
+
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        writer.u8(FORMAT_VERSION << 4)?;
+        writer.varint(self.timestamp.unwrap_or(0) as u64)?;
+        for event in self.events.iter().flatten() {
+            let kind = event.kind.to_byte()?;
+            writer.u8(if event.active { kind } else { kind | CLEARED })?;
+            writer.signed_varint(event.value)?;
+        }
+        Ok(writer.position())
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+        let version = reader.u8()? >> 4;
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let timestamp = match reader.varint()? {
+            0 => None,
+            timestamp => Some(u32::try_from(timestamp).map_err(|_| Error::InvalidValue)?),
+        };
+        let mut result = Self::new(timestamp);
+        while !reader.is_empty() {
+            let kind = reader.u8()?;
+            let event = AlarmEvent {
+                kind: AlarmKind::from_byte(kind & !CLEARED)?,
+                active: kind & CLEARED == 0,
+                value: reader.signed_varint()?,
+            };
+            if !result.push(event) {
+                return Err(Error::TrailingData);
+            }
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,145 @@
+use crate::codec::{varint_size, Reader, Writer};
+use crate::{Error, S0_CHANNEL_COUNT};
+
+/// The most readings one backlog message can hold
+pub const MAX_BACKLOG_READINGS: usize = 8;
+
+/// The energy values of one measurement, kept by the device until it knows they arrived. The backend deduplicates
+/// readings by their timestamp.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Reading {
+    pub timestamp: u32, // In seconds since the unix epoch (UTC)
+    pub meter_import_wh: Option<u64>,
+    pub meter_export_wh: Option<u64>,
+    pub counter_wh: [Option<u64>; S0_CHANNEL_COUNT],
+}
+
+impl Reading {
+    fn values(&self) -> impl Iterator<Item = &Option<u64>> {
+        [&self.meter_import_wh, &self.meter_export_wh]
+            .into_iter()
+            .chain(self.counter_wh.iter())
+    }
+
+    /// The number of bytes the reading takes in a backlog message, following a reading with the given timestamp
+    pub fn size(&self, previous_timestamp: Option<u32>) -> usize {
+        let timestamp = self
+            .timestamp
+            .saturating_sub(previous_timestamp.unwrap_or(0));
+        varint_size(timestamp as u64)
+            + 1
+            + self
+                .values()
+                .flatten()
+                .map(|value| varint_size(*value))
+                .sum::<usize>()
+    }
+
+    fn encode(&self, writer: &mut Writer, previous_timestamp: Option<u32>) -> Result<(), Error> {
+        let timestamp = self
+            .timestamp
+            .checked_sub(previous_timestamp.unwrap_or(0))
+            .ok_or(Error::InvalidValue)?;
+        writer.varint(timestamp as u64)?;
+        let presence = self
+            .values()
+            .enumerate()
+            .filter(|(_, value)| value.is_some())
+            .fold(0u8, |presence, (index, _)| presence | 1 << index);
+        writer.u8(presence)?;
+        for value in self.values().flatten() {
+            writer.varint(*value)?;
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader, previous_timestamp: Option<u32>) -> Result<Self, Error> {
+        let timestamp = previous_timestamp
+            .unwrap_or(0)
+            .checked_add(u32::try_from(reader.varint()?).map_err(|_| Error::InvalidValue)?)
+            .ok_or(Error::InvalidValue)?;
+        let presence = reader.u8()?;
+        let mut value = |index: usize| -> Result<Option<u64>, Error> {
+            match presence & (1 << index) {
+                0 => Ok(None),
+                _ => reader.varint().map(Some),
+            }
+        };
+        let mut result = Self {
+            timestamp,
+            meter_import_wh: value(0)?,
+            meter_export_wh: value(1)?,
+            ..Default::default()
+        };
+        for (channel, counter) in result.counter_wh.iter_mut().enumerate() {
+            *counter = value(2 + channel)?;
+        }
+        Ok(result)
+    }
+}
+
+/// Readings that did not make it to the backend when they were measured, sent once the network is reachable again.
+///
+/// Encoding: the readings, oldest first, until the end of the message. Each one consists of:
+///   timestamp          varint, the first one in seconds since the unix epoch, the others relative to the previous one
+///   presence bitmask   1 byte: bit 0 meter import, bit 1 meter export, bits 2 to 7 S0 counter 0 to 5
+///   values             varint each, in Wh, for each bit set
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Backlog {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::serialize_present"))]
+    readings: [Option<Reading>; MAX_BACKLOG_READINGS],
+}
+
+impl Backlog {
+    /// The size of the header byte
+    pub const HEADER_SIZE: usize = 1;
+
+    /// Adds a reading, which has to be newer than the ones added before. Returns false if the message is full.
+    pub fn push(&mut self, reading: Reading) -> bool {
+        match self.readings.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(reading);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn readings(&self) -> impl Iterator<Item = &Reading> {
+        self.readings.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.readings().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readings[0].is_none()
+    }
+
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        let mut previous_timestamp = None;
+        for reading in self.readings() {
+            reading.encode(writer, previous_timestamp)?;
+            previous_timestamp = Some(reading.timestamp);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let mut result = Self::default();
+        let mut previous_timestamp = None;
+        while !reader.is_empty() {
+            let reading = Reading::decode(reader, previous_timestamp)?;
+            if !result.push(reading) {
+                return Err(Error::TrailingData);
+            }
+            previous_timestamp = Some(reading.timestamp);
+        }
+        Ok(result)
+    }
+}
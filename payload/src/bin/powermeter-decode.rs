@@ -0,0 +1,107 @@
+//! Decodes a FRMPayload (as hex or base64) into JSON
+//!
+//! Usage: powermeter-decode [--downlink] [--fport N] <payload>
+
+use std::process::ExitCode;
+
+use base64::Engine;
+use powermeter_payload::{
+    Alarms, ClockSyncDownlink, ClockSyncUplink, Downlink, FragmentationDownlink,
+    FragmentationUplink, MulticastDownlink, MulticastUplink, Uplink, ALARM_FPORT, CLOCK_SYNC_FPORT,
+    FRAGMENTATION_FPORT, MULTICAST_FPORT, UPLINK_FPORT,
+};
+
+const USAGE: &str = "Usage: powermeter-decode [--downlink] [--fport N] <payload as hex or base64>";
+
+fn main() -> ExitCode {
+    let mut downlink = false;
+    let mut fport = UPLINK_FPORT;
+    let mut payload = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--downlink" => downlink = true,
+            "--fport" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => fport = value,
+                None => return usage_error(),
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if payload.is_none() => payload = Some(arg),
+            _ => return usage_error(),
+        }
+    }
+    let Some(payload) = payload else {
+        return usage_error();
+    };
+
+    let Some(bytes) = parse_payload(&payload) else {
+        eprintln!("Payload is neither hex nor base64: {payload}");
+        return ExitCode::FAILURE;
+    };
+
+    let json = if fport == CLOCK_SYNC_FPORT && downlink {
+        ClockSyncDownlink::decode(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|messages| serde_json::to_string_pretty(&messages))
+    } else if fport == CLOCK_SYNC_FPORT {
+        ClockSyncUplink::decode(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|messages| serde_json::to_string_pretty(&messages))
+    } else if fport == MULTICAST_FPORT && downlink {
+        MulticastDownlink::decode(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|messages| serde_json::to_string_pretty(&messages))
+    } else if fport == MULTICAST_FPORT {
+        MulticastUplink::decode(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|messages| serde_json::to_string_pretty(&messages))
+    } else if fport == FRAGMENTATION_FPORT && downlink {
+        FragmentationDownlink::decode(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|messages| serde_json::to_string_pretty(&messages))
+    } else if fport == FRAGMENTATION_FPORT {
+        FragmentationUplink::decode(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|messages| serde_json::to_string_pretty(&messages))
+    } else if downlink {
+        Downlink::decode(fport, &bytes).map(|message| serde_json::to_string_pretty(&message))
+    } else if fport == UPLINK_FPORT {
+        Uplink::decode(&bytes).map(|message| serde_json::to_string_pretty(&message))
+    } else if fport == ALARM_FPORT {
+        Alarms::decode(&bytes).map(|message| serde_json::to_string_pretty(&message))
+    } else {
+        eprintln!(
+            "Uplinks are only sent on FPort {UPLINK_FPORT}, {ALARM_FPORT}, {MULTICAST_FPORT}, {FRAGMENTATION_FPORT} and {CLOCK_SYNC_FPORT}"
+        );
+        return ExitCode::FAILURE;
+    };
+
+    match json {
+        Ok(json) => {
+            println!("{}", json.expect("messages can always be serialized"));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Decoding failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Hex is tried first, since every hex string would also be valid base64
+fn parse_payload(payload: &str) -> Option<Vec<u8>> {
+    const_hex::decode(payload).ok().or_else(|| {
+        base64::engine::general_purpose::STANDARD
+            .decode(payload)
+            .ok()
+    })
+}
+
+fn usage_error() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::FAILURE
+}
@@ -0,0 +1,161 @@
+//! The LoRaWAN Application Layer Clock Synchronization package (TS003 v1.0.0). The device sends its time, the network
+//! answers with the correction to apply. Several messages may be concatenated in one frame.
+
+use crate::codec::{messages, Writer};
+use crate::Error;
+
+/// The FPort reserved for the clock synchronization package
+pub const CLOCK_SYNC_FPORT: u8 = 202;
+
+const PACKAGE_IDENTIFIER: u8 = 1;
+const PACKAGE_VERSION: u8 = 1;
+
+const PACKAGE_VERSION_CID: u8 = 0x00;
+const APP_TIME_CID: u8 = 0x01;
+const DEVICE_APP_TIME_PERIODICITY_CID: u8 = 0x02;
+const FORCE_DEVICE_RESYNC_CID: u8 = 0x03;
+
+const ANSWER_REQUIRED: u8 = 1 << 4;
+const TOKEN_MASK: u8 = 0x0F;
+
+/// Messages the device sends. Times are in seconds since the GPS epoch (1980-01-06), modulo 2^32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum ClockSyncUplink {
+    PackageVersionAns,
+    /// The device's current time. The network answers with an [`ClockSyncDownlink::AppTimeAns`] carrying the same
+    /// token if the time is off or an answer is required.
+    AppTimeReq {
+        device_time: u32,
+        token: u8,
+        answer_required: bool,
+    },
+    DeviceAppTimePeriodicityAns {
+        not_supported: bool,
+        device_time: u32,
+    },
+}
+
+/// Messages the network sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum ClockSyncDownlink {
+    PackageVersionReq,
+    /// The number of seconds to add to the device time sent in the request with this token
+    AppTimeAns {
+        time_correction: i32,
+        token: u8,
+    },
+    /// The device should synchronize every 128 * 2^periodicity seconds
+    DeviceAppTimePeriodicityReq {
+        periodicity: u8,
+    },
+    /// The device should send this many AppTimeReqs, e.g. because its clock is known to be off
+    ForceDeviceResyncReq {
+        nb_transmissions: u8,
+    },
+}
+
+impl ClockSyncUplink {
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        match *self {
+            ClockSyncUplink::PackageVersionAns => {
+                writer.bytes(&[PACKAGE_VERSION_CID, PACKAGE_IDENTIFIER, PACKAGE_VERSION])?;
+            }
+            ClockSyncUplink::AppTimeReq {
+                device_time,
+                token,
+                answer_required,
+            } => {
+                writer.u8(APP_TIME_CID)?;
+                writer.bytes(&device_time.to_le_bytes())?;
+                writer.u8(token & TOKEN_MASK | if answer_required { ANSWER_REQUIRED } else { 0 })?;
+            }
+            ClockSyncUplink::DeviceAppTimePeriodicityAns {
+                not_supported,
+                device_time,
+            } => {
+                writer.u8(DEVICE_APP_TIME_PERIODICITY_CID)?;
+                writer.u8(not_supported as u8)?;
+                writer.bytes(&device_time.to_le_bytes())?;
+            }
+        }
+        Ok(writer.position())
+    }
+
+    /// Decodes all messages contained in the payload
+    pub fn decode(payload: &[u8]) -> impl Iterator<Item = Result<Self, Error>> + '_ {
+        messages(payload, |cid, reader| match cid {
+            PACKAGE_VERSION_CID => {
+                let [identifier, version] = reader.bytes()?;
+                if identifier != PACKAGE_IDENTIFIER || version != PACKAGE_VERSION {
+                    return Err(Error::InvalidValue);
+                }
+                Ok(ClockSyncUplink::PackageVersionAns)
+            }
+            APP_TIME_CID => {
+                let device_time = u32::from_le_bytes(reader.bytes()?);
+                let param = reader.u8()?;
+                Ok(ClockSyncUplink::AppTimeReq {
+                    device_time,
+                    token: param & TOKEN_MASK,
+                    answer_required: param & ANSWER_REQUIRED != 0,
+                })
+            }
+            DEVICE_APP_TIME_PERIODICITY_CID => Ok(ClockSyncUplink::DeviceAppTimePeriodicityAns {
+                not_supported: reader.u8()? & 1 != 0,
+                device_time: u32::from_le_bytes(reader.bytes()?),
+            }),
+            cid => Err(Error::UnknownMessageType(cid)),
+        })
+    }
+}
+
+impl ClockSyncDownlink {
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        match *self {
+            ClockSyncDownlink::PackageVersionReq => writer.u8(PACKAGE_VERSION_CID)?,
+            ClockSyncDownlink::AppTimeAns {
+                time_correction,
+                token,
+            } => {
+                writer.u8(APP_TIME_CID)?;
+                writer.bytes(&time_correction.to_le_bytes())?;
+                writer.u8(token & TOKEN_MASK)?;
+            }
+            ClockSyncDownlink::DeviceAppTimePeriodicityReq { periodicity } => {
+                writer.bytes(&[DEVICE_APP_TIME_PERIODICITY_CID, periodicity & 0x0F])?;
+            }
+            ClockSyncDownlink::ForceDeviceResyncReq { nb_transmissions } => {
+                writer.bytes(&[FORCE_DEVICE_RESYNC_CID, nb_transmissions & 0x07])?;
+            }
+        }
+        Ok(writer.position())
+    }
+
+    /// Decodes all messages contained in the payload
+    pub fn decode(payload: &[u8]) -> impl Iterator<Item = Result<Self, Error>> + '_ {
+        messages(payload, |cid, reader| match cid {
+            PACKAGE_VERSION_CID => Ok(ClockSyncDownlink::PackageVersionReq),
+            APP_TIME_CID => Ok(ClockSyncDownlink::AppTimeAns {
+                time_correction: i32::from_le_bytes(reader.bytes()?),
+                token: reader.u8()? & TOKEN_MASK,
+            }),
+            DEVICE_APP_TIME_PERIODICITY_CID => Ok(ClockSyncDownlink::DeviceAppTimePeriodicityReq {
+                periodicity: reader.u8()? & 0x0F,
+            }),
+            FORCE_DEVICE_RESYNC_CID => Ok(ClockSyncDownlink::ForceDeviceResyncReq {
+                nb_transmissions: reader.u8()? & 0x07,
+            }),
+            cid => Err(Error::UnknownMessageType(cid)),
+        })
+    }
+}
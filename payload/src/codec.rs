@@ -0,0 +1,147 @@
+use crate::Error;
+
+/// Writes values into a byte buffer, failing if it is too small
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+
+    /// The number of bytes written so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.bytes(&[value])
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Result<(), Error> {
+        let end = self.position + value.len();
+        if end > self.buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        self.buf[self.position..end].copy_from_slice(value);
+        self.position = end;
+        Ok(())
+    }
+
+    /// Unsigned LEB128: 7 bits per byte, least significant group first, the high bit is set on all but the last byte
+    pub fn varint(&mut self, mut value: u64) -> Result<(), Error> {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                return self.u8(byte);
+            }
+            self.u8(byte | 0x80)?;
+        }
+    }
+
+    /// Zigzag encoded varint, so small negative numbers stay short
+    pub fn signed_varint(&mut self, value: i64) -> Result<(), Error> {
+        self.varint(zigzag(value))
+    }
+}
+
+/// Reads values from a byte buffer, failing if it ends early
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position == self.buf.len()
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let end = self.position + N;
+        if end > self.buf.len() {
+            return Err(Error::Truncated);
+        }
+        let mut result = [0u8; N];
+        result.copy_from_slice(&self.buf[self.position..end]);
+        self.position = end;
+        Ok(result)
+    }
+
+    pub fn varint(&mut self) -> Result<u64, Error> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= 64 || (shift == 63 && byte & 0x7F > 1) {
+                return Err(Error::Overflow);
+            }
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn signed_varint(&mut self) -> Result<i64, Error> {
+        Ok(unzigzag(self.varint()?))
+    }
+
+    /// The bytes not read yet
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.position..];
+        self.position = self.buf.len();
+        rest
+    }
+}
+
+/// The number of bytes a varint of this value takes
+pub fn varint_size(value: u64) -> usize {
+    let bits = 64 - value.leading_zeros() as usize;
+    bits.div_ceil(7).max(1)
+}
+
+/// The number of bytes a zigzag encoded varint of this value takes
+pub fn signed_varint_size(value: i64) -> usize {
+    varint_size(zigzag(value))
+}
+
+/// Maps signed values to unsigned ones, so small negative numbers stay short as a varint (0, -1, 1, -2, … become
+/// 0, 1, 2, 3, …). Also used for the values of signed settings (see [`crate::ConfigKey`]).
+pub fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// The signed value a zigzag encoded one stands for
+pub fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Splits the payload into messages. Decoding stops at the first error, since the length of an unknown message (and
+/// so the start of the next one) can't be known.
+pub fn messages<'a, T>(
+    payload: &'a [u8],
+    decode: impl Fn(u8, &mut Reader) -> Result<T, Error> + 'a,
+) -> impl Iterator<Item = Result<T, Error>> + 'a {
+    let mut reader = Reader::new(payload);
+    let mut failed = false;
+    core::iter::from_fn(move || {
+        if failed || reader.is_empty() {
+            return None;
+        }
+        let result = reader.u8().and_then(|cid| decode(cid, &mut reader));
+        failed = result.is_err();
+        Some(result)
+    })
+}
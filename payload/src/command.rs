@@ -0,0 +1,334 @@
+use crate::codec::{Reader, Writer};
+use crate::{Error, MAX_COUNTER_IMPULSES, S0_CHANNEL_COUNT};
+
+/// A command sent by the backend on the command FPort. Each command is answered by a [`CommandAck`] in the next
+/// measurement uplink.
+///
+/// Encoding: opcode byte, followed by the opcode specific arguments. Numbers are varints, signed ones zigzag encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "command", rename_all = "snake_case"))]
+pub enum Command {
+    /// 0x01: channel (u8), impulses (up to [`MAX_COUNTER_IMPULSES`])
+    SetCounter { channel: usize, impulses: u64 },
+    /// 0x02: channel (u8), signed offset in impulses
+    OffsetCounter { channel: usize, offset: i64 },
+    /// 0x03: seconds between measurements
+    SetInterval { seconds: u32 },
+    /// 0x04: no arguments. The device reboots after acknowledging the command.
+    Reboot,
+    /// 0x05: no arguments. The device answers with a status uplink.
+    RequestStatus,
+    /// 0x06: key (u8), value
+    SetConfig { key: ConfigKey, value: u64 },
+    /// 0x07: no arguments. The device joins the network again.
+    Rejoin,
+}
+
+const SET_COUNTER: u8 = 0x01;
+const OFFSET_COUNTER: u8 = 0x02;
+const SET_INTERVAL: u8 = 0x03;
+const REBOOT: u8 = 0x04;
+const REQUEST_STATUS: u8 = 0x05;
+const SET_CONFIG: u8 = 0x06;
+const REJOIN: u8 = 0x07;
+
+impl Command {
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        match *self {
+            Command::SetCounter { channel, impulses } => {
+                writer.u8(SET_COUNTER)?;
+                writer.u8(channel_to_byte(channel)?)?;
+                writer.varint(check_impulses(impulses)?)
+            }
+            Command::OffsetCounter { channel, offset } => {
+                writer.u8(OFFSET_COUNTER)?;
+                writer.u8(channel_to_byte(channel)?)?;
+                writer.signed_varint(offset)
+            }
+            Command::SetInterval { seconds } => {
+                writer.u8(SET_INTERVAL)?;
+                writer.varint(seconds as u64)
+            }
+            Command::Reboot => writer.u8(REBOOT),
+            Command::RequestStatus => writer.u8(REQUEST_STATUS),
+            Command::SetConfig { key, value } => {
+                writer.u8(SET_CONFIG)?;
+                writer.u8(key.to_byte()?)?;
+                writer.varint(value)
+            }
+            Command::Rejoin => writer.u8(REJOIN),
+        }
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let command = match reader.u8()? {
+            SET_COUNTER => Command::SetCounter {
+                channel: channel_from_byte(reader.u8()?)?,
+                impulses: check_impulses(reader.varint()?)?,
+            },
+            OFFSET_COUNTER => Command::OffsetCounter {
+                channel: channel_from_byte(reader.u8()?)?,
+                offset: reader.signed_varint()?,
+            },
+            SET_INTERVAL => Command::SetInterval {
+                seconds: u32::try_from(reader.varint()?).map_err(|_| Error::InvalidValue)?,
+            },
+            REBOOT => Command::Reboot,
+            REQUEST_STATUS => Command::RequestStatus,
+            SET_CONFIG => Command::SetConfig {
+                key: ConfigKey::from_byte(reader.u8()?)?,
+                value: reader.varint()?,
+            },
+            REJOIN => Command::Rejoin,
+            opcode => return Err(Error::UnknownMessageType(opcode)),
+        };
+        if !reader.is_empty() {
+            return Err(Error::TrailingData);
+        }
+        Ok(command)
+    }
+}
+
+fn channel_to_byte(channel: usize) -> Result<u8, Error> {
+    if channel < S0_CHANNEL_COUNT {
+        Ok(channel as u8)
+    } else {
+        Err(Error::InvalidValue)
+    }
+}
+
+fn check_impulses(impulses: u64) -> Result<u64, Error> {
+    if impulses <= MAX_COUNTER_IMPULSES {
+        Ok(impulses)
+    } else {
+        Err(Error::InvalidValue)
+    }
+}
+
+fn channel_from_byte(channel: u8) -> Result<usize, Error> {
+    let channel = channel as usize;
+    if channel < S0_CHANNEL_COUNT {
+        Ok(channel)
+    } else {
+        Err(Error::InvalidValue)
+    }
+}
+
+/// The settings that can be changed with [`Command::SetConfig`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ConfigKey {
+    /// 0x00 to 0x05: the impulses per kWh of S0 channel 0 to 5
+    S0ImpulsesPerKwh(usize),
+    /// 0x10: the maximum random delay added to the measurement interval, in seconds
+    RandomSleepVariation,
+    /// 0x11: 1 to measure at multiples of the interval since midnight UTC (once the time is known), 0 to not align
+    AlignToWallClock,
+    /// 0x12: how often important uplinks are repeated if they are not acknowledged
+    ConfirmedRetries,
+    /// 0x13: the delay before the first repetition of an unacknowledged uplink, in seconds. It doubles each time.
+    RetryBackoff,
+    /// 0x14: the uplink airtime the device may use per day, in seconds
+    AirtimeBudget,
+    /// 0x15: what the device is powered by, for the battery level: 0 mains, 1 a lithium-ion cell, 2 a
+    /// lithium-thionyl chloride (LiSOCl2) cell
+    BatteryType,
+    /// 0x16: the voltage between the battery and the supply input, e.g. across a protection diode, in mV. It is added
+    /// to the measured voltage.
+    BatteryOffset,
+    /// 0x17: the offset added to the internal temperature sensor's reading, in 0.1 °C. Signed, [`zigzag`] encoded.
+    ///
+    /// [`zigzag`]: crate::zigzag
+    TemperatureOffset,
+    /// 0x18: the factor the internal temperature sensor's reading is multiplied with before adding the offset, in ‰
+    TemperatureSlope,
+    /// 0x19: the gain of the antenna, in dBi. The transmit power is reduced by it to stay within the maximum EIRP.
+    AntennaGain,
+    /// 0x1A: the maximum radiated power (EIRP), in dBm. At most the region's limit, lower where the installation
+    /// requires it.
+    MaxEirp,
+    /// 0x1B: the voltage of the ADC reference (VREF), in mV, as measured on the board
+    AdcReference,
+    /// 0x1C: the temperature above which an alarm is sent, in 0.1 °C. Signed, [`zigzag`] encoded.
+    ///
+    /// [`zigzag`]: crate::zigzag
+    TemperatureHighAlarm,
+    /// 0x1D: the temperature below which an alarm is sent, in 0.1 °C. Signed, [`zigzag`] encoded.
+    ///
+    /// [`zigzag`]: crate::zigzag
+    TemperatureLowAlarm,
+    /// 0x1E: the meter reads that have to fail in a row for an alarm, 0 for none
+    MeterFailureAlarm,
+    /// 0x1F: the battery level below which an alarm is sent, in %, 0 for none
+    BatteryLowAlarm,
+    /// 0x20: the hours an S0 channel may go without an impulse before an alarm is sent, 0 for none
+    S0StuckAlarm,
+    /// 0x21: the impulses per hour above which an S0 channel sends an alarm, 0 for none
+    S0RateAlarm,
+    /// 0x22: the S0 channels checked for the S0 alarms, bit 0 to 5 for channel 0 to 5
+    S0AlarmChannels,
+    /// 0x23: the minimum time between two alarm uplinks, in seconds
+    AlarmHoldoff,
+}
+
+const S0_IMPULSES_PER_KWH: u8 = 0x00;
+const RANDOM_SLEEP_VARIATION: u8 = 0x10;
+const ALIGN_TO_WALL_CLOCK: u8 = 0x11;
+const CONFIRMED_RETRIES: u8 = 0x12;
+const RETRY_BACKOFF: u8 = 0x13;
+const AIRTIME_BUDGET: u8 = 0x14;
+const BATTERY_TYPE: u8 = 0x15;
+const BATTERY_OFFSET: u8 = 0x16;
+const TEMPERATURE_OFFSET: u8 = 0x17;
+const TEMPERATURE_SLOPE: u8 = 0x18;
+const ANTENNA_GAIN: u8 = 0x19;
+const MAX_EIRP: u8 = 0x1A;
+const ADC_REFERENCE: u8 = 0x1B;
+const TEMPERATURE_HIGH_ALARM: u8 = 0x1C;
+const TEMPERATURE_LOW_ALARM: u8 = 0x1D;
+const METER_FAILURE_ALARM: u8 = 0x1E;
+const BATTERY_LOW_ALARM: u8 = 0x1F;
+const S0_STUCK_ALARM: u8 = 0x20;
+const S0_RATE_ALARM: u8 = 0x21;
+const S0_ALARM_CHANNELS: u8 = 0x22;
+const ALARM_HOLDOFF: u8 = 0x23;
+
+impl ConfigKey {
+    fn to_byte(self) -> Result<u8, Error> {
+        match self {
+            ConfigKey::S0ImpulsesPerKwh(channel) => {
+                Ok(S0_IMPULSES_PER_KWH + channel_to_byte(channel)?)
+            }
+            ConfigKey::RandomSleepVariation => Ok(RANDOM_SLEEP_VARIATION),
+            ConfigKey::AlignToWallClock => Ok(ALIGN_TO_WALL_CLOCK),
+            ConfigKey::ConfirmedRetries => Ok(CONFIRMED_RETRIES),
+            ConfigKey::RetryBackoff => Ok(RETRY_BACKOFF),
+            ConfigKey::AirtimeBudget => Ok(AIRTIME_BUDGET),
+            ConfigKey::BatteryType => Ok(BATTERY_TYPE),
+            ConfigKey::BatteryOffset => Ok(BATTERY_OFFSET),
+            ConfigKey::TemperatureOffset => Ok(TEMPERATURE_OFFSET),
+            ConfigKey::TemperatureSlope => Ok(TEMPERATURE_SLOPE),
+            ConfigKey::AntennaGain => Ok(ANTENNA_GAIN),
+            ConfigKey::MaxEirp => Ok(MAX_EIRP),
+            ConfigKey::AdcReference => Ok(ADC_REFERENCE),
+            ConfigKey::TemperatureHighAlarm => Ok(TEMPERATURE_HIGH_ALARM),
+            ConfigKey::TemperatureLowAlarm => Ok(TEMPERATURE_LOW_ALARM),
+            ConfigKey::MeterFailureAlarm => Ok(METER_FAILURE_ALARM),
+            ConfigKey::BatteryLowAlarm => Ok(BATTERY_LOW_ALARM),
+            ConfigKey::S0StuckAlarm => Ok(S0_STUCK_ALARM),
+            ConfigKey::S0RateAlarm => Ok(S0_RATE_ALARM),
+            ConfigKey::S0AlarmChannels => Ok(S0_ALARM_CHANNELS),
+            ConfigKey::AlarmHoldoff => Ok(ALARM_HOLDOFF),
+        }
+    }
+
+    fn from_byte(key: u8) -> Result<Self, Error> {
+        match key {
+            key if key < S0_IMPULSES_PER_KWH + S0_CHANNEL_COUNT as u8 => Ok(
+                ConfigKey::S0ImpulsesPerKwh((key - S0_IMPULSES_PER_KWH) as usize),
+            ),
+            RANDOM_SLEEP_VARIATION => Ok(ConfigKey::RandomSleepVariation),
+            ALIGN_TO_WALL_CLOCK => Ok(ConfigKey::AlignToWallClock),
+            CONFIRMED_RETRIES => Ok(ConfigKey::ConfirmedRetries),
+            RETRY_BACKOFF => Ok(ConfigKey::RetryBackoff),
+            AIRTIME_BUDGET => Ok(ConfigKey::AirtimeBudget),
+            BATTERY_TYPE => Ok(ConfigKey::BatteryType),
+            BATTERY_OFFSET => Ok(ConfigKey::BatteryOffset),
+            TEMPERATURE_OFFSET => Ok(ConfigKey::TemperatureOffset),
+            TEMPERATURE_SLOPE => Ok(ConfigKey::TemperatureSlope),
+            ANTENNA_GAIN => Ok(ConfigKey::AntennaGain),
+            MAX_EIRP => Ok(ConfigKey::MaxEirp),
+            ADC_REFERENCE => Ok(ConfigKey::AdcReference),
+            TEMPERATURE_HIGH_ALARM => Ok(ConfigKey::TemperatureHighAlarm),
+            TEMPERATURE_LOW_ALARM => Ok(ConfigKey::TemperatureLowAlarm),
+            METER_FAILURE_ALARM => Ok(ConfigKey::MeterFailureAlarm),
+            BATTERY_LOW_ALARM => Ok(ConfigKey::BatteryLowAlarm),
+            S0_STUCK_ALARM => Ok(ConfigKey::S0StuckAlarm),
+            S0_RATE_ALARM => Ok(ConfigKey::S0RateAlarm),
+            S0_ALARM_CHANNELS => Ok(ConfigKey::S0AlarmChannels),
+            ALARM_HOLDOFF => Ok(ConfigKey::AlarmHoldoff),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// The outcome of a command, sent back to the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CommandResult {
+    /// 0: The command was executed
+    Ok,
+    /// 1: The opcode is not known to this firmware
+    UnknownCommand,
+    /// 2: The command is too short or too long
+    Malformed,
+    /// 3: An argument is out of the allowed range
+    OutOfRange,
+    /// 4: The command was valid, but could not be executed
+    Failed,
+}
+
+impl CommandResult {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            CommandResult::Ok => 0,
+            CommandResult::UnknownCommand => 1,
+            CommandResult::Malformed => 2,
+            CommandResult::OutOfRange => 3,
+            CommandResult::Failed => 4,
+        }
+    }
+
+    pub(crate) fn from_byte(result: u8) -> Result<Self, Error> {
+        match result {
+            0 => Ok(CommandResult::Ok),
+            1 => Ok(CommandResult::UnknownCommand),
+            2 => Ok(CommandResult::Malformed),
+            3 => Ok(CommandResult::OutOfRange),
+            4 => Ok(CommandResult::Failed),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+impl From<Error> for CommandResult {
+    /// The result to acknowledge a command with that could not be decoded
+    fn from(error: Error) -> Self {
+        match error {
+            Error::UnknownMessageType(_) => CommandResult::UnknownCommand,
+            Error::InvalidValue | Error::Overflow => CommandResult::OutOfRange,
+            _ => CommandResult::Malformed,
+        }
+    }
+}
+
+/// Tells the backend what became of the command with the given tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommandAck {
+    pub tag: u8,
+    pub result: CommandResult,
+}
+
+impl CommandAck {
+    pub(crate) const SIZE: usize = 2;
+
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.u8(self.tag)?;
+        writer.u8(self.result.to_byte())
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            tag: reader.u8()?,
+            result: CommandResult::from_byte(reader.u8()?)?,
+        })
+    }
+}
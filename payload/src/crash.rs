@@ -0,0 +1,159 @@
+use crate::codec::{varint_size, Reader, Writer};
+use crate::{Error, ResetReason};
+
+/// The most code addresses a crash report holds
+pub const MAX_CRASH_ADDRESSES: usize = 6;
+
+const ADDRESS_SIZE: usize = 4;
+
+/// Why the device restarted and, after a panic or hard fault, where it crashed. Sent after booting if the previous
+/// firmware crashed.
+///
+/// Encoding:
+///   reset reason   u8, as in the measurement
+///   crash          until the end of the message, if known:
+///     uptime         varint, seconds the firmware ran before it crashed
+///     line           varint, of the panic location, 0 for a hard fault
+///     file hash      4 bytes (little endian), [`hash`] of the path of the source file that panicked
+///     message hash   4 bytes (little endian), [`hash`] of the panic message
+///     addresses      4 bytes (little endian) each, until the end of the message
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Diagnostic {
+    pub reset_reason: ResetReason,
+    pub crash: Option<Crash>,
+}
+
+/// What the firmware saved when it crashed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Crash {
+    pub uptime_s: u32,
+    pub line: u32,         // 0 for a hard fault
+    pub file_hash: u32,    // 0 for a hard fault
+    pub message_hash: u32, // 0 for a hard fault
+    /// Code addresses found on the stack, innermost first. After a hard fault, the first one is the faulting
+    /// instruction and the second one the link register. Look them up with `addr2line` in the firmware's ELF file.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::serialize_present"))]
+    pub addresses: [Option<u32>; MAX_CRASH_ADDRESSES],
+}
+
+impl Crash {
+    /// Adds an address after the ones added before. Returns false if the report is full.
+    pub fn push(&mut self, address: u32) -> bool {
+        match self.addresses.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(address);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Leaves out the outermost address, e.g. to fit a smaller payload. Returns false if there is none.
+    pub fn pop(&mut self) -> bool {
+        match self.addresses.iter_mut().rev().find(|slot| slot.is_some()) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn size(&self) -> usize {
+        varint_size(self.uptime_s as u64)
+            + varint_size(self.line as u64)
+            + 8
+            + self.addresses.iter().flatten().count() * ADDRESS_SIZE
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.varint(self.uptime_s as u64)?;
+        writer.varint(self.line as u64)?;
+        writer.bytes(&self.file_hash.to_le_bytes())?;
+        writer.bytes(&self.message_hash.to_le_bytes())?;
+        for address in self.addresses.iter().flatten() {
+            writer.bytes(&address.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let mut result = Self {
+            uptime_s: u32::try_from(reader.varint()?).map_err(|_| Error::InvalidValue)?,
+            line: u32::try_from(reader.varint()?).map_err(|_| Error::InvalidValue)?,
+            file_hash: u32::from_le_bytes(reader.bytes()?),
+            message_hash: u32::from_le_bytes(reader.bytes()?),
+            ..Default::default()
+        };
+        while !reader.is_empty() {
+            if !result.push(u32::from_le_bytes(reader.bytes()?)) {
+                return Err(Error::TrailingData);
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl Diagnostic {
+    /// The size of the message, including the header byte
+    pub fn size(&self) -> usize {
+        1 + ResetReason::SIZE + self.crash.as_ref().map_or(0, Crash::size)
+    }
+
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        self.reset_reason.encode(writer)?;
+        if let Some(crash) = &self.crash {
+            crash.encode(writer)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let reset_reason = ResetReason::decode(reader)?;
+        let crash = match reader.is_empty() {
+            true => None,
+            false => Some(Crash::decode(reader)?),
+        };
+        Ok(Self {
+            reset_reason,
+            crash,
+        })
+    }
+}
+
+/// Hashes text for a crash report (32-bit FNV-1a), so the backend can match it with the panic messages and source
+/// files it knows. Text written in several parts hashes the same as written at once.
+#[derive(Debug, Clone, Copy)]
+pub struct CrashHasher(u32);
+
+impl CrashHasher {
+    pub fn finish(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for CrashHasher {
+    fn default() -> Self {
+        Self(0x811C_9DC5)
+    }
+}
+
+impl core::fmt::Write for CrashHasher {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.0 = (self.0 ^ byte as u32).wrapping_mul(0x0100_0193);
+        }
+        Ok(())
+    }
+}
+
+/// The hash of a panic message or source file path, as sent in a crash report
+pub fn hash(text: &str) -> u32 {
+    let mut hasher = CrashHasher::default();
+    core::fmt::Write::write_str(&mut hasher, text).unwrap();
+    hasher.finish()
+}
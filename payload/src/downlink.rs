@@ -0,0 +1,63 @@
+use crate::codec::{Reader, Writer};
+use crate::{Command, Error, MAX_COUNTER_IMPULSES, S0_CHANNEL_COUNT};
+
+/// The FPort commands are sent on
+pub const COMMAND_FPORT: u8 = 10;
+
+/// Everything the backend can send to the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum Downlink {
+    /// Sets the S0 counter to the given number of impulses (up to [`MAX_COUNTER_IMPULSES`]). Sent on FPort counter + 1
+    /// as an 8-byte little endian value. Kept for compatibility, new backends should use [`Command::SetCounter`] instead.
+    SetCounter { counter: usize, impulses: u64 },
+    /// Sent on [`COMMAND_FPORT`]: a tag byte chosen by the backend, followed by the command. The tag is repeated in
+    /// the acknowledgement.
+    Command { tag: u8, command: Command },
+}
+
+impl Downlink {
+    /// Encodes the message into the buffer, returning the FPort and the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<(u8, usize), Error> {
+        let mut writer = Writer::new(buf);
+        match *self {
+            Downlink::SetCounter { counter, impulses } => {
+                if counter >= S0_CHANNEL_COUNT || impulses > MAX_COUNTER_IMPULSES {
+                    return Err(Error::InvalidValue);
+                }
+                writer.bytes(&impulses.to_le_bytes())?;
+                Ok((counter as u8 + 1, writer.position()))
+            }
+            Downlink::Command { tag, command } => {
+                writer.u8(tag)?;
+                command.encode(&mut writer)?;
+                Ok((COMMAND_FPORT, writer.position()))
+            }
+        }
+    }
+
+    pub fn decode(fport: u8, payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+        if fport == COMMAND_FPORT {
+            return Ok(Downlink::Command {
+                tag: reader.u8()?,
+                command: Command::decode(&mut reader)?,
+            });
+        }
+
+        let counter = match (fport as usize).checked_sub(1) {
+            Some(counter) if counter < S0_CHANNEL_COUNT => counter,
+            _ => return Err(Error::UnknownMessageType(fport)),
+        };
+        let impulses = u64::from_le_bytes(reader.bytes()?);
+        if !reader.is_empty() {
+            return Err(Error::TrailingData);
+        }
+        if impulses > MAX_COUNTER_IMPULSES {
+            return Err(Error::InvalidValue);
+        }
+        Ok(Downlink::SetCounter { counter, impulses })
+    }
+}
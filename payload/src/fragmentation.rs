@@ -0,0 +1,291 @@
+//! The LoRaWAN Fragmented Data Block Transport package (TS004 v1.0.0). The network splits a data block (e.g. a
+//! firmware image) into fragments, followed by redundant ones, so the device can recover the block even if some of
+//! them are lost. Several messages may be concatenated in one frame, except for data fragments.
+
+use crate::codec::{messages, Reader, Writer};
+use crate::Error;
+
+/// The FPort reserved for the fragmented data block transport package
+pub const FRAGMENTATION_FPORT: u8 = 201;
+
+const PACKAGE_IDENTIFIER: u8 = 3;
+const PACKAGE_VERSION: u8 = 1;
+
+const PACKAGE_VERSION_CID: u8 = 0x00;
+const FRAG_SESSION_STATUS_CID: u8 = 0x01;
+const FRAG_SESSION_SETUP_CID: u8 = 0x02;
+const FRAG_SESSION_DELETE_CID: u8 = 0x03;
+const DATA_FRAGMENT_CID: u8 = 0x08;
+
+// Fragment numbers take the lower 14 bits of a 16 bit field, the session index the upper 2
+const FRAGMENT_NUMBER_MASK: u16 = 0x3FFF;
+const SESSION_INDEX_SHIFT: u16 = 14;
+
+/// Why a fragmentation session could not be set up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FragSessionSetupStatus {
+    pub encoding_unsupported: bool,
+    pub not_enough_memory: bool,
+    pub index_unsupported: bool,
+    pub wrong_descriptor: bool,
+}
+
+impl FragSessionSetupStatus {
+    pub fn is_ok(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Messages the device sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum FragmentationUplink {
+    PackageVersionAns,
+    FragSessionStatusAns {
+        session_index: u8,
+        /// The number of fragments received so far, coded ones included
+        fragments_received: u16,
+        /// The number of fragments still missing to recover the data block, up to 255
+        fragments_missing: u8,
+        not_enough_matrix_memory: bool,
+    },
+    FragSessionSetupAns {
+        session_index: u8,
+        status: FragSessionSetupStatus,
+    },
+    FragSessionDeleteAns {
+        session_index: u8,
+        session_does_not_exist: bool,
+    },
+}
+
+/// Messages the network sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum FragmentationDownlink<'a> {
+    PackageVersionReq,
+    /// Asks for the status of the session. If `participants_only` is set, only devices still missing fragments answer.
+    FragSessionStatusReq {
+        session_index: u8,
+        participants_only: bool,
+    },
+    FragSessionSetupReq {
+        session_index: u8,
+        /// The multicast groups (bit X for group X) whose fragments feed the session
+        multicast_group_mask: u8,
+        /// The number of uncoded fragments the data block is split into
+        fragment_count: u16,
+        fragment_size: u8,
+        /// 0 is the only matrix defined by the specification
+        fragmentation_matrix: u8,
+        /// The answer to a FragSessionStatusReq is delayed by a random time of up to 2^(4 + delay) seconds
+        block_ack_delay: u8,
+        /// The number of bytes appended to the data block to fill the last fragment
+        padding: u8,
+        /// Defined by the application, see the README
+        descriptor: u32,
+    },
+    FragSessionDeleteReq {
+        session_index: u8,
+    },
+    /// Fragment `number` of the session, counted from 1. Numbers above the fragment count are coded fragments.
+    DataFragment {
+        session_index: u8,
+        number: u16,
+        data: &'a [u8],
+    },
+}
+
+impl FragmentationUplink {
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        match *self {
+            FragmentationUplink::PackageVersionAns => {
+                writer.bytes(&[PACKAGE_VERSION_CID, PACKAGE_IDENTIFIER, PACKAGE_VERSION])?;
+            }
+            FragmentationUplink::FragSessionStatusAns {
+                session_index,
+                fragments_received,
+                fragments_missing,
+                not_enough_matrix_memory,
+            } => {
+                writer.u8(FRAG_SESSION_STATUS_CID)?;
+                let received_and_index = fragments_received & FRAGMENT_NUMBER_MASK
+                    | ((session_index & 0x03) as u16) << SESSION_INDEX_SHIFT;
+                writer.bytes(&received_and_index.to_le_bytes())?;
+                writer.u8(fragments_missing)?;
+                writer.u8(not_enough_matrix_memory as u8)?;
+            }
+            FragmentationUplink::FragSessionSetupAns {
+                session_index,
+                status,
+            } => {
+                writer.u8(FRAG_SESSION_SETUP_CID)?;
+                writer.u8(status.encoding_unsupported as u8
+                    | (status.not_enough_memory as u8) << 1
+                    | (status.index_unsupported as u8) << 2
+                    | (status.wrong_descriptor as u8) << 3
+                    | (session_index & 0x03) << 6)?;
+            }
+            FragmentationUplink::FragSessionDeleteAns {
+                session_index,
+                session_does_not_exist,
+            } => {
+                writer.u8(FRAG_SESSION_DELETE_CID)?;
+                writer.u8(session_index & 0x03 | (session_does_not_exist as u8) << 2)?;
+            }
+        }
+        Ok(writer.position())
+    }
+
+    /// Decodes all messages contained in the payload
+    pub fn decode(payload: &[u8]) -> impl Iterator<Item = Result<Self, Error>> + '_ {
+        messages(payload, |cid, reader| match cid {
+            PACKAGE_VERSION_CID => {
+                let [identifier, version] = reader.bytes()?;
+                if identifier != PACKAGE_IDENTIFIER || version != PACKAGE_VERSION {
+                    return Err(Error::InvalidValue);
+                }
+                Ok(FragmentationUplink::PackageVersionAns)
+            }
+            FRAG_SESSION_STATUS_CID => {
+                let received_and_index = u16::from_le_bytes(reader.bytes()?);
+                Ok(FragmentationUplink::FragSessionStatusAns {
+                    session_index: (received_and_index >> SESSION_INDEX_SHIFT) as u8,
+                    fragments_received: received_and_index & FRAGMENT_NUMBER_MASK,
+                    fragments_missing: reader.u8()?,
+                    not_enough_matrix_memory: reader.u8()? & 1 != 0,
+                })
+            }
+            FRAG_SESSION_SETUP_CID => {
+                let status = reader.u8()?;
+                Ok(FragmentationUplink::FragSessionSetupAns {
+                    session_index: status >> 6,
+                    status: FragSessionSetupStatus {
+                        encoding_unsupported: status & 1 != 0,
+                        not_enough_memory: status & (1 << 1) != 0,
+                        index_unsupported: status & (1 << 2) != 0,
+                        wrong_descriptor: status & (1 << 3) != 0,
+                    },
+                })
+            }
+            FRAG_SESSION_DELETE_CID => {
+                let status = reader.u8()?;
+                Ok(FragmentationUplink::FragSessionDeleteAns {
+                    session_index: status & 0x03,
+                    session_does_not_exist: status & (1 << 2) != 0,
+                })
+            }
+            cid => Err(Error::UnknownMessageType(cid)),
+        })
+    }
+}
+
+impl<'a> FragmentationDownlink<'a> {
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        match *self {
+            FragmentationDownlink::PackageVersionReq => writer.u8(PACKAGE_VERSION_CID)?,
+            FragmentationDownlink::FragSessionStatusReq {
+                session_index,
+                participants_only,
+            } => {
+                writer.u8(FRAG_SESSION_STATUS_CID)?;
+                writer.u8(participants_only as u8 | (session_index & 0x03) << 1)?;
+            }
+            FragmentationDownlink::FragSessionSetupReq {
+                session_index,
+                multicast_group_mask,
+                fragment_count,
+                fragment_size,
+                fragmentation_matrix,
+                block_ack_delay,
+                padding,
+                descriptor,
+            } => {
+                writer.u8(FRAG_SESSION_SETUP_CID)?;
+                writer.u8(multicast_group_mask & 0x0F | (session_index & 0x03) << 4)?;
+                writer.bytes(&fragment_count.to_le_bytes())?;
+                writer.u8(fragment_size)?;
+                writer.u8(block_ack_delay & 0x07 | (fragmentation_matrix & 0x07) << 3)?;
+                writer.u8(padding)?;
+                writer.bytes(&descriptor.to_le_bytes())?;
+            }
+            FragmentationDownlink::FragSessionDeleteReq { session_index } => {
+                writer.bytes(&[FRAG_SESSION_DELETE_CID, session_index & 0x03])?;
+            }
+            FragmentationDownlink::DataFragment {
+                session_index,
+                number,
+                data,
+            } => {
+                writer.u8(DATA_FRAGMENT_CID)?;
+                let index_and_number = number & FRAGMENT_NUMBER_MASK
+                    | ((session_index & 0x03) as u16) << SESSION_INDEX_SHIFT;
+                writer.bytes(&index_and_number.to_le_bytes())?;
+                writer.bytes(data)?;
+            }
+        }
+        Ok(writer.position())
+    }
+
+    /// Decodes all messages contained in the payload. A data fragment takes up the rest of the payload.
+    pub fn decode(payload: &'a [u8]) -> impl Iterator<Item = Result<Self, Error>> + 'a {
+        let mut reader = Reader::new(payload);
+        let mut done = false;
+        core::iter::from_fn(move || {
+            if done || reader.is_empty() {
+                return None;
+            }
+            let result = reader.u8().and_then(|cid| match cid {
+                PACKAGE_VERSION_CID => Ok(FragmentationDownlink::PackageVersionReq),
+                FRAG_SESSION_STATUS_CID => {
+                    let param = reader.u8()?;
+                    Ok(FragmentationDownlink::FragSessionStatusReq {
+                        session_index: (param >> 1) & 0x03,
+                        participants_only: param & 1 != 0,
+                    })
+                }
+                FRAG_SESSION_SETUP_CID => {
+                    let session = reader.u8()?;
+                    let fragment_count = u16::from_le_bytes(reader.bytes()?);
+                    let fragment_size = reader.u8()?;
+                    let control = reader.u8()?;
+                    Ok(FragmentationDownlink::FragSessionSetupReq {
+                        session_index: (session >> 4) & 0x03,
+                        multicast_group_mask: session & 0x0F,
+                        fragment_count,
+                        fragment_size,
+                        fragmentation_matrix: (control >> 3) & 0x07,
+                        block_ack_delay: control & 0x07,
+                        padding: reader.u8()?,
+                        descriptor: u32::from_le_bytes(reader.bytes()?),
+                    })
+                }
+                FRAG_SESSION_DELETE_CID => Ok(FragmentationDownlink::FragSessionDeleteReq {
+                    session_index: reader.u8()? & 0x03,
+                }),
+                DATA_FRAGMENT_CID => {
+                    let index_and_number = u16::from_le_bytes(reader.bytes()?);
+                    done = true;
+                    Ok(FragmentationDownlink::DataFragment {
+                        session_index: (index_and_number >> SESSION_INDEX_SHIFT) as u8,
+                        number: index_and_number & FRAGMENT_NUMBER_MASK,
+                        data: reader.rest(),
+                    })
+                }
+                cid => Err(Error::UnknownMessageType(cid)),
+            });
+            done |= result.is_err();
+            Some(result)
+        })
+    }
+}
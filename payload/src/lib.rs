@@ -0,0 +1,100 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod alarm;
+pub mod backlog;
+pub mod clock_sync;
+mod codec;
+pub mod command;
+pub mod crash;
+pub mod downlink;
+pub mod fragmentation;
+pub mod load_profile;
+pub mod multicast;
+pub mod reset;
+pub mod status;
+pub mod supply;
+pub mod uplink;
+
+pub use alarm::{AlarmEvent, AlarmKind, Alarms, ALARM_FPORT, MAX_ALARM_EVENTS};
+pub use backlog::{Backlog, Reading, MAX_BACKLOG_READINGS};
+pub use clock_sync::{ClockSyncDownlink, ClockSyncUplink, CLOCK_SYNC_FPORT};
+pub use codec::{unzigzag, zigzag};
+pub use command::{Command, CommandAck, CommandResult, ConfigKey};
+pub use crash::{Crash, CrashHasher, Diagnostic, MAX_CRASH_ADDRESSES};
+pub use downlink::{Downlink, COMMAND_FPORT};
+pub use fragmentation::{
+    FragSessionSetupStatus, FragmentationDownlink, FragmentationUplink, FRAGMENTATION_FPORT,
+};
+pub use load_profile::{Bucket, BucketFlags, LoadProfile, MAX_LOAD_PROFILE_BUCKETS};
+pub use multicast::{
+    McClassCSessionStatus, McGroupAddress, MulticastDownlink, MulticastUplink,
+    MAX_MULTICAST_GROUPS, MULTICAST_FPORT,
+};
+pub use reset::ResetReason;
+pub use status::Status;
+pub use supply::Supply;
+pub use uplink::{Measurement, Uplink};
+
+/// The version of the uplink format, sent in the upper nibble of the header byte
+pub const FORMAT_VERSION: u8 = 1;
+
+/// The FPort all uplinks are sent on
+pub const UPLINK_FPORT: u8 = 1;
+
+/// The largest payload any region allows at any data rate
+pub const MAX_PAYLOAD_SIZE: usize = 242;
+
+/// The number of S0 channels the device reads
+pub const S0_CHANNEL_COUNT: usize = 6;
+
+/// The largest value an S0 counter can be set to, so its energy in Wh (impulses * 1000 / impulses per kWh) still fits
+/// 64 bits
+pub const MAX_COUNTER_IMPULSES: u64 = u64::MAX / 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The message ended before all fields were read
+    Truncated,
+    /// The message does not fit the buffer
+    BufferTooSmall,
+    /// A varint does not fit 64 bits
+    Overflow,
+    /// The message is longer than expected
+    TrailingData,
+    /// The message was sent in a format version this crate does not know
+    UnsupportedVersion(u8),
+    /// The message type or FPort is not known
+    UnknownMessageType(u8),
+    /// A value is out of range, e.g. a counter index larger than the number of channels
+    InvalidValue,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "message truncated"),
+            Error::BufferTooSmall => write!(f, "buffer too small"),
+            Error::Overflow => write!(f, "varint overflow"),
+            Error::TrailingData => write!(f, "unexpected data after the end of the message"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported format version {version}"),
+            Error::UnknownMessageType(message_type) => {
+                write!(f, "unknown message type {message_type}")
+            }
+            Error::InvalidValue => write!(f, "invalid value"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Serializes the fixed size arrays messages keep their entries in as a list of the entries present
+#[cfg(feature = "serde")]
+fn serialize_present<S: serde::Serializer, T: serde::Serialize, const N: usize>(
+    entries: &[Option<T>; N],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(entries.iter().flatten())
+}
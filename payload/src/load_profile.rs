@@ -0,0 +1,224 @@
+use crate::codec::{varint_size, Reader, Writer};
+use crate::{Error, S0_CHANNEL_COUNT};
+
+/// The most buckets one load profile message can hold
+pub const MAX_LOAD_PROFILE_BUCKETS: usize = 24;
+
+// The meter's import and export, then the S0 counters
+const CHANNEL_COUNT: usize = 2 + S0_CHANNEL_COUNT;
+
+/// What happened during the interval of a bucket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BucketFlags {
+    /// The device was reset, so the interval may not be complete
+    pub power_fail: bool,
+    /// The clock was set, so the interval may be longer or shorter
+    pub clock_adjusted: bool,
+    /// There was no measurement at the interval's start or end, the values are interpolated
+    pub estimated: bool,
+}
+
+impl BucketFlags {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn to_byte(self) -> u8 {
+        self.power_fail as u8 | (self.clock_adjusted as u8) << 1 | (self.estimated as u8) << 2
+    }
+
+    fn from_byte(flags: u8) -> Self {
+        Self {
+            power_fail: flags & 1 != 0,
+            clock_adjusted: flags & (1 << 1) != 0,
+            estimated: flags & (1 << 2) != 0,
+        }
+    }
+}
+
+/// The energy used during one interval. `None` if the value is unknown, e.g. because the meter did not respond.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Bucket {
+    pub flags: BucketFlags,
+    pub meter_import_wh: Option<u32>,
+    pub meter_export_wh: Option<u32>,
+    pub counter_wh: [Option<u32>; S0_CHANNEL_COUNT],
+}
+
+impl Bucket {
+    fn values(&self) -> impl Iterator<Item = Option<u32>> + '_ {
+        [self.meter_import_wh, self.meter_export_wh]
+            .into_iter()
+            .chain(self.counter_wh.iter().copied())
+    }
+
+    fn set_value(&mut self, channel: usize, value: Option<u32>) {
+        match channel {
+            0 => self.meter_import_wh = value,
+            1 => self.meter_export_wh = value,
+            channel => self.counter_wh[channel - 2] = value,
+        }
+    }
+}
+
+/// The energy used per fixed interval (e.g. 15 minutes) over a stretch of consecutive intervals.
+///
+/// Encoding:
+///   start              varint, the start of the first bucket in seconds since the unix epoch (UTC)
+///   interval           u8, in minutes
+///   channel mask       1 byte: bit 0 meter import, bit 1 meter export, bits 2 to 7 S0 counter 0 to 5. Channels
+///                      without a value in any bucket are left out.
+///   flag count         u8, the number of buckets with flags set, followed by the bucket index (u8) and the flags (u8,
+///                      bit 0 power fail, bit 1 clock adjusted, bit 2 estimated) of each
+///   buckets            until the end of the message. One varint per channel in the mask: the energy in Wh plus 1,
+///                      0 if the value is unknown
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LoadProfile {
+    pub start: u32,
+    pub interval_minutes: u8,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::serialize_present"))]
+    buckets: [Option<Bucket>; MAX_LOAD_PROFILE_BUCKETS],
+}
+
+impl LoadProfile {
+    pub fn new(start: u32, interval_minutes: u8) -> Self {
+        Self {
+            start,
+            interval_minutes,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the bucket following the last one. Returns false if the message is full.
+    pub fn push(&mut self, bucket: Bucket) -> bool {
+        match self.buckets.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(bucket);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the last bucket, e.g. because it made the message too large
+    pub fn pop(&mut self) -> Option<Bucket> {
+        self.buckets
+            .iter_mut()
+            .rev()
+            .find(|slot| slot.is_some())?
+            .take()
+    }
+
+    pub fn buckets(&self) -> impl Iterator<Item = &Bucket> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets[0].is_none()
+    }
+
+    /// The number of bytes the message takes, including the header byte
+    pub fn size(&self) -> usize {
+        let mask = self.channel_mask();
+        let flagged = self
+            .buckets()
+            .filter(|bucket| !bucket.flags.is_empty())
+            .count();
+        let values: usize = self
+            .buckets()
+            .flat_map(|bucket| masked(bucket, mask))
+            .map(|value| varint_size(value.map_or(0, |value| value as u64 + 1)))
+            .sum();
+        1 + varint_size(self.start as u64) + 3 + 2 * flagged + values
+    }
+
+    fn channel_mask(&self) -> u8 {
+        let mask = self.buckets().fold(0, |mask, bucket| {
+            bucket
+                .values()
+                .enumerate()
+                .filter(|(_, value)| value.is_some())
+                .fold(mask, |mask, (channel, _)| mask | 1 << channel)
+        });
+        // Without any channel, the buckets would take no space and their number would be lost
+        if mask == 0 {
+            1
+        } else {
+            mask
+        }
+    }
+
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        let mask = self.channel_mask();
+        writer.varint(self.start as u64)?;
+        writer.u8(self.interval_minutes)?;
+        writer.u8(mask)?;
+
+        let flagged = self
+            .buckets()
+            .filter(|bucket| !bucket.flags.is_empty())
+            .count();
+        writer.u8(flagged as u8)?;
+        for (index, bucket) in self.buckets().enumerate() {
+            if !bucket.flags.is_empty() {
+                writer.u8(index as u8)?;
+                writer.u8(bucket.flags.to_byte())?;
+            }
+        }
+
+        for value in self.buckets().flat_map(|bucket| masked(bucket, mask)) {
+            writer.varint(value.map_or(0, |value| value as u64 + 1))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let start = u32::try_from(reader.varint()?).map_err(|_| Error::InvalidValue)?;
+        let mut result = Self::new(start, reader.u8()?);
+        let mask = reader.u8()?;
+
+        let mut flags = [BucketFlags::default(); MAX_LOAD_PROFILE_BUCKETS];
+        for _ in 0..reader.u8()? {
+            let index = reader.u8()? as usize;
+            *flags.get_mut(index).ok_or(Error::InvalidValue)? =
+                BucketFlags::from_byte(reader.u8()?);
+        }
+
+        let mut index = 0;
+        while !reader.is_empty() {
+            let mut bucket = Bucket {
+                flags: *flags.get(index).ok_or(Error::TrailingData)?,
+                ..Default::default()
+            };
+            for channel in (0..CHANNEL_COUNT).filter(|channel| mask & (1 << channel) != 0) {
+                let value = match reader.varint()? {
+                    0 => None,
+                    value => Some(u32::try_from(value - 1).map_err(|_| Error::InvalidValue)?),
+                };
+                bucket.set_value(channel, value);
+            }
+            result.push(bucket);
+            index += 1;
+        }
+        Ok(result)
+    }
+}
+
+/// The values of the channels set in the mask
+fn masked(bucket: &Bucket, mask: u8) -> impl Iterator<Item = Option<u32>> + '_ {
+    bucket
+        .values()
+        .enumerate()
+        .filter(move |(channel, _)| mask & (1 << channel) != 0)
+        .map(|(_, value)| value)
+}
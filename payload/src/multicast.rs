@@ -0,0 +1,301 @@
+//! The LoRaWAN Remote Multicast Setup package (TS005 v1.0.0). The network hands out multicast group addresses and keys
+//! and schedules class C sessions, during which all devices of a group receive the same downlinks, e.g. the fragments
+//! of a firmware update. Several messages may be concatenated in one frame.
+
+use crate::codec::{messages, Writer};
+use crate::Error;
+
+/// The FPort reserved for the remote multicast setup package
+pub const MULTICAST_FPORT: u8 = 200;
+
+/// The number of multicast groups a device can be part of
+pub const MAX_MULTICAST_GROUPS: usize = 4;
+
+const PACKAGE_IDENTIFIER: u8 = 2;
+const PACKAGE_VERSION: u8 = 1;
+
+const PACKAGE_VERSION_CID: u8 = 0x00;
+const MC_GROUP_STATUS_CID: u8 = 0x01;
+const MC_GROUP_SETUP_CID: u8 = 0x02;
+const MC_GROUP_DELETE_CID: u8 = 0x03;
+const MC_CLASS_C_SESSION_CID: u8 = 0x04;
+
+const GROUP_ID_MASK: u8 = 0x03;
+
+/// A multicast group and the device address its downlinks are sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct McGroupAddress {
+    pub group_id: u8,
+    pub address: u32,
+}
+
+/// Why a class C session could not be scheduled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct McClassCSessionStatus {
+    pub datarate_error: bool,
+    pub frequency_error: bool,
+    pub group_undefined: bool,
+}
+
+impl McClassCSessionStatus {
+    pub fn is_ok(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Messages the device sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum MulticastUplink {
+    PackageVersionAns,
+    /// The groups asked for that are defined, and how many are defined in total
+    McGroupStatusAns {
+        total_groups: u8,
+        #[cfg_attr(feature = "serde", serde(serialize_with = "crate::serialize_present"))]
+        groups: [Option<McGroupAddress>; MAX_MULTICAST_GROUPS],
+    },
+    McGroupSetupAns {
+        group_id: u8,
+        id_error: bool,
+    },
+    McGroupDeleteAns {
+        group_id: u8,
+        group_undefined: bool,
+    },
+    /// If the session was scheduled, `time_to_start` is the number of seconds until it starts
+    McClassCSessionAns {
+        group_id: u8,
+        status: McClassCSessionStatus,
+        time_to_start: Option<u32>,
+    },
+}
+
+/// Messages the network sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum MulticastDownlink {
+    PackageVersionReq,
+    /// Bit X of the mask asks for the status of group X
+    McGroupStatusReq {
+        group_mask: u8,
+    },
+    /// Defines a group. The key is encrypted with the McKEKey derived from the device's root key.
+    McGroupSetupReq {
+        group_id: u8,
+        address: u32,
+        encrypted_key: [u8; 16],
+        min_fcount: u32,
+        max_fcount: u32,
+    },
+    McGroupDeleteReq {
+        group_id: u8,
+    },
+    /// Schedules a class C session of the group, starting at `session_time` (seconds since the GPS epoch, modulo
+    /// 2^32) and lasting 2^`timeout` seconds
+    McClassCSessionReq {
+        group_id: u8,
+        session_time: u32,
+        timeout: u8,
+        frequency_hz: u32,
+        datarate: u8,
+    },
+}
+
+impl MulticastUplink {
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        match *self {
+            MulticastUplink::PackageVersionAns => {
+                writer.bytes(&[PACKAGE_VERSION_CID, PACKAGE_IDENTIFIER, PACKAGE_VERSION])?;
+            }
+            MulticastUplink::McGroupStatusAns {
+                total_groups,
+                groups,
+            } => {
+                writer.u8(MC_GROUP_STATUS_CID)?;
+                let mask = groups.iter().flatten().fold(0, |mask, group| {
+                    mask | 1 << (group.group_id & GROUP_ID_MASK)
+                });
+                writer.u8(mask | (total_groups & 0x07) << 4)?;
+                for group in groups.iter().flatten() {
+                    writer.u8(group.group_id & GROUP_ID_MASK)?;
+                    writer.bytes(&group.address.to_le_bytes())?;
+                }
+            }
+            MulticastUplink::McGroupSetupAns { group_id, id_error } => {
+                writer.u8(MC_GROUP_SETUP_CID)?;
+                writer.u8(group_id & GROUP_ID_MASK | (id_error as u8) << 2)?;
+            }
+            MulticastUplink::McGroupDeleteAns {
+                group_id,
+                group_undefined,
+            } => {
+                writer.u8(MC_GROUP_DELETE_CID)?;
+                writer.u8(group_id & GROUP_ID_MASK | (group_undefined as u8) << 2)?;
+            }
+            MulticastUplink::McClassCSessionAns {
+                group_id,
+                status,
+                time_to_start,
+            } => {
+                writer.u8(MC_CLASS_C_SESSION_CID)?;
+                writer.u8(group_id & GROUP_ID_MASK
+                    | (status.datarate_error as u8) << 2
+                    | (status.frequency_error as u8) << 3
+                    | (status.group_undefined as u8) << 4)?;
+                if status.is_ok() {
+                    let time_to_start = time_to_start.ok_or(Error::InvalidValue)?;
+                    writer.bytes(&time_to_start.to_le_bytes()[..3])?;
+                }
+            }
+        }
+        Ok(writer.position())
+    }
+
+    /// Decodes all messages contained in the payload
+    pub fn decode(payload: &[u8]) -> impl Iterator<Item = Result<Self, Error>> + '_ {
+        messages(payload, |cid, reader| match cid {
+            PACKAGE_VERSION_CID => {
+                let [identifier, version] = reader.bytes()?;
+                if identifier != PACKAGE_IDENTIFIER || version != PACKAGE_VERSION {
+                    return Err(Error::InvalidValue);
+                }
+                Ok(MulticastUplink::PackageVersionAns)
+            }
+            MC_GROUP_STATUS_CID => {
+                let status = reader.u8()?;
+                let mut groups = [None; MAX_MULTICAST_GROUPS];
+                for slot in groups
+                    .iter_mut()
+                    .take((status & 0x0F).count_ones() as usize)
+                {
+                    *slot = Some(McGroupAddress {
+                        group_id: reader.u8()? & GROUP_ID_MASK,
+                        address: u32::from_le_bytes(reader.bytes()?),
+                    });
+                }
+                Ok(MulticastUplink::McGroupStatusAns {
+                    total_groups: (status >> 4) & 0x07,
+                    groups,
+                })
+            }
+            MC_GROUP_SETUP_CID => {
+                let status = reader.u8()?;
+                Ok(MulticastUplink::McGroupSetupAns {
+                    group_id: status & GROUP_ID_MASK,
+                    id_error: status & (1 << 2) != 0,
+                })
+            }
+            MC_GROUP_DELETE_CID => {
+                let status = reader.u8()?;
+                Ok(MulticastUplink::McGroupDeleteAns {
+                    group_id: status & GROUP_ID_MASK,
+                    group_undefined: status & (1 << 2) != 0,
+                })
+            }
+            MC_CLASS_C_SESSION_CID => {
+                let flags = reader.u8()?;
+                let status = McClassCSessionStatus {
+                    datarate_error: flags & (1 << 2) != 0,
+                    frequency_error: flags & (1 << 3) != 0,
+                    group_undefined: flags & (1 << 4) != 0,
+                };
+                let time_to_start = match status.is_ok() {
+                    true => Some(u24(reader.bytes()?)),
+                    false => None,
+                };
+                Ok(MulticastUplink::McClassCSessionAns {
+                    group_id: flags & GROUP_ID_MASK,
+                    status,
+                    time_to_start,
+                })
+            }
+            cid => Err(Error::UnknownMessageType(cid)),
+        })
+    }
+}
+
+impl MulticastDownlink {
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        match *self {
+            MulticastDownlink::PackageVersionReq => writer.u8(PACKAGE_VERSION_CID)?,
+            MulticastDownlink::McGroupStatusReq { group_mask } => {
+                writer.bytes(&[MC_GROUP_STATUS_CID, group_mask & 0x0F])?;
+            }
+            MulticastDownlink::McGroupSetupReq {
+                group_id,
+                address,
+                encrypted_key,
+                min_fcount,
+                max_fcount,
+            } => {
+                writer.bytes(&[MC_GROUP_SETUP_CID, group_id & GROUP_ID_MASK])?;
+                writer.bytes(&address.to_le_bytes())?;
+                writer.bytes(&encrypted_key)?;
+                writer.bytes(&min_fcount.to_le_bytes())?;
+                writer.bytes(&max_fcount.to_le_bytes())?;
+            }
+            MulticastDownlink::McGroupDeleteReq { group_id } => {
+                writer.bytes(&[MC_GROUP_DELETE_CID, group_id & GROUP_ID_MASK])?;
+            }
+            MulticastDownlink::McClassCSessionReq {
+                group_id,
+                session_time,
+                timeout,
+                frequency_hz,
+                datarate,
+            } => {
+                writer.bytes(&[MC_CLASS_C_SESSION_CID, group_id & GROUP_ID_MASK])?;
+                writer.bytes(&session_time.to_le_bytes())?;
+                writer.u8(timeout & 0x0F)?;
+                writer.bytes(&(frequency_hz / 100).to_le_bytes()[..3])?;
+                writer.u8(datarate)?;
+            }
+        }
+        Ok(writer.position())
+    }
+
+    /// Decodes all messages contained in the payload
+    pub fn decode(payload: &[u8]) -> impl Iterator<Item = Result<Self, Error>> + '_ {
+        messages(payload, |cid, reader| match cid {
+            PACKAGE_VERSION_CID => Ok(MulticastDownlink::PackageVersionReq),
+            MC_GROUP_STATUS_CID => Ok(MulticastDownlink::McGroupStatusReq {
+                group_mask: reader.u8()? & 0x0F,
+            }),
+            MC_GROUP_SETUP_CID => Ok(MulticastDownlink::McGroupSetupReq {
+                group_id: reader.u8()? & GROUP_ID_MASK,
+                address: u32::from_le_bytes(reader.bytes()?),
+                encrypted_key: reader.bytes()?,
+                min_fcount: u32::from_le_bytes(reader.bytes()?),
+                max_fcount: u32::from_le_bytes(reader.bytes()?),
+            }),
+            MC_GROUP_DELETE_CID => Ok(MulticastDownlink::McGroupDeleteReq {
+                group_id: reader.u8()? & GROUP_ID_MASK,
+            }),
+            MC_CLASS_C_SESSION_CID => Ok(MulticastDownlink::McClassCSessionReq {
+                group_id: reader.u8()? & GROUP_ID_MASK,
+                session_time: u32::from_le_bytes(reader.bytes()?),
+                timeout: reader.u8()? & 0x0F,
+                // Sent in units of 100 Hz
+                frequency_hz: u24(reader.bytes()?) * 100,
+                datarate: reader.u8()?,
+            }),
+            cid => Err(Error::UnknownMessageType(cid)),
+        })
+    }
+}
+
+fn u24(bytes: [u8; 3]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
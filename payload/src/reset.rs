@@ -0,0 +1,82 @@
+use crate::codec::{Reader, Writer};
+use crate::Error;
+
+/// Why the device started, sent in the first measurement after booting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ResetReason {
+    /// 0: The power was switched on (or the reset pin pulled)
+    PowerOn,
+    /// 1: The watchdog was not fed, without the supervisor knowing why, e.g. because the executor got stuck
+    Watchdog,
+    /// 2: The main loop stopped making progress, e.g. while reading the meter
+    MainLoopStalled,
+    /// 3: The radio did not finish an operation in time
+    RadioStalled,
+    /// 4: An S0 counter task stopped running
+    CounterStalled,
+    /// 5: The reboot command
+    Requested,
+    /// 6: A firmware update was received, the bootloader swapped it in
+    FirmwareUpdate,
+    /// 7: A new firmware did not confirm that it works in time, the bootloader swapped the previous one back in
+    TrialFailed,
+    /// 8: The supply voltage dropped too low for a moment, the RAM kept its contents
+    BrownOut,
+    /// 9: The firmware panicked
+    Panic,
+    /// 10: The processor ran into a hard fault, e.g. an invalid memory access
+    HardFault,
+}
+
+impl ResetReason {
+    pub(crate) const SIZE: usize = 1;
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ResetReason::PowerOn => 0,
+            ResetReason::Watchdog => 1,
+            ResetReason::MainLoopStalled => 2,
+            ResetReason::RadioStalled => 3,
+            ResetReason::CounterStalled => 4,
+            ResetReason::Requested => 5,
+            ResetReason::FirmwareUpdate => 6,
+            ResetReason::TrialFailed => 7,
+            ResetReason::BrownOut => 8,
+            ResetReason::Panic => 9,
+            ResetReason::HardFault => 10,
+        }
+    }
+
+    /// Whether the firmware crashed, which is reported with a diagnostic uplink
+    pub fn is_crash(self) -> bool {
+        matches!(self, ResetReason::Panic | ResetReason::HardFault)
+    }
+
+    pub fn from_byte(reason: u8) -> Result<Self, Error> {
+        match reason {
+            0 => Ok(ResetReason::PowerOn),
+            1 => Ok(ResetReason::Watchdog),
+            2 => Ok(ResetReason::MainLoopStalled),
+            3 => Ok(ResetReason::RadioStalled),
+            4 => Ok(ResetReason::CounterStalled),
+            5 => Ok(ResetReason::Requested),
+            6 => Ok(ResetReason::FirmwareUpdate),
+            7 => Ok(ResetReason::TrialFailed),
+            8 => Ok(ResetReason::BrownOut),
+            9 => Ok(ResetReason::Panic),
+            10 => Ok(ResetReason::HardFault),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.u8(self.to_byte())
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        Self::from_byte(reader.u8()?)
+    }
+}
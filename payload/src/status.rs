@@ -0,0 +1,158 @@
+use crate::codec::{Reader, Writer};
+use crate::Error;
+
+/// Information about the device itself, sent when requested by the backend.
+///
+/// Encoding: 2-byte presence bitmask (little endian), then each present field in ascending bit order:
+///   bit 0: firmware version       3 bytes: major, minor, patch
+///   bit 1: uptime                 varint, in seconds
+///   bit 2: measurement interval   varint, in seconds
+///   bit 3: confirmed uplinks      varint, sent since boot
+///   bit 4: unacknowledged uplinks varint, confirmed uplinks sent since boot that were not acknowledged
+///   bit 5: link margin            u8, in dB above the demodulation floor, from the last link check
+///   bit 6: gateway count          u8, the gateways that received the last link check
+///   bit 7: data rate              u8, the region's data rate index used for uplinks
+///   bit 8: transmit power         signed varint, in dBm
+///   bit 9: RSSI                   signed varint, in dBm, of the last downlink
+///   bit 10: SNR                   signed varint, in dB, of the last downlink
+///   bit 11: airtime               varint, in milliseconds, used by uplinks over the last 24 hours
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Status {
+    pub firmware_version: Option<[u8; 3]>,
+    pub uptime_s: Option<u64>,
+    pub measurement_interval_s: Option<u32>,
+    pub confirmed_uplinks: Option<u32>,
+    pub unacknowledged_uplinks: Option<u32>,
+    pub link_margin_db: Option<u8>,
+    pub gateway_count: Option<u8>,
+    pub datarate: Option<u8>,
+    pub tx_power_dbm: Option<i8>,
+    pub rssi_dbm: Option<i16>,
+    pub snr_db: Option<i8>,
+    pub airtime_last_day_ms: Option<u32>,
+}
+
+impl Status {
+    fn presence(&self) -> u16 {
+        let mut presence = 0;
+        for (index, present) in [
+            self.firmware_version.is_some(),
+            self.uptime_s.is_some(),
+            self.measurement_interval_s.is_some(),
+            self.confirmed_uplinks.is_some(),
+            self.unacknowledged_uplinks.is_some(),
+            self.link_margin_db.is_some(),
+            self.gateway_count.is_some(),
+            self.datarate.is_some(),
+            self.tx_power_dbm.is_some(),
+            self.rssi_dbm.is_some(),
+            self.snr_db.is_some(),
+            self.airtime_last_day_ms.is_some(),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if present {
+                presence |= 1 << index;
+            }
+        }
+        presence
+    }
+
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.bytes(&self.presence().to_le_bytes())?;
+        if let Some(version) = self.firmware_version {
+            writer.bytes(&version)?;
+        }
+        if let Some(uptime) = self.uptime_s {
+            writer.varint(uptime)?;
+        }
+        for value in [
+            self.measurement_interval_s,
+            self.confirmed_uplinks,
+            self.unacknowledged_uplinks,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            writer.varint(value as u64)?;
+        }
+        for value in [self.link_margin_db, self.gateway_count, self.datarate]
+            .into_iter()
+            .flatten()
+        {
+            writer.u8(value)?;
+        }
+        for value in [
+            self.tx_power_dbm.map(i16::from),
+            self.rssi_dbm,
+            self.snr_db.map(i16::from),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            writer.signed_varint(value as i64)?;
+        }
+        if let Some(airtime) = self.airtime_last_day_ms {
+            writer.varint(airtime as u64)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let presence = u16::from_le_bytes(reader.bytes()?);
+        let present = |index: usize| presence & (1 << index) != 0;
+        let mut result = Self::default();
+        if present(0) {
+            result.firmware_version = Some(reader.bytes()?);
+        }
+        if present(1) {
+            result.uptime_s = Some(reader.varint()?);
+        }
+        let mut u32_field = |index: usize| -> Result<Option<u32>, Error> {
+            if !present(index) {
+                return Ok(None);
+            }
+            u32::try_from(reader.varint()?)
+                .map(Some)
+                .map_err(|_| Error::InvalidValue)
+        };
+        result.measurement_interval_s = u32_field(2)?;
+        result.confirmed_uplinks = u32_field(3)?;
+        result.unacknowledged_uplinks = u32_field(4)?;
+        if present(5) {
+            result.link_margin_db = Some(reader.u8()?);
+        }
+        if present(6) {
+            result.gateway_count = Some(reader.u8()?);
+        }
+        if present(7) {
+            result.datarate = Some(reader.u8()?);
+        }
+        let mut signed_field = |index: usize| -> Result<Option<i64>, Error> {
+            if !present(index) {
+                return Ok(None);
+            }
+            reader.signed_varint().map(Some)
+        };
+        result.tx_power_dbm = signed_field(8)?
+            .map(i8::try_from)
+            .transpose()
+            .map_err(|_| Error::InvalidValue)?;
+        result.rssi_dbm = signed_field(9)?
+            .map(i16::try_from)
+            .transpose()
+            .map_err(|_| Error::InvalidValue)?;
+        result.snr_db = signed_field(10)?
+            .map(i8::try_from)
+            .transpose()
+            .map_err(|_| Error::InvalidValue)?;
+        if present(11) {
+            result.airtime_last_day_ms =
+                Some(u32::try_from(reader.varint()?).map_err(|_| Error::InvalidValue)?);
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,42 @@
+use crate::codec::{varint_size, Reader, Writer};
+use crate::Error;
+
+// Sent instead of the battery level if the device is mains powered (or the level is unknown)
+const NO_BATTERY_LEVEL: u8 = 0xFF;
+
+/// The supply voltage and what it means for the battery, sent with the measurements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Supply {
+    pub millivolts: u16,             // Of the battery (or VSYS, if mains powered)
+    pub battery_percent: Option<u8>, // Estimated from the battery's discharge curve, 0 to 100
+}
+
+impl Supply {
+    pub(crate) fn size(&self) -> usize {
+        varint_size(self.millivolts as u64) + 1
+    }
+
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.varint(self.millivolts as u64)?;
+        match self.battery_percent {
+            Some(percent) if percent <= 100 => writer.u8(percent),
+            Some(_) => Err(Error::InvalidValue),
+            None => writer.u8(NO_BATTERY_LEVEL),
+        }
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let millivolts = u16::try_from(reader.varint()?).map_err(|_| Error::InvalidValue)?;
+        let battery_percent = match reader.u8()? {
+            NO_BATTERY_LEVEL => None,
+            percent if percent <= 100 => Some(percent),
+            _ => return Err(Error::InvalidValue),
+        };
+        Ok(Self {
+            millivolts,
+            battery_percent,
+        })
+    }
+}
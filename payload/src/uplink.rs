@@ -0,0 +1,278 @@
+use crate::codec::{signed_varint_size, varint_size, Reader, Writer};
+use crate::{
+    Backlog, CommandAck, Diagnostic, Error, LoadProfile, Reading, ResetReason, Status, Supply,
+    FORMAT_VERSION, S0_CHANNEL_COUNT,
+};
+
+const MEASUREMENT_MESSAGE: u8 = 0;
+const STATUS_MESSAGE: u8 = 1;
+const BACKLOG_MESSAGE: u8 = 2;
+const LOAD_PROFILE_MESSAGE: u8 = 3;
+const DIAGNOSTIC_MESSAGE: u8 = 4;
+
+/// Everything the device sends to the backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+#[allow(clippy::large_enum_variant)] // There is no heap to box the larger messages on, and only one message exists at a time
+pub enum Uplink {
+    Measurement(Measurement),
+    Status(Status),
+    Backlog(Backlog),
+    LoadProfile(LoadProfile),
+    Diagnostic(Diagnostic),
+}
+
+impl Uplink {
+    /// Encodes the message into the buffer, returning the number of bytes used
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(buf);
+        match self {
+            Uplink::Measurement(measurement) => {
+                writer.u8(header(MEASUREMENT_MESSAGE))?;
+                measurement.encode(&mut writer)?;
+            }
+            Uplink::Status(status) => {
+                writer.u8(header(STATUS_MESSAGE))?;
+                status.encode(&mut writer)?;
+            }
+            Uplink::Backlog(backlog) => {
+                writer.u8(header(BACKLOG_MESSAGE))?;
+                backlog.encode(&mut writer)?;
+            }
+            Uplink::LoadProfile(load_profile) => {
+                writer.u8(header(LOAD_PROFILE_MESSAGE))?;
+                load_profile.encode(&mut writer)?;
+            }
+            Uplink::Diagnostic(diagnostic) => {
+                writer.u8(header(DIAGNOSTIC_MESSAGE))?;
+                diagnostic.encode(&mut writer)?;
+            }
+        }
+        Ok(writer.position())
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+        let header = reader.u8()?;
+        let version = header >> 4;
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        match header & 0x0F {
+            MEASUREMENT_MESSAGE => Ok(Uplink::Measurement(Measurement::decode(&mut reader)?)),
+            STATUS_MESSAGE => Ok(Uplink::Status(Status::decode(&mut reader)?)),
+            BACKLOG_MESSAGE => Ok(Uplink::Backlog(Backlog::decode(&mut reader)?)),
+            LOAD_PROFILE_MESSAGE => Ok(Uplink::LoadProfile(LoadProfile::decode(&mut reader)?)),
+            DIAGNOSTIC_MESSAGE => Ok(Uplink::Diagnostic(Diagnostic::decode(&mut reader)?)),
+            message_type => Err(Error::UnknownMessageType(message_type)),
+        }
+    }
+}
+
+fn header(message_type: u8) -> u8 {
+    (FORMAT_VERSION << 4) | message_type
+}
+
+/// The values measured in one cycle. Everything that is `None` is left out of the message.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Measurement {
+    pub flash_wear: Option<u8>, // 0 is new, 255 totally worn
+    pub temperature_decicelsius: Option<i16>,
+    pub meter_import_wh: Option<u64>, // From the IEC62056 connection
+    pub meter_export_wh: Option<u64>, // From the IEC62056 connection
+    pub meter_id: Option<u64>,        // The IEC62056 meter's serial number
+    pub counter_wh: [Option<u64>; S0_CHANNEL_COUNT], // From the S0 counters
+    pub command_ack: Option<CommandAck>, // The result of the last command received
+    pub timestamp: Option<u32>, // When the values were measured, in seconds since the unix epoch (UTC)
+    pub reset_reason: Option<ResetReason>, // Why the device started, only in the first measurement after booting
+    pub supply: Option<Supply>, // The supply voltage and battery level, if the board can measure it
+    pub humidity_permille: Option<u16>, // Relative humidity in the cabinet, if the sensor measures it
+}
+
+impl Measurement {
+    /// The number of fields, each one has a bit in the presence bitmask
+    pub const FIELD_COUNT: usize = 10 + S0_CHANNEL_COUNT;
+    const COMMAND_ACK_FIELD: usize = 5 + S0_CHANNEL_COUNT;
+    const TIMESTAMP_FIELD: usize = 6 + S0_CHANNEL_COUNT;
+    const RESET_REASON_FIELD: usize = 7 + S0_CHANNEL_COUNT;
+    const SUPPLY_FIELD: usize = 8 + S0_CHANNEL_COUNT;
+    const HUMIDITY_FIELD: usize = 9 + S0_CHANNEL_COUNT;
+    const MAX_HUMIDITY_PERMILLE: u16 = 1000;
+
+    /// The size of the header byte and the presence bitmask
+    pub const HEADER_SIZE: usize = 3;
+
+    /// The bitmask of the fields contained in the message
+    pub fn presence(&self) -> u16 {
+        (0..Self::FIELD_COUNT)
+            .filter(|index| self.field_size(*index) > 0)
+            .fold(0, |presence, index| presence | 1 << index)
+    }
+
+    /// The number of bytes the field with the given presence bit takes, 0 if it is absent
+    pub fn field_size(&self, index: usize) -> usize {
+        match index {
+            0 => self.flash_wear.map_or(0, |_| 1),
+            1 => self
+                .temperature_decicelsius
+                .map_or(0, |value| signed_varint_size(value as i64)),
+            2 => self.meter_import_wh.map_or(0, varint_size),
+            3 => self.meter_export_wh.map_or(0, varint_size),
+            4 => self.meter_id.map_or(0, varint_size),
+            Self::COMMAND_ACK_FIELD => self.command_ack.map_or(0, |_| CommandAck::SIZE),
+            Self::TIMESTAMP_FIELD => self.timestamp.map_or(0, |value| varint_size(value as u64)),
+            Self::RESET_REASON_FIELD => self.reset_reason.map_or(0, |_| ResetReason::SIZE),
+            Self::SUPPLY_FIELD => self.supply.map_or(0, |supply| supply.size()),
+            Self::HUMIDITY_FIELD => self
+                .humidity_permille
+                .map_or(0, |value| varint_size(value as u64)),
+            index if index < Self::COMMAND_ACK_FIELD => {
+                self.counter_wh[index - 5].map_or(0, varint_size)
+            }
+            _ => 0,
+        }
+    }
+
+    /// The energy values to keep in case the message does not arrive. Only measurements with a timestamp can be sent
+    /// again later.
+    pub fn reading(&self) -> Option<Reading> {
+        Some(Reading {
+            timestamp: self.timestamp?,
+            meter_import_wh: self.meter_import_wh,
+            meter_export_wh: self.meter_export_wh,
+            counter_wh: self.counter_wh,
+        })
+    }
+
+    /// Removes all fields whose bit is not set in the mask
+    pub fn retain(&mut self, mask: u16) {
+        let keep = |index: usize| mask & (1 << index) != 0;
+        if !keep(0) {
+            self.flash_wear = None;
+        }
+        if !keep(1) {
+            self.temperature_decicelsius = None;
+        }
+        if !keep(2) {
+            self.meter_import_wh = None;
+        }
+        if !keep(3) {
+            self.meter_export_wh = None;
+        }
+        if !keep(4) {
+            self.meter_id = None;
+        }
+        for (channel, counter) in self.counter_wh.iter_mut().enumerate() {
+            if !keep(5 + channel) {
+                *counter = None;
+            }
+        }
+        if !keep(Self::COMMAND_ACK_FIELD) {
+            self.command_ack = None;
+        }
+        if !keep(Self::TIMESTAMP_FIELD) {
+            self.timestamp = None;
+        }
+        if !keep(Self::RESET_REASON_FIELD) {
+            self.reset_reason = None;
+        }
+        if !keep(Self::SUPPLY_FIELD) {
+            self.supply = None;
+        }
+        if !keep(Self::HUMIDITY_FIELD) {
+            self.humidity_permille = None;
+        }
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.bytes(&self.presence().to_le_bytes())?;
+        if let Some(value) = self.flash_wear {
+            writer.u8(value)?;
+        }
+        if let Some(value) = self.temperature_decicelsius {
+            writer.signed_varint(value as i64)?;
+        }
+        for value in [self.meter_import_wh, self.meter_export_wh, self.meter_id]
+            .iter()
+            .chain(self.counter_wh.iter())
+            .flatten()
+        {
+            writer.varint(*value)?;
+        }
+        if let Some(ack) = self.command_ack {
+            ack.encode(writer)?;
+        }
+        if let Some(value) = self.timestamp {
+            writer.varint(value as u64)?;
+        }
+        if let Some(reason) = self.reset_reason {
+            reason.encode(writer)?;
+        }
+        if let Some(supply) = self.supply {
+            supply.encode(writer)?;
+        }
+        if let Some(value) = self.humidity_permille {
+            if value > Self::MAX_HUMIDITY_PERMILLE {
+                return Err(Error::InvalidValue);
+            }
+            writer.varint(value as u64)?;
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let presence = u16::from_le_bytes(reader.bytes()?);
+        let present = |index: usize| presence & (1 << index) != 0;
+        let mut result = Self::default();
+        if present(0) {
+            result.flash_wear = Some(reader.u8()?);
+        }
+        if present(1) {
+            let value = reader.signed_varint()?;
+            result.temperature_decicelsius =
+                Some(i16::try_from(value).map_err(|_| Error::InvalidValue)?);
+        }
+        if present(2) {
+            result.meter_import_wh = Some(reader.varint()?);
+        }
+        if present(3) {
+            result.meter_export_wh = Some(reader.varint()?);
+        }
+        if present(4) {
+            result.meter_id = Some(reader.varint()?);
+        }
+        for (channel, counter) in result.counter_wh.iter_mut().enumerate() {
+            if present(5 + channel) {
+                *counter = Some(reader.varint()?);
+            }
+        }
+        if present(Self::COMMAND_ACK_FIELD) {
+            result.command_ack = Some(CommandAck::decode(reader)?);
+        }
+        if present(Self::TIMESTAMP_FIELD) {
+            let value = reader.varint()?;
+            result.timestamp = Some(u32::try_from(value).map_err(|_| Error::InvalidValue)?);
+        }
+        if present(Self::RESET_REASON_FIELD) {
+            result.reset_reason = Some(ResetReason::decode(reader)?);
+        }
+        if present(Self::SUPPLY_FIELD) {
+            result.supply = Some(Supply::decode(reader)?);
+        }
+        if present(Self::HUMIDITY_FIELD) {
+            let value = reader.varint()?;
+            result.humidity_permille = Some(
+                u16::try_from(value)
+                    .ok()
+                    .filter(|value| *value <= Self::MAX_HUMIDITY_PERMILLE)
+                    .ok_or(Error::InvalidValue)?,
+            );
+        }
+        // Fields added in later revisions of this version are at the end, so we can stop here
+        Ok(result)
+    }
+}
@@ -0,0 +1,71 @@
+#![cfg(feature = "cli")]
+
+use std::process::{Command, Output};
+
+fn decode(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_powermeter-decode"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn decode_json(args: &[&str]) -> serde_json::Value {
+    let output = decode(args);
+    assert!(output.status.success(), "{output:?}");
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn measurement() {
+    // Only the flash wear of 3
+    let json = decode_json(&["10010003"]);
+    assert_eq!(json["type"], "measurement");
+    assert_eq!(json["flash_wear"], 3);
+    assert_eq!(json["temperature_decicelsius"], serde_json::Value::Null);
+}
+
+#[test]
+fn base64() {
+    // The same message as 10 01 00 03
+    assert_eq!(decode_json(&["EAEAAw=="])["flash_wear"], 3);
+}
+
+#[test]
+fn alarm() {
+    // 72 °C, without a timestamp
+    let json = decode_json(&["--fport", "20", "100001a00b"]);
+    assert_eq!(json["timestamp"], serde_json::Value::Null);
+    assert_eq!(json["events"][0]["kind"], "temperature_high");
+    assert_eq!(json["events"][0]["active"], true);
+    assert_eq!(json["events"][0]["value"], 720);
+}
+
+#[test]
+fn downlink() {
+    // Set the first counter to 900 impulses
+    let json = decode_json(&["--downlink", "--fport", "1", "8403000000000000"]);
+    assert_eq!(json["type"], "set_counter");
+    assert_eq!(json["counter"], 0);
+    assert_eq!(json["impulses"], 900);
+}
+
+#[test]
+fn command() {
+    // Tag 7, set the interval to 900 s
+    let json = decode_json(&["--downlink", "--fport", "10", "07038407"]);
+    assert_eq!(json["type"], "command");
+    assert_eq!(json["tag"], 7);
+    assert_eq!(json["command"]["command"], "set_interval");
+    assert_eq!(json["command"]["seconds"], 900);
+}
+
+#[test]
+fn invalid() {
+    let truncated = decode(&["100101"]);
+    assert!(!truncated.status.success());
+    assert!(String::from_utf8_lossy(&truncated.stderr).contains("message truncated"));
+
+    assert!(!decode(&["not a payload!"]).status.success());
+    assert!(!decode(&["--fport", "99", "00"]).status.success());
+    assert!(!decode(&[]).status.success());
+}
@@ -0,0 +1,845 @@
+use powermeter_payload::{
+    crash, unzigzag, zigzag, AlarmEvent, AlarmKind, Alarms, Bucket, BucketFlags, ClockSyncDownlink,
+    ClockSyncUplink, Command, CommandAck, CommandResult, ConfigKey, Crash, CrashHasher, Diagnostic,
+    Downlink, Error, FragSessionSetupStatus, FragmentationDownlink, FragmentationUplink,
+    LoadProfile, McClassCSessionStatus, McGroupAddress, Measurement, MulticastDownlink,
+    MulticastUplink, ResetReason, Status, Supply, Uplink, COMMAND_FPORT, MAX_ALARM_EVENTS,
+    MAX_COUNTER_IMPULSES, MAX_CRASH_ADDRESSES, MAX_PAYLOAD_SIZE, S0_CHANNEL_COUNT,
+};
+
+/// Encodes the message, checks that every smaller buffer is rejected and returns the encoding
+fn encode(encode: impl Fn(&mut [u8]) -> Result<usize, Error>) -> Vec<u8> {
+    let mut buf = [0; MAX_PAYLOAD_SIZE];
+    let len = encode(&mut buf).unwrap();
+    for short in 0..len {
+        assert_eq!(
+            encode(&mut buf[..short]),
+            Err(Error::BufferTooSmall),
+            "{short} bytes"
+        );
+    }
+    buf[..len].to_vec()
+}
+
+fn round_trip_uplink(uplink: Uplink) -> Vec<u8> {
+    let bytes = encode(|buf| uplink.encode(buf));
+    assert_eq!(Uplink::decode(&bytes), Ok(uplink));
+    bytes
+}
+
+fn round_trip_downlink(downlink: Downlink) -> (u8, Vec<u8>) {
+    let fport = downlink.encode(&mut [0; MAX_PAYLOAD_SIZE]).unwrap().0;
+    let bytes = encode(|buf| downlink.encode(buf).map(|(_, len)| len));
+    assert_eq!(Downlink::decode(fport, &bytes), Ok(downlink));
+    (fport, bytes)
+}
+
+/// Checks that the message is rejected if any part of its end is missing. An empty payload is a valid list of
+/// messages for the iterators, so it is left out.
+fn assert_truncations_fail<T>(bytes: &[u8], decode: impl Fn(&[u8]) -> Result<T, Error>) {
+    for len in 1..bytes.len() {
+        assert!(decode(&bytes[..len]).is_err(), "{len} bytes");
+    }
+}
+
+fn full_measurement() -> Measurement {
+    Measurement {
+        flash_wear: Some(3),
+        temperature_decicelsius: Some(-125),
+        meter_import_wh: Some(12_345_678),
+        meter_export_wh: Some(0),
+        meter_id: Some(u64::MAX),
+        counter_wh: [
+            Some(1),
+            None,
+            Some(MAX_COUNTER_IMPULSES),
+            None,
+            None,
+            Some(300),
+        ],
+        command_ack: Some(CommandAck {
+            tag: 42,
+            result: CommandResult::OutOfRange,
+        }),
+        timestamp: Some(1_700_000_000),
+        reset_reason: Some(ResetReason::Watchdog),
+        supply: Some(Supply {
+            millivolts: 3_612,
+            battery_percent: Some(87),
+        }),
+        humidity_permille: Some(1_000),
+    }
+}
+
+#[test]
+fn measurement() {
+    let bytes = round_trip_uplink(Uplink::Measurement(full_measurement()));
+    assert_truncations_fail(&bytes, Uplink::decode);
+    round_trip_uplink(Uplink::Measurement(Measurement::default()));
+
+    let measurement = Measurement {
+        flash_wear: Some(3),
+        ..Default::default()
+    };
+    let bytes = round_trip_uplink(Uplink::Measurement(measurement.clone()));
+    assert_eq!(bytes, [0x10, 0x01, 0x00, 0x03]);
+    assert_eq!(measurement.presence(), 0x0001);
+}
+
+#[test]
+fn measurement_later_fields() {
+    // Fields added in later revisions of the format follow the known ones and are skipped
+    let mut bytes = round_trip_uplink(Uplink::Measurement(full_measurement()));
+    bytes.push(0);
+    assert_eq!(
+        Uplink::decode(&bytes),
+        Ok(Uplink::Measurement(full_measurement()))
+    );
+}
+
+#[test]
+fn status() {
+    let status = Status {
+        firmware_version: Some([1, 2, 3]),
+        uptime_s: Some(u64::MAX),
+        measurement_interval_s: Some(900),
+        confirmed_uplinks: Some(17),
+        unacknowledged_uplinks: Some(2),
+        link_margin_db: Some(20),
+        gateway_count: Some(3),
+        datarate: Some(5),
+        tx_power_dbm: Some(-4),
+        rssi_dbm: Some(-120),
+        snr_db: Some(-20),
+        airtime_last_day_ms: Some(36_000),
+    };
+    let bytes = round_trip_uplink(Uplink::Status(status));
+    assert_truncations_fail(&bytes, Uplink::decode);
+    round_trip_uplink(Uplink::Status(Status::default()));
+}
+
+#[test]
+fn supply() {
+    // Mains powered: the voltage, without a battery level
+    let measurement = Measurement {
+        supply: Some(Supply {
+            millivolts: 5_000,
+            battery_percent: None,
+        }),
+        ..Default::default()
+    };
+    let bytes = round_trip_uplink(Uplink::Measurement(measurement));
+    assert_eq!(bytes, [0x10, 0x00, 0x40, 0x88, 0x27, 0xFF]);
+    assert_eq!(
+        Uplink::decode(&[0x10, 0x00, 0x40, 0x88, 0x27, 101]),
+        Err(Error::InvalidValue)
+    );
+    let invalid = Measurement {
+        supply: Some(Supply {
+            millivolts: 3_000,
+            battery_percent: Some(101),
+        }),
+        ..Default::default()
+    };
+    let mut buf = [0u8; MAX_PAYLOAD_SIZE];
+    assert_eq!(
+        Uplink::Measurement(invalid).encode(&mut buf),
+        Err(Error::InvalidValue)
+    );
+}
+
+#[test]
+fn humidity() {
+    let measurement = Measurement {
+        humidity_permille: Some(555),
+        ..Default::default()
+    };
+    let bytes = round_trip_uplink(Uplink::Measurement(measurement));
+    assert_eq!(bytes, [0x10, 0x00, 0x80, 0xAB, 0x04]);
+    assert_eq!(
+        Uplink::decode(&[0x10, 0x00, 0x80, 0xE9, 0x07]),
+        Err(Error::InvalidValue)
+    );
+}
+
+#[test]
+fn signed_config_values() {
+    for value in [0, -1, 1, -200, 200, i64::MIN, i64::MAX] {
+        assert_eq!(unzigzag(zigzag(value)), value);
+    }
+    assert_eq!(zigzag(-25), 49);
+}
+
+#[test]
+fn measurement_retain() {
+    let mut measurement = full_measurement();
+    measurement.retain(0b100_0000_0011);
+    assert_eq!(
+        measurement,
+        Measurement {
+            flash_wear: Some(3),
+            temperature_decicelsius: Some(-125),
+            counter_wh: [None, None, None, None, None, Some(300)],
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn uplink_header() {
+    assert_eq!(Uplink::decode(&[]), Err(Error::Truncated));
+    assert_eq!(Uplink::decode(&[0x20]), Err(Error::UnsupportedVersion(2)));
+    assert_eq!(Uplink::decode(&[0x1F]), Err(Error::UnknownMessageType(15)));
+}
+
+#[test]
+fn varint_edges() {
+    // Every field after the temperature is a plain varint
+    for (value, size) in [(0, 1), (127, 1), (128, 2), (u64::MAX, 10)] {
+        let bytes = round_trip_uplink(Uplink::Measurement(Measurement {
+            meter_id: Some(value),
+            ..Default::default()
+        }));
+        assert_eq!(bytes.len(), Measurement::HEADER_SIZE + size, "{value}");
+    }
+    for value in [0, -1, 1, i16::MIN, i16::MAX] {
+        round_trip_uplink(Uplink::Measurement(Measurement {
+            temperature_decicelsius: Some(value),
+            ..Default::default()
+        }));
+    }
+
+    let meter_id = |varint: &[u8]| Uplink::decode(&[&[0x10, 0x10, 0x00], varint].concat());
+    let max = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+    assert_eq!(
+        meter_id(&max),
+        Ok(Uplink::Measurement(Measurement {
+            meter_id: Some(u64::MAX),
+            ..Default::default()
+        }))
+    );
+    // One bit more than 64
+    let mut too_large = max;
+    too_large[9] = 0x02;
+    assert_eq!(meter_id(&too_large), Err(Error::Overflow));
+    // Eleven bytes
+    let mut too_long = max.to_vec();
+    too_long[9] = 0x81;
+    too_long.push(0x00);
+    assert_eq!(meter_id(&too_long), Err(Error::Overflow));
+    // The continuation bit set on the last byte
+    assert_eq!(meter_id(&[0x80]), Err(Error::Truncated));
+}
+
+#[test]
+fn set_counter() {
+    for counter in 0..S0_CHANNEL_COUNT {
+        let (fport, bytes) = round_trip_downlink(Downlink::SetCounter {
+            counter,
+            impulses: MAX_COUNTER_IMPULSES,
+        });
+        assert_eq!(fport, counter as u8 + 1);
+        assert_eq!(bytes, MAX_COUNTER_IMPULSES.to_le_bytes());
+        assert_truncations_fail(&bytes, |bytes| Downlink::decode(fport, bytes));
+        assert_eq!(
+            Downlink::decode(fport, &[bytes, vec![0]].concat()),
+            Err(Error::TrailingData)
+        );
+    }
+
+    let mut buf = [0; MAX_PAYLOAD_SIZE];
+    for downlink in [
+        Downlink::SetCounter {
+            counter: 0,
+            impulses: MAX_COUNTER_IMPULSES + 1,
+        },
+        Downlink::SetCounter {
+            counter: S0_CHANNEL_COUNT,
+            impulses: 0,
+        },
+    ] {
+        assert_eq!(downlink.encode(&mut buf), Err(Error::InvalidValue));
+    }
+    assert_eq!(
+        Downlink::decode(1, &(MAX_COUNTER_IMPULSES + 1).to_le_bytes()),
+        Err(Error::InvalidValue)
+    );
+    assert_eq!(
+        Downlink::decode(S0_CHANNEL_COUNT as u8 + 1, &[0; 8]),
+        Err(Error::UnknownMessageType(S0_CHANNEL_COUNT as u8 + 1))
+    );
+    assert_eq!(
+        Downlink::decode(0, &[0; 8]),
+        Err(Error::UnknownMessageType(0))
+    );
+}
+
+#[test]
+fn commands() {
+    let commands = [
+        Command::SetCounter {
+            channel: 0,
+            impulses: MAX_COUNTER_IMPULSES,
+        },
+        Command::OffsetCounter {
+            channel: S0_CHANNEL_COUNT - 1,
+            offset: -1,
+        },
+        Command::SetInterval { seconds: u32::MAX },
+        Command::Reboot,
+        Command::RequestStatus,
+        Command::SetConfig {
+            key: ConfigKey::S0ImpulsesPerKwh(5),
+            value: u64::MAX,
+        },
+        Command::SetConfig {
+            key: ConfigKey::RandomSleepVariation,
+            value: 0,
+        },
+        Command::SetConfig {
+            key: ConfigKey::AlignToWallClock,
+            value: 1,
+        },
+        Command::SetConfig {
+            key: ConfigKey::ConfirmedRetries,
+            value: 8,
+        },
+        Command::SetConfig {
+            key: ConfigKey::RetryBackoff,
+            value: 30,
+        },
+        Command::SetConfig {
+            key: ConfigKey::AirtimeBudget,
+            value: 864,
+        },
+        Command::SetConfig {
+            key: ConfigKey::BatteryType,
+            value: 2,
+        },
+        Command::SetConfig {
+            key: ConfigKey::BatteryOffset,
+            value: 300,
+        },
+        Command::SetConfig {
+            key: ConfigKey::TemperatureOffset,
+            value: zigzag(-25),
+        },
+        Command::SetConfig {
+            key: ConfigKey::TemperatureSlope,
+            value: 1020,
+        },
+        Command::SetConfig {
+            key: ConfigKey::MaxEirp,
+            value: 14,
+        },
+        Command::SetConfig {
+            key: ConfigKey::AdcReference,
+            value: 3_000,
+        },
+        Command::SetConfig {
+            key: ConfigKey::TemperatureLowAlarm,
+            value: zigzag(-50),
+        },
+        Command::SetConfig {
+            key: ConfigKey::S0AlarmChannels,
+            value: 0b10_0001,
+        },
+        Command::SetConfig {
+            key: ConfigKey::AlarmHoldoff,
+            value: 3_600,
+        },
+        Command::Rejoin,
+    ];
+    for (tag, command) in commands.into_iter().enumerate() {
+        let (fport, bytes) = round_trip_downlink(Downlink::Command {
+            tag: tag as u8,
+            command,
+        });
+        assert_eq!(fport, COMMAND_FPORT);
+        assert_truncations_fail(&bytes, |bytes| Downlink::decode(fport, bytes));
+        let mut oversize = bytes.clone();
+        oversize.push(0);
+        assert_eq!(
+            Downlink::decode(fport, &oversize),
+            Err(Error::TrailingData),
+            "{command:?}"
+        );
+    }
+    assert_eq!(
+        Downlink::decode(COMMAND_FPORT, &[0, 0xFF]),
+        Err(Error::UnknownMessageType(0xFF))
+    );
+}
+
+#[test]
+fn command_limits() {
+    let mut buf = [0; MAX_PAYLOAD_SIZE];
+    for command in [
+        Command::SetCounter {
+            channel: 0,
+            impulses: MAX_COUNTER_IMPULSES + 1,
+        },
+        Command::SetCounter {
+            channel: S0_CHANNEL_COUNT,
+            impulses: 0,
+        },
+        Command::OffsetCounter {
+            channel: S0_CHANNEL_COUNT,
+            offset: 0,
+        },
+        Command::SetConfig {
+            key: ConfigKey::S0ImpulsesPerKwh(S0_CHANNEL_COUNT),
+            value: 1000,
+        },
+    ] {
+        let downlink = Downlink::Command { tag: 0, command };
+        assert_eq!(downlink.encode(&mut buf), Err(Error::InvalidValue));
+    }
+
+    // MAX_COUNTER_IMPULSES + 1 as a varint
+    let mut set_counter = vec![0x00, 0x01, 0x00];
+    let mut value = MAX_COUNTER_IMPULSES + 1;
+    while value >= 0x80 {
+        set_counter.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    set_counter.push(value as u8);
+    assert_eq!(
+        Downlink::decode(COMMAND_FPORT, &set_counter),
+        Err(Error::InvalidValue)
+    );
+
+    // An interval beyond u32
+    assert_eq!(
+        Downlink::decode(COMMAND_FPORT, &[0x00, 0x03, 0x80, 0x80, 0x80, 0x80, 0x10]),
+        Err(Error::InvalidValue)
+    );
+    // The payload does not know the device's limits, the most negative temperature offset reaches it as is. The
+    // device has to range check it without taking its absolute value, which overflows.
+    let (fport, bytes) = round_trip_downlink(Downlink::Command {
+        tag: 0,
+        command: Command::SetConfig {
+            key: ConfigKey::TemperatureOffset,
+            value: zigzag(i16::MIN as i64),
+        },
+    });
+    let Ok(Downlink::Command {
+        command: Command::SetConfig { value, .. },
+        ..
+    }) = Downlink::decode(fport, &bytes)
+    else {
+        panic!("not a config command");
+    };
+    let offset = i16::try_from(unzigzag(value)).unwrap();
+    assert_eq!(offset, i16::MIN);
+    assert_eq!(offset.checked_abs(), None);
+    assert!(offset.unsigned_abs() > 200); // The device accepts ±20 °C
+    // A config key past the last S0 channel, and one that is not assigned
+    for key in [S0_CHANNEL_COUNT as u8, 0xFF] {
+        assert_eq!(
+            Downlink::decode(COMMAND_FPORT, &[0x00, 0x06, key, 0x00]),
+            Err(Error::InvalidValue)
+        );
+    }
+}
+
+#[test]
+fn command_acks() {
+    for (error, result) in [
+        (
+            Error::UnknownMessageType(0xFF),
+            CommandResult::UnknownCommand,
+        ),
+        (Error::InvalidValue, CommandResult::OutOfRange),
+        (Error::Overflow, CommandResult::OutOfRange),
+        (Error::Truncated, CommandResult::Malformed),
+        (Error::TrailingData, CommandResult::Malformed),
+    ] {
+        assert_eq!(CommandResult::from(error), result);
+    }
+    for result in [
+        CommandResult::Ok,
+        CommandResult::UnknownCommand,
+        CommandResult::Malformed,
+        CommandResult::OutOfRange,
+        CommandResult::Failed,
+    ] {
+        round_trip_uplink(Uplink::Measurement(Measurement {
+            command_ack: Some(CommandAck { tag: 255, result }),
+            ..Default::default()
+        }));
+    }
+}
+
+#[test]
+fn clock_sync() {
+    let uplinks = [
+        ClockSyncUplink::PackageVersionAns,
+        ClockSyncUplink::AppTimeReq {
+            device_time: u32::MAX,
+            token: 15,
+            answer_required: true,
+        },
+        ClockSyncUplink::DeviceAppTimePeriodicityAns {
+            not_supported: false,
+            device_time: 1_400_000_000,
+        },
+    ];
+    for uplink in uplinks {
+        let bytes = encode(|buf| uplink.encode(buf));
+        assert_eq!(
+            ClockSyncUplink::decode(&bytes).collect::<Vec<_>>(),
+            [Ok(uplink)]
+        );
+        assert_truncations_fail(&bytes, |bytes| collect(ClockSyncUplink::decode(bytes)));
+    }
+
+    let downlinks = [
+        ClockSyncDownlink::PackageVersionReq,
+        ClockSyncDownlink::AppTimeAns {
+            time_correction: i32::MIN,
+            token: 3,
+        },
+        ClockSyncDownlink::AppTimeAns {
+            time_correction: -1,
+            token: 0,
+        },
+        ClockSyncDownlink::DeviceAppTimePeriodicityReq { periodicity: 15 },
+        ClockSyncDownlink::ForceDeviceResyncReq {
+            nb_transmissions: 7,
+        },
+    ];
+    let mut payload = Vec::new();
+    for downlink in downlinks {
+        let bytes = encode(|buf| downlink.encode(buf));
+        assert_eq!(
+            ClockSyncDownlink::decode(&bytes).collect::<Vec<_>>(),
+            [Ok(downlink)]
+        );
+        assert_truncations_fail(&bytes, |bytes| collect(ClockSyncDownlink::decode(bytes)));
+        payload.extend(bytes);
+    }
+    // Several messages in one downlink
+    assert_eq!(
+        collect(ClockSyncDownlink::decode(&payload)),
+        Ok(downlinks.to_vec())
+    );
+}
+
+#[test]
+fn load_profile() {
+    let mut load_profile = LoadProfile::new(1_700_000_000, 15);
+    assert!(load_profile.push(Bucket {
+        flags: BucketFlags {
+            power_fail: true,
+            ..Default::default()
+        },
+        meter_import_wh: Some(250),
+        meter_export_wh: None,
+        counter_wh: [None, Some(0), None, None, None, None],
+    }));
+    assert!(load_profile.push(Bucket {
+        flags: BucketFlags::default(),
+        meter_import_wh: None,
+        meter_export_wh: None,
+        counter_wh: [None, Some(u32::MAX - 1), None, None, None, None],
+    }));
+    assert!(load_profile.push(Bucket {
+        flags: BucketFlags {
+            clock_adjusted: true,
+            estimated: true,
+            ..Default::default()
+        },
+        meter_import_wh: Some(0),
+        meter_export_wh: None,
+        counter_wh: [None; S0_CHANNEL_COUNT],
+    }));
+    round_trip_uplink(Uplink::LoadProfile(load_profile));
+    round_trip_uplink(Uplink::LoadProfile(LoadProfile::new(0, 60)));
+}
+
+#[test]
+fn diagnostic() {
+    let mut crash = Crash {
+        uptime_s: 86_400,
+        line: 123,
+        file_hash: crash::hash("src/main.rs"),
+        message_hash: crash::hash("called `Option::unwrap()` on a `None` value"),
+        ..Default::default()
+    };
+    for address in 0..MAX_CRASH_ADDRESSES as u32 {
+        assert!(crash.push(0x1000_7001 + address * 0x100));
+    }
+    assert!(!crash.push(0x1000_8001));
+    let diagnostic = Diagnostic {
+        reset_reason: ResetReason::Panic,
+        crash: Some(crash.clone()),
+    };
+    let bytes = round_trip_uplink(Uplink::Diagnostic(diagnostic.clone()));
+    assert_eq!(bytes.len(), diagnostic.size());
+    // The addresses fill the rest of the message, one more than fit is rejected
+    let mut too_long = bytes.clone();
+    too_long.extend_from_slice(&[1, 0, 0, 0]);
+    assert_eq!(Uplink::decode(&too_long), Err(Error::TrailingData));
+
+    while crash.pop() {}
+    assert_eq!(crash.addresses, [None; MAX_CRASH_ADDRESSES]);
+    round_trip_uplink(Uplink::Diagnostic(Diagnostic {
+        reset_reason: ResetReason::HardFault,
+        crash: Some(crash),
+    }));
+    let bytes = round_trip_uplink(Uplink::Diagnostic(Diagnostic {
+        reset_reason: ResetReason::BrownOut,
+        crash: None,
+    }));
+    assert_eq!(bytes, [0x14, 8]);
+}
+
+#[test]
+fn alarms() {
+    let round_trip = |alarms: &Alarms| {
+        let bytes = encode(|buf| alarms.encode(buf));
+        assert_eq!(Alarms::decode(&bytes).as_ref(), Ok(alarms));
+        assert_eq!(bytes.len(), alarms.size());
+        bytes
+    };
+
+    let mut alarms = Alarms::new(Some(1_700_000_000));
+    let kinds = [
+        AlarmKind::TemperatureHigh,
+        AlarmKind::TemperatureLow,
+        AlarmKind::MeterUnreachable,
+        AlarmKind::BatteryLow,
+    ]
+    .into_iter()
+    .chain((0..S0_CHANNEL_COUNT).map(AlarmKind::S0Stuck))
+    .chain((0..S0_CHANNEL_COUNT).map(AlarmKind::S0RateHigh));
+    for (index, kind) in kinds.enumerate() {
+        assert!(alarms.push(AlarmEvent {
+            kind,
+            active: index % 2 == 0,
+            value: -(index as i64) * 1000,
+        }));
+    }
+    let full = AlarmEvent {
+        kind: AlarmKind::BatteryLow,
+        active: true,
+        value: 0,
+    };
+    assert!(!alarms.push(full));
+    assert_eq!(alarms.events.iter().flatten().count(), MAX_ALARM_EVENTS);
+    let bytes = round_trip(&alarms);
+    // Events end with the message, so only a cut inside one (or before the timestamp) is noticed
+    assert_eq!(
+        Alarms::decode(&bytes[..bytes.len() - 1]),
+        Err(Error::Truncated)
+    );
+    assert_eq!(Alarms::decode(&bytes[..1]), Err(Error::Truncated));
+    // The events fill the rest of the message, one more than fit is rejected
+    let mut too_long = bytes.clone();
+    too_long.extend_from_slice(&[0x04, 0x00]);
+    assert_eq!(Alarms::decode(&too_long), Err(Error::TrailingData));
+
+    while alarms.pop() {}
+    assert!(alarms.is_empty());
+    assert_eq!(round_trip(&alarms), [0x10, 0x80, 0xE2, 0xCF, 0xAA, 0x06]);
+
+    // Without a timestamp: 72 °C, then S0 channel 5 back to 3 impulses per hour
+    let mut alarms = Alarms::new(None);
+    alarms.push(AlarmEvent {
+        kind: AlarmKind::TemperatureHigh,
+        active: true,
+        value: 720,
+    });
+    alarms.push(AlarmEvent {
+        kind: AlarmKind::S0RateHigh(5),
+        active: false,
+        value: 3,
+    });
+    assert_eq!(
+        round_trip(&alarms),
+        [0x10, 0x00, 0x01, 0xA0, 0x0B, 0x9D, 0x06]
+    );
+
+    assert_eq!(
+        Alarms::decode(&[0x20, 0x00]),
+        Err(Error::UnsupportedVersion(2))
+    );
+    // A channel past the last one, and a kind that is not assigned
+    for kind in [0x10 + S0_CHANNEL_COUNT as u8, 0x7F] {
+        assert_eq!(
+            Alarms::decode(&[0x10, 0x00, kind, 0x00]),
+            Err(Error::InvalidValue)
+        );
+    }
+    let mut invalid = Alarms::new(None);
+    invalid.push(AlarmEvent {
+        kind: AlarmKind::S0Stuck(S0_CHANNEL_COUNT),
+        active: true,
+        value: 0,
+    });
+    assert_eq!(
+        invalid.encode(&mut [0; MAX_PAYLOAD_SIZE]),
+        Err(Error::InvalidValue)
+    );
+}
+
+#[test]
+fn crash_hash() {
+    // The 32-bit FNV-1a test vectors
+    assert_eq!(crash::hash(""), 0x811C_9DC5);
+    assert_eq!(crash::hash("a"), 0xE40C_292C);
+    assert_eq!(crash::hash("foobar"), 0xBF9C_F968);
+    // Written in parts, as the panic handler formats the message
+    let mut hasher = CrashHasher::default();
+    core::fmt::Write::write_fmt(&mut hasher, format_args!("foo{}", "bar")).unwrap();
+    assert_eq!(hasher.finish(), crash::hash("foobar"));
+}
+
+#[test]
+fn multicast() {
+    let uplinks = [
+        MulticastUplink::PackageVersionAns,
+        MulticastUplink::McGroupStatusAns {
+            total_groups: 2,
+            groups: [
+                Some(McGroupAddress {
+                    group_id: 0,
+                    address: 0x0123_4567,
+                }),
+                Some(McGroupAddress {
+                    group_id: 3,
+                    address: u32::MAX,
+                }),
+                None,
+                None,
+            ],
+        },
+        MulticastUplink::McGroupSetupAns {
+            group_id: 1,
+            id_error: true,
+        },
+        MulticastUplink::McGroupDeleteAns {
+            group_id: 2,
+            group_undefined: false,
+        },
+        MulticastUplink::McClassCSessionAns {
+            group_id: 0,
+            status: McClassCSessionStatus::default(),
+            time_to_start: Some(3600),
+        },
+        MulticastUplink::McClassCSessionAns {
+            group_id: 3,
+            status: McClassCSessionStatus {
+                datarate_error: true,
+                frequency_error: false,
+                group_undefined: true,
+            },
+            time_to_start: None,
+        },
+    ];
+    for uplink in uplinks {
+        let bytes = encode(|buf| uplink.encode(buf));
+        assert_eq!(
+            MulticastUplink::decode(&bytes).collect::<Vec<_>>(),
+            [Ok(uplink)]
+        );
+    }
+
+    let downlinks = [
+        MulticastDownlink::PackageVersionReq,
+        MulticastDownlink::McGroupStatusReq { group_mask: 0x0F },
+        MulticastDownlink::McGroupSetupReq {
+            group_id: 2,
+            address: 0xFEDC_BA98,
+            encrypted_key: [0xA5; 16],
+            min_fcount: 0,
+            max_fcount: u32::MAX,
+        },
+        MulticastDownlink::McGroupDeleteReq { group_id: 3 },
+        MulticastDownlink::McClassCSessionReq {
+            group_id: 1,
+            session_time: 1_400_000_000,
+            timeout: 15,
+            frequency_hz: 869_525_000,
+            datarate: 3,
+        },
+    ];
+    for downlink in downlinks {
+        let bytes = encode(|buf| downlink.encode(buf));
+        assert_eq!(
+            MulticastDownlink::decode(&bytes).collect::<Vec<_>>(),
+            [Ok(downlink)]
+        );
+        assert_truncations_fail(&bytes, |bytes| collect(MulticastDownlink::decode(bytes)));
+    }
+}
+
+#[test]
+fn fragmentation() {
+    let uplinks = [
+        FragmentationUplink::PackageVersionAns,
+        FragmentationUplink::FragSessionStatusAns {
+            session_index: 3,
+            fragments_received: 0x3FFF,
+            fragments_missing: 255,
+            not_enough_matrix_memory: true,
+        },
+        FragmentationUplink::FragSessionSetupAns {
+            session_index: 0,
+            status: FragSessionSetupStatus {
+                encoding_unsupported: true,
+                not_enough_memory: false,
+                index_unsupported: true,
+                wrong_descriptor: false,
+            },
+        },
+        FragmentationUplink::FragSessionDeleteAns {
+            session_index: 1,
+            session_does_not_exist: true,
+        },
+    ];
+    for uplink in uplinks {
+        let bytes = encode(|buf| uplink.encode(buf));
+        assert_eq!(
+            FragmentationUplink::decode(&bytes).collect::<Vec<_>>(),
+            [Ok(uplink)]
+        );
+    }
+
+    let data = [0x5A; 48];
+    let downlinks = [
+        FragmentationDownlink::PackageVersionReq,
+        FragmentationDownlink::FragSessionStatusReq {
+            session_index: 2,
+            participants_only: true,
+        },
+        FragmentationDownlink::FragSessionSetupReq {
+            session_index: 1,
+            multicast_group_mask: 0x05,
+            fragment_count: 1000,
+            fragment_size: 48,
+            fragmentation_matrix: 0,
+            block_ack_delay: 3,
+            padding: 17,
+            descriptor: 0x0102_0304,
+        },
+        FragmentationDownlink::FragSessionDeleteReq { session_index: 3 },
+        FragmentationDownlink::DataFragment {
+            session_index: 3,
+            number: 0x3FFF,
+            data: &data,
+        },
+    ];
+    for downlink in downlinks {
+        let bytes = encode(|buf| downlink.encode(buf));
+        assert_eq!(
+            FragmentationDownlink::decode(&bytes).collect::<Vec<_>>(),
+            [Ok(downlink)]
+        );
+    }
+}
+
+fn collect<T>(messages: impl Iterator<Item = Result<T, Error>>) -> Result<Vec<T>, Error> {
+    messages.collect()
+}
@@ -0,0 +1,92 @@
+use core::sync::atomic::Ordering;
+
+use embassy_time::{Duration, Instant};
+use lorawan_device::async_device::region::DR;
+use portable_atomic::AtomicU32;
+
+use crate::lorawan_region;
+
+// MHDR (1), FHDR without FOpts (7), FPort (1) and MIC (4) are added to the application payload
+const LORAWAN_OVERHEAD: usize = 13;
+// LoRaWAN uses 8 preamble symbols, explicit headers, a CRC and coding rate 4/5
+const PREAMBLE_SYMBOLS: u64 = 8;
+const CODING_RATE: u64 = 1;
+
+// The airtime used per hour (in ms) over the last day, indexed by the hour since boot modulo 24. It is not persisted, so
+// the budget starts over after a reset.
+const HOURS: usize = 24;
+static USED_PER_HOUR_MS: [AtomicU32; HOURS] = [const { AtomicU32::new(0) }; HOURS];
+static CURRENT_HOUR: AtomicU32 = AtomicU32::new(0);
+// The airtime used (in ms) since the last call to `take_cycle`
+static USED_THIS_CYCLE_MS: AtomicU32 = AtomicU32::new(0);
+
+/// The time it takes to transmit an uplink with the given application payload size at the given data rate.
+/// Calculated according to the Semtech SX1262 datasheet, chapter 6.1.4.
+pub fn time_on_air(datarate: DR, payload_size: usize) -> Duration {
+    let (spreading_factor, bandwidth_hz) = lorawan_region::modulation(datarate);
+    let sf = spreading_factor as u64;
+    let symbol_time_us = (1u64 << sf) * 1_000_000 / bandwidth_hz as u64;
+
+    // Low data rate optimization is mandated for symbols longer than 16 ms
+    let low_data_rate_optimization = if symbol_time_us > 16_000 { 1 } else { 0 };
+
+    let payload_bits = 8 * (payload_size + LORAWAN_OVERHEAD) as i64 - 4 * sf as i64 + 28 + 16;
+    let bits_per_symbol_group = 4 * (sf - 2 * low_data_rate_optimization);
+    let symbol_groups = (payload_bits.max(0) as u64).div_ceil(bits_per_symbol_group);
+    let payload_symbols = 8 + symbol_groups * (CODING_RATE + 4);
+
+    // The preamble is followed by 4.25 more symbols, so everything is calculated in quarter symbols
+    let quarter_symbols = 4 * (PREAMBLE_SYMBOLS + payload_symbols) + 17;
+    Duration::from_micros(quarter_symbols * symbol_time_us / 4)
+}
+
+/// Counts an uplink towards the airtime used over the last day
+pub fn record(airtime: Duration) {
+    advance();
+    let hour = CURRENT_HOUR.load(Ordering::Relaxed) as usize % HOURS;
+    USED_PER_HOUR_MS[hour].add(airtime.as_millis() as u32, Ordering::Relaxed);
+    USED_THIS_CYCLE_MS.add(airtime.as_millis() as u32, Ordering::Relaxed);
+}
+
+/// The airtime used by all uplinks since the last call (the measurement, retries, backlog, alarms, ...), which the
+/// measurement interval has to make room for
+pub fn take_cycle() -> Duration {
+    Duration::from_millis(USED_THIS_CYCLE_MS.swap(0, Ordering::Relaxed) as u64)
+}
+
+/// The airtime used by uplinks over the last 24 hours
+pub fn used_last_day() -> Duration {
+    advance();
+    let used_ms: u64 = USED_PER_HOUR_MS
+        .iter()
+        .map(|used| used.load(Ordering::Relaxed) as u64)
+        .sum();
+    Duration::from_millis(used_ms)
+}
+
+/// What is left of the daily budget
+pub fn remaining(budget: Duration) -> Duration {
+    let used = used_last_day();
+    if used >= budget {
+        Duration::from_secs(0)
+    } else {
+        budget - used
+    }
+}
+
+/// The largest application payload that can be sent at the data rate without using more than the given airtime
+pub fn max_payload_within(datarate: DR, airtime: Duration) -> usize {
+    (0..=lorawan_region::max_payload(datarate))
+        .rev()
+        .find(|size| time_on_air(datarate, *size) <= airtime)
+        .unwrap_or(0)
+}
+
+// Clears the hours that ended more than a day ago
+fn advance() {
+    let hour = (Instant::now().as_secs() / 3600) as u32;
+    let current = CURRENT_HOUR.swap(hour, Ordering::Relaxed);
+    for passed in (current + 1..=hour).take(HOURS) {
+        USED_PER_HOUR_MS[passed as usize % HOURS].store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,258 @@
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+use lorawan_device::async_device::{radio, Device, SendResponse, Timings};
+use lorawan_device::{CryptoFactory, RngCore};
+use portable_atomic::AtomicU32;
+use powermeter_payload::{
+    AlarmEvent, AlarmKind, Alarms, Supply, ALARM_FPORT, MAX_ALARM_EVENTS, MAX_PAYLOAD_SIZE,
+};
+
+use crate::config::Config;
+use crate::policy::{self, Delivery};
+use crate::temperature::Climate;
+use crate::{commands, lorawan_region, mac, S0_CHANNEL_COUNT};
+
+// How far a value has to return past its threshold before the alarm clears, so one that hovers around the threshold
+// does not send an alarm every cycle
+pub const TEMPERATURE_HYSTERESIS_DECICELSIUS: i16 = 20;
+const BATTERY_HYSTERESIS_PERCENT: u8 = 5;
+// The impulse rate clears at this share of its threshold, in %
+const RATE_CLEAR_PERCENT: u64 = 80;
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+// Where each alarm is kept, in the order they are sent: the temperature first, then the meter, the battery and the
+// S0 channels
+const TEMPERATURE_HIGH: usize = 0;
+const TEMPERATURE_LOW: usize = 1;
+const METER_UNREACHABLE: usize = 2;
+const BATTERY_LOW: usize = 3;
+const S0_STUCK: usize = 4;
+const S0_RATE_HIGH: usize = S0_STUCK + S0_CHANNEL_COUNT;
+
+// The impulses counted on each S0 channel since the last check. The counters themselves can be set by commands, which
+// would look like a burst of impulses.
+static IMPULSES: [AtomicU32; S0_CHANNEL_COUNT] = [const { AtomicU32::new(0) }; S0_CHANNEL_COUNT];
+
+/// Counts an impulse on the S0 channel for the alarms
+pub fn impulse(channel: usize) {
+    IMPULSES[channel].add(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy)]
+struct Alarm {
+    active: bool,
+    reported: bool, // The network acknowledged the current state
+    value: i64,     // The one that last changed the state
+}
+
+impl Alarm {
+    // Only raised alarms are reported, a clear one after booting is not news
+    const CLEAR: Self = Self {
+        active: false,
+        reported: true,
+        value: 0,
+    };
+
+    fn update(&mut self, active: bool, value: i64) {
+        if active != self.active {
+            self.active = active;
+            self.reported = false;
+            self.value = value;
+        }
+    }
+}
+
+/// Checks the measurements against the configured thresholds and sends an alarm uplink on [`ALARM_FPORT`] when an
+/// alarm is raised or cleared, instead of waiting for someone to look at the measurements. Alarms clear with some
+/// hysteresis and are sent at most once per configured holdoff. The state is not saved, all alarms start clear after a
+/// reset.
+pub struct AlarmMonitor {
+    alarms: [Alarm; MAX_ALARM_EVENTS],
+    meter_failures: u32, // Reads in a row
+    last_check: Instant,
+    last_impulse: [Instant; S0_CHANNEL_COUNT],
+    last_sent: Option<Instant>,
+}
+
+impl Default for AlarmMonitor {
+    fn default() -> Self {
+        // The S0 channels get the full time to count their first impulse
+        let now = Instant::now();
+        Self {
+            alarms: [Alarm::CLEAR; MAX_ALARM_EVENTS],
+            meter_failures: 0,
+            last_check: now,
+            last_impulse: [now; S0_CHANNEL_COUNT],
+            last_sent: None,
+        }
+    }
+}
+
+impl AlarmMonitor {
+    /// Updates the alarms with the values of this cycle. A value that was not measured leaves its alarm as it is.
+    pub fn check(
+        &mut self,
+        config: &Config,
+        climate: Option<Climate>,
+        meter_answered: bool,
+        supply: Option<Supply>,
+    ) {
+        if let Some(climate) = climate {
+            let temperature = climate.temperature_decicelsius;
+            let high = &mut self.alarms[TEMPERATURE_HIGH];
+            let limit = match high.active {
+                true => {
+                    config.temperature_high_alarm_decicelsius - TEMPERATURE_HYSTERESIS_DECICELSIUS
+                }
+                false => config.temperature_high_alarm_decicelsius,
+            };
+            high.update(temperature > limit, temperature as i64);
+            let low = &mut self.alarms[TEMPERATURE_LOW];
+            let limit = match low.active {
+                true => {
+                    config.temperature_low_alarm_decicelsius + TEMPERATURE_HYSTERESIS_DECICELSIUS
+                }
+                false => config.temperature_low_alarm_decicelsius,
+            };
+            low.update(temperature < limit, temperature as i64);
+        }
+
+        self.meter_failures = match meter_answered {
+            true => 0,
+            false => self.meter_failures.saturating_add(1),
+        };
+        self.alarms[METER_UNREACHABLE].update(
+            config.meter_failure_alarm != 0
+                && self.meter_failures >= config.meter_failure_alarm as u32,
+            self.meter_failures as i64,
+        );
+
+        if let Some(percent) = supply.and_then(|supply| supply.battery_percent) {
+            let battery = &mut self.alarms[BATTERY_LOW];
+            let limit = match battery.active {
+                true => config
+                    .battery_low_alarm_percent
+                    .saturating_add(BATTERY_HYSTERESIS_PERCENT),
+                false => config.battery_low_alarm_percent,
+            };
+            battery.update(percent < limit, percent as i64);
+        }
+
+        let elapsed = self.last_check.elapsed();
+        self.last_check = Instant::now();
+        for channel in 0..S0_CHANNEL_COUNT {
+            let impulses = IMPULSES[channel].swap(0, Ordering::Relaxed) as u64;
+            if impulses > 0 {
+                self.last_impulse[channel] = self.last_check;
+            }
+            let checked = config.s0_alarm_channels & (1 << channel) != 0;
+
+            let silent_h = self.last_impulse[channel].elapsed().as_secs() / HOUR.as_secs();
+            self.alarms[S0_STUCK + channel].update(
+                checked
+                    && config.s0_stuck_alarm_h != 0
+                    && silent_h >= config.s0_stuck_alarm_h as u64,
+                silent_h as i64,
+            );
+
+            let per_hour = impulses * HOUR.as_millis() / elapsed.as_millis().max(1);
+            let rate = &mut self.alarms[S0_RATE_HIGH + channel];
+            let threshold = config.s0_rate_alarm_per_h as u64;
+            let limit = match rate.active {
+                true => threshold * RATE_CLEAR_PERCENT / 100,
+                false => threshold,
+            };
+            rate.update(
+                checked && threshold != 0 && per_hour > limit,
+                per_hour as i64,
+            );
+        }
+    }
+
+    /// Sends the alarms whose state the network does not know yet, if the holdoff since the last alarm uplink has
+    /// passed. `timestamp` is when the values were measured, in seconds since the unix epoch.
+    pub async fn send<R, C, T, G>(
+        &mut self,
+        device: &mut Device<R, C, T, G>,
+        config: &Config,
+        timestamp: Option<u32>,
+    ) where
+        R: radio::PhyRxTx + Timings,
+        T: radio::Timer,
+        C: CryptoFactory + Default,
+        G: RngCore,
+    {
+        if self.alarms.iter().all(|alarm| alarm.reported) {
+            return;
+        }
+        let holdoff = Duration::from_secs(config.alarm_holdoff_s as u64);
+        if self
+            .last_sent
+            .is_some_and(|last_sent| last_sent.elapsed() < holdoff)
+        {
+            info!("Alarm held back until the holdoff has passed");
+            return;
+        }
+
+        let mut alarms = Alarms::new(timestamp);
+        for (index, alarm) in self.alarms.iter().enumerate() {
+            if !alarm.reported {
+                alarms.push(AlarmEvent {
+                    kind: kind(index),
+                    active: alarm.active,
+                    value: alarm.value,
+                });
+            }
+        }
+        // The ones left out go with the next alarm uplink
+        let max_payload = lorawan_region::max_payload(device.get_datarate())
+            .saturating_sub(mac::pending_fopts_len());
+        while alarms.size() > max_payload && alarms.pop() {}
+        if alarms.is_empty() {
+            warn!("Alarm does not fit the current data rate.");
+            return;
+        }
+        let mut buf = [0u8; MAX_PAYLOAD_SIZE];
+        let size = alarms.encode(&mut buf).unwrap();
+        info!("Sending alarm: {:?}", alarms);
+        let resp = policy::send(device, &buf[..size], ALARM_FPORT, Delivery::Retried, config).await;
+
+        let Some(resp) = resp else {
+            return;
+        };
+        self.last_sent = Some(Instant::now());
+        match resp {
+            // Sent again once the holdoff has passed
+            SendResponse::NoAck => warn!("Alarm not acknowledged."),
+            // Nothing was sent, the measurement's uplink joins again
+            SendResponse::SessionExpired => {}
+            resp => {
+                let sent = alarms.events.iter().flatten().count();
+                for alarm in self
+                    .alarms
+                    .iter_mut()
+                    .filter(|alarm| !alarm.reported)
+                    .take(sent)
+                {
+                    alarm.reported = true;
+                }
+                if let SendResponse::DownlinkReceived(_) = resp {
+                    commands::receive(device);
+                }
+            }
+        }
+    }
+}
+
+fn kind(index: usize) -> AlarmKind {
+    match index {
+        TEMPERATURE_HIGH => AlarmKind::TemperatureHigh,
+        TEMPERATURE_LOW => AlarmKind::TemperatureLow,
+        METER_UNREACHABLE => AlarmKind::MeterUnreachable,
+        BATTERY_LOW => AlarmKind::BatteryLow,
+        index if index < S0_RATE_HIGH => AlarmKind::S0Stuck(index - S0_STUCK),
+        index => AlarmKind::S0RateHigh(index - S0_RATE_HIGH),
+    }
+}
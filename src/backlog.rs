@@ -0,0 +1,249 @@
+use bincode::{Decode, Encode};
+use defmt::{info, warn};
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_time::Duration;
+use lorawan_device::async_device::{radio, Device, SendResponse, Timings};
+use lorawan_device::{CryptoFactory, RngCore};
+use powermeter_payload::{Backlog, Reading, Uplink, MAX_PAYLOAD_SIZE, UPLINK_FPORT};
+
+use crate::commands;
+use crate::config::Config;
+use crate::flash_region::FlashRegion;
+use crate::policy::{self, Delivery};
+use crate::{clock, lorawan_region, mac, S0_CHANNEL_COUNT};
+
+// 4 hours at the default 5 minute interval, 12 hours at a 15 minute one. The buffer has to fit a flash sector.
+const CAPACITY: usize = 48;
+// Readings sent unconfirmed are only known to have arrived once a later confirmed uplink is acknowledged. We send one
+// at least this often. TTN allows 10 downlinks (including acknowledgements) per day.
+const CONFIRM_INTERVAL: Duration = Duration::from_secs(3 * 60 * 60);
+// How many backlog messages are sent per measurement cycle at most, so the catch-up doesn't use up the duty cycle
+const MAX_CATCH_UP_MESSAGES: usize = 4;
+
+// The buffer is saved to the slots of its own flash region (see memory.x) after every measurement
+const SLOT_SIZE: usize = ERASE_SIZE;
+// Encoded with fixed size integers, the largest the buffer gets: the readings, their count and the reachability
+const STORED_READING_SIZE: usize = 4 + 2 * (1 + 8) + S0_CHANNEL_COUNT * (1 + 8) + 1;
+const MAX_ENCODED_SIZE: usize = CAPACITY * STORED_READING_SIZE + 8 + 1;
+const _: () = assert!(MAX_ENCODED_SIZE <= FlashRegion::record_capacity(SLOT_SIZE));
+
+extern "C" {
+    static __readings_start: u32;
+    static __readings_end: u32;
+}
+
+#[derive(Default, Clone, Copy, Encode, Decode)]
+struct StoredReading {
+    timestamp: u32,
+    meter_import_wh: Option<u64>,
+    meter_export_wh: Option<u64>,
+    counter_wh: [Option<u64>; S0_CHANNEL_COUNT],
+    probably_delivered: bool, // Sent unconfirmed while the network was reachable
+}
+
+impl StoredReading {
+    fn reading(&self) -> Reading {
+        Reading {
+            timestamp: self.timestamp,
+            meter_import_wh: self.meter_import_wh,
+            meter_export_wh: self.meter_export_wh,
+            counter_wh: self.counter_wh,
+        }
+    }
+}
+
+/// Keeps the readings until the network acknowledged them, so they can be sent again after an outage.
+/// Readings are kept oldest first.
+#[derive(Clone, Encode, Decode)]
+pub struct ReadingBuffer {
+    readings: [StoredReading; CAPACITY],
+    len: usize,
+    network_reachable: bool, // False after a confirmed uplink was not acknowledged
+}
+
+impl Default for ReadingBuffer {
+    fn default() -> Self {
+        Self {
+            readings: [StoredReading::default(); CAPACITY],
+            len: 0,
+            network_reachable: true,
+        }
+    }
+}
+
+impl ReadingBuffer {
+    /// Restores the buffer from the newest slot of its flash region, or starts empty
+    pub fn load() -> Self {
+        region().load().unwrap_or_default()
+    }
+
+    /// Writes the buffer to the slot after the newest one
+    pub fn save(&self) {
+        region().save(self);
+    }
+
+    /// Adds the reading of the current measurement. If the buffer is full, the oldest one is dropped.
+    pub fn push(&mut self, reading: Reading) {
+        if self.len == CAPACITY {
+            warn!("Reading buffer full, dropping the oldest reading.");
+            self.readings.rotate_left(1);
+            self.len -= 1;
+        }
+        self.readings[self.len] = StoredReading {
+            timestamp: reading.timestamp,
+            meter_import_wh: reading.meter_import_wh,
+            meter_export_wh: reading.meter_export_wh,
+            counter_wh: reading.counter_wh,
+            probably_delivered: false,
+        };
+        self.len += 1;
+    }
+
+    /// Whether the next measurement should be sent confirmed, to find out if the readings arrive
+    pub fn confirm_due(&self) -> bool {
+        if !self.network_reachable || self.len >= CAPACITY * 3 / 4 {
+            return true;
+        }
+        let oldest_unconfirmed = self.readings[..self.len]
+            .iter()
+            .find(|reading| reading.probably_delivered);
+        match (oldest_unconfirmed, clock::utc_now()) {
+            (Some(reading), Some(now)) => {
+                now.as_secs() >= reading.timestamp as u64 + CONFIRM_INTERVAL.as_secs()
+            }
+            _ => false,
+        }
+    }
+
+    /// The measurement with the given timestamp was sent unconfirmed, including all values of the reading
+    pub fn sent_unconfirmed(&mut self, timestamp: u32) {
+        if !self.network_reachable {
+            return;
+        }
+        for reading in self.readings[..self.len].iter_mut() {
+            if reading.timestamp == timestamp {
+                reading.probably_delivered = true;
+            }
+        }
+    }
+
+    /// A confirmed measurement was acknowledged. The network was reachable when all readings sent unconfirmed since
+    /// the last acknowledgement were sent, so they arrived as well. `timestamp` is the one of the measurement, if it
+    /// contained all values of its reading.
+    pub fn acknowledged(&mut self, timestamp: Option<u32>) {
+        if !self.network_reachable {
+            info!("Network reachable again.");
+        }
+        self.network_reachable = true;
+        self.retain(|reading| !reading.probably_delivered && Some(reading.timestamp) != timestamp);
+    }
+
+    /// A confirmed uplink was not acknowledged. It is unknown when the network became unreachable, so all readings
+    /// since the last acknowledgement have to be sent again.
+    pub fn not_acknowledged(&mut self) {
+        if self.network_reachable {
+            warn!("Network unreachable, keeping the readings until it is back.");
+        }
+        self.network_reachable = false;
+        for reading in self.readings[..self.len].iter_mut() {
+            reading.probably_delivered = false;
+        }
+    }
+
+    /// Sends the readings that did not arrive yet, as long as the network is reachable
+    pub async fn catch_up<R, C, T, G>(&mut self, device: &mut Device<R, C, T, G>, config: &Config)
+    where
+        R: radio::PhyRxTx + Timings,
+        T: radio::Timer,
+        C: CryptoFactory + Default,
+        G: RngCore,
+    {
+        for _ in 0..MAX_CATCH_UP_MESSAGES {
+            if !self.network_reachable {
+                return;
+            }
+            // Leaving room for the MAC commands that go along
+            let max_payload = lorawan_region::max_payload(device.get_datarate())
+                .saturating_sub(mac::pending_fopts_len());
+            let Some(message) = self.next_message(max_payload) else {
+                return;
+            };
+
+            let mut buf = [0u8; MAX_PAYLOAD_SIZE];
+            let size = Uplink::Backlog(message.clone()).encode(&mut buf).unwrap();
+            info!("Sending {:?} buffered readings", message.len());
+            // Not retried: if the network is unreachable, the readings stay buffered until it is back
+            match policy::send(
+                device,
+                &buf[..size],
+                UPLINK_FPORT,
+                Delivery::Confirmed,
+                config,
+            )
+            .await
+            {
+                None => return,
+                Some(SendResponse::NoAck) => self.not_acknowledged(),
+                Some(resp) => {
+                    info!("Sending okay: {:?}", resp);
+                    self.retain(|reading| {
+                        !message
+                            .readings()
+                            .any(|sent| sent.timestamp == reading.timestamp)
+                    });
+                    if let SendResponse::DownlinkReceived(_) = resp {
+                        commands::receive(device);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The oldest readings that did not arrive yet, as many as fit the payload size
+    fn next_message(&self, max_payload: usize) -> Option<Backlog> {
+        let mut message = Backlog::default();
+        let mut size = Backlog::HEADER_SIZE;
+        let mut previous_timestamp = None;
+        for reading in self.readings[..self.len]
+            .iter()
+            .filter(|reading| !reading.probably_delivered)
+            .map(StoredReading::reading)
+        {
+            // Timestamps have to increase within a message, which they don't if the clock was set back
+            if previous_timestamp.is_some_and(|previous| reading.timestamp < previous) {
+                break;
+            }
+            let reading_size = reading.size(previous_timestamp);
+            if size + reading_size > max_payload || !message.push(reading) {
+                break;
+            }
+            size += reading_size;
+            previous_timestamp = Some(reading.timestamp);
+        }
+        if message.is_empty() {
+            if self.readings[..self.len]
+                .iter()
+                .any(|reading| !reading.probably_delivered)
+            {
+                warn!("Data rate too low to send buffered readings!");
+            }
+            return None;
+        }
+        Some(message)
+    }
+
+    fn retain(&mut self, keep: impl Fn(&StoredReading) -> bool) {
+        let mut len = 0;
+        for index in 0..self.len {
+            if keep(&self.readings[index]) {
+                self.readings[len] = self.readings[index];
+                len += 1;
+            }
+        }
+        self.len = len;
+    }
+}
+
+fn region() -> FlashRegion {
+    unsafe { FlashRegion::new(&__readings_start, &__readings_end, SLOT_SIZE) }
+}
@@ -0,0 +1,161 @@
+use bincode::{Decode, Encode};
+use defmt::{info, Format};
+use embassy_rp::adc::{Adc, Async, Channel as AdcChannel};
+use embassy_rp::gpio::Pull;
+#[cfg(feature = "pico_w")]
+use embassy_rp::gpio::{Level, Output};
+#[cfg(feature = "pico_w")]
+use embassy_rp::peripherals::PIN_25;
+use embassy_rp::peripherals::PIN_29;
+#[cfg(feature = "pico_w")]
+use embassy_time::Timer;
+use powermeter_payload::Supply;
+
+use crate::config::Config;
+use crate::{adc_microvolts, mac, median};
+
+// VSYS is measured through a 1:3 divider on the Pico boards
+const DIVIDER: u32 = 3;
+const SAMPLE_COUNT: usize = 5;
+
+// The battery voltage (in mV) at which the given percentage of the capacity is left, at the small load of the idle
+// device. Between two points, the level is interpolated.
+const LITHIUM_ION_CURVE: [(u16, u8); 11] = [
+    (3300, 0),
+    (3500, 5),
+    (3600, 12),
+    (3650, 20),
+    (3700, 30),
+    (3750, 40),
+    (3800, 50),
+    (3900, 65),
+    (4000, 80),
+    (4100, 90),
+    (4200, 100),
+];
+// LiSOCl2 cells keep their voltage until they are nearly empty, so the level is only a rough estimate until the end
+const LITHIUM_THIONYL_CHLORIDE_CURVE: [(u16, u8); 7] = [
+    (2900, 0),
+    (3100, 5),
+    (3300, 10),
+    (3400, 20),
+    (3500, 50),
+    (3600, 90),
+    (3650, 100),
+];
+
+/// What the device is powered by, configured by a command
+#[derive(Clone, Copy, PartialEq, Format, Encode, Decode)]
+pub enum BatteryType {
+    Mains,
+    LithiumIon,
+    LithiumThionylChloride,
+}
+
+impl BatteryType {
+    /// The battery type with the given value of the configuration command
+    pub fn from_config(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(BatteryType::Mains),
+            1 => Some(BatteryType::LithiumIon),
+            2 => Some(BatteryType::LithiumThionylChloride),
+            _ => None,
+        }
+    }
+
+    fn discharge_curve(self) -> Option<&'static [(u16, u8)]> {
+        match self {
+            BatteryType::Mains => None,
+            BatteryType::LithiumIon => Some(&LITHIUM_ION_CURVE),
+            BatteryType::LithiumThionylChloride => Some(&LITHIUM_THIONYL_CHLORIDE_CURVE),
+        }
+    }
+
+    /// The level left at the given battery voltage, in percent
+    fn level(self, millivolts: u16) -> Option<u8> {
+        let curve = self.discharge_curve()?;
+        let upper = curve
+            .iter()
+            .position(|(curve_mv, _)| *curve_mv > millivolts)
+            .unwrap_or(curve.len());
+        Some(match upper {
+            0 => curve[0].1,
+            upper if upper == curve.len() => curve[upper - 1].1,
+            upper => {
+                let (low_mv, low_percent) = curve[upper - 1];
+                let (high_mv, high_percent) = curve[upper];
+                let percent = low_percent as u32
+                    + (millivolts - low_mv) as u32 * (high_percent - low_percent) as u32
+                        / (high_mv - low_mv) as u32;
+                percent as u8
+            }
+        })
+    }
+}
+
+/// Measures VSYS on ADC3 (GPIO29). On the Pico W, the pin is the CYW43's SPI clock as well, so it can only be used
+/// while the CYW43 is powered off, i.e. in battery builds.
+#[cfg_attr(all(feature = "pico_w", not(feature = "battery")), allow(dead_code))]
+pub struct SupplyMonitor {
+    vsys: AdcChannel<'static>,
+    // On the Pico W, the divider is only connected while the CYW43's chip select is high
+    #[cfg(feature = "pico_w")]
+    wl_cs: Output<'static>,
+}
+
+impl SupplyMonitor {
+    #[cfg(feature = "pico_non_w")]
+    pub fn new(vsys: PIN_29) -> Self {
+        Self {
+            vsys: AdcChannel::new_pin(vsys, Pull::None),
+        }
+    }
+
+    #[cfg(all(feature = "pico_w", feature = "battery"))]
+    pub fn new(vsys: PIN_29, wl_cs: PIN_25) -> Self {
+        Self {
+            vsys: AdcChannel::new_pin(vsys, Pull::None),
+            wl_cs: Output::new(wl_cs, Level::Low),
+        }
+    }
+
+    pub async fn measure(&mut self, adc: &mut Adc<'static, Async>, config: &Config) -> Supply {
+        #[cfg(feature = "pico_w")]
+        {
+            self.wl_cs.set_high();
+            Timer::after_millis(1).await;
+        }
+        let mut samples = [0u16; SAMPLE_COUNT];
+        for sample in samples.iter_mut() {
+            *sample = adc.read(&mut self.vsys).await.unwrap();
+        }
+        // Not to power the CYW43 through its chip select
+        #[cfg(feature = "pico_w")]
+        self.wl_cs.set_low();
+
+        let vsys_mv = adc_microvolts(median(&mut samples), config) * DIVIDER / 1000;
+        let millivolts = (vsys_mv + config.battery_offset_mv as u32).min(u16::MAX as u32) as u16;
+        let battery_percent = config.battery_type.level(millivolts);
+        info!(
+            "supply: {:?} mV, battery {:?} %",
+            millivolts, battery_percent
+        );
+        Supply {
+            millivolts,
+            battery_percent,
+        }
+    }
+}
+
+/// The battery level as the DevStatusAns MAC command reports it: 0 on mains power, 1 (empty) to 254 (full), 255 if
+/// it could not be measured
+pub fn dev_status_level(config: &Config, supply: Option<Supply>) -> u8 {
+    match (
+        config.battery_type,
+        supply.and_then(|supply| supply.battery_percent),
+    ) {
+        (BatteryType::Mains, _) => 0,
+        (_, Some(percent)) => 1 + (percent as u32 * 253 / 100) as u8,
+        (_, None) => mac::BATTERY_LEVEL_UNKNOWN,
+    }
+}
@@ -82,8 +82,8 @@ pub struct BlinkPeripherals {
 }
 
 #[cfg(feature = "pico_non_w")]
-pub async fn init(initial_period: Duration, spawner: Spawner, p: Peripherals) {
-    let led = Output::new(p.PIN_25.degrade(), Level::Low);
+pub async fn init(initial_period: Duration, spawner: Spawner, p: BlinkPeripherals) {
+    let led = Output::new(p.led.degrade(), Level::Low);
     spawner.spawn(blink_task(led, initial_period)).unwrap();
 }
 
@@ -0,0 +1,152 @@
+use defmt::warn;
+use embassy_rp::bind_interrupts;
+use embassy_rp::i2c::{self, Async, I2c, InterruptHandler};
+use embassy_rp::peripherals::{I2C0, PIN_4, PIN_5};
+use embassy_time::Timer;
+
+use crate::temperature::Climate;
+
+bind_interrupts!(struct Irqs {
+    I2C0_IRQ => InterruptHandler<I2C0>;
+});
+
+// With SDO low
+const ADDRESS: u16 = 0x76;
+const CHIP_ID_REGISTER: u8 = 0xD0;
+const CHIP_ID: u8 = 0x60;
+// The calibration values are spread over two register blocks
+const CALIBRATION_T_REGISTER: u8 = 0x88;
+const CALIBRATION_H1_REGISTER: u8 = 0xA1;
+const CALIBRATION_H2_REGISTER: u8 = 0xE1;
+const CTRL_HUM_REGISTER: u8 = 0xF2;
+const CTRL_MEAS_REGISTER: u8 = 0xF4;
+const DATA_REGISTER: u8 = 0xFA;
+// Humidity and temperature oversampled once, the pressure skipped, then a single measurement (forced mode)
+const CTRL_HUM: u8 = 0b001;
+const CTRL_MEAS: u8 = 0b001_000_01;
+const MEASUREMENT_TIME_MS: u64 = 10;
+
+/// The factory calibration of the sensor, as the compensation formulas in the datasheet name it
+struct Calibration {
+    t1: i32,
+    t2: i32,
+    t3: i32,
+    h1: i32,
+    h2: i32,
+    h3: i32,
+    h4: i32,
+    h5: i32,
+    h6: i32,
+}
+
+/// A Bosch BME280 on I2C0, SDA on GPIO4 and SCL on GPIO5. The pressure is not measured.
+pub struct Bme280 {
+    i2c: I2c<'static, I2C0, Async>,
+    // Read with the first measurement, so a sensor connected later is found as well
+    calibration: Option<Calibration>,
+}
+
+impl Bme280 {
+    pub fn new(i2c: I2C0, sda: PIN_4, scl: PIN_5) -> Self {
+        Self {
+            i2c: I2c::new_async(i2c, scl, sda, Irqs, i2c::Config::default()),
+            calibration: None,
+        }
+    }
+
+    pub async fn measure(&mut self) -> Option<Climate> {
+        match self.try_measure().await {
+            Ok(climate) => climate,
+            Err(error) => {
+                warn!("BME280: {:?}", error);
+                None
+            }
+        }
+    }
+
+    async fn try_measure(&mut self) -> Result<Option<Climate>, i2c::Error> {
+        if self.calibration.is_none() {
+            self.calibration = self.read_calibration().await?;
+        }
+        let Some(calibration) = self.calibration.as_ref() else {
+            return Ok(None);
+        };
+        self.i2c
+            .write_async(ADDRESS, [CTRL_HUM_REGISTER, CTRL_HUM])
+            .await?;
+        self.i2c
+            .write_async(ADDRESS, [CTRL_MEAS_REGISTER, CTRL_MEAS])
+            .await?;
+        Timer::after_millis(MEASUREMENT_TIME_MS).await;
+        // Temperature (20 bits) and humidity (16 bits)
+        let mut data = [0u8; 5];
+        self.i2c
+            .write_read_async(ADDRESS, [DATA_REGISTER], &mut data)
+            .await?;
+        let raw_temperature =
+            ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | ((data[2] as i32) >> 4);
+        let raw_humidity = ((data[3] as i32) << 8) | data[4] as i32;
+
+        // The integer compensation from chapter 4.2.3 of the datasheet
+        let var1 = (((raw_temperature >> 3) - (calibration.t1 << 1)) * calibration.t2) >> 11;
+        let var2 = (((((raw_temperature >> 4) - calibration.t1)
+            * ((raw_temperature >> 4) - calibration.t1))
+            >> 12)
+            * calibration.t3)
+            >> 14;
+        let t_fine = var1 + var2;
+        let centicelsius = (t_fine * 5 + 128) >> 8;
+
+        let mut h = t_fine - 76800;
+        h = (((raw_humidity << 14) - (calibration.h4 << 20) - (calibration.h5 * h) + 16384) >> 15)
+            * (((((((h * calibration.h6) >> 10) * (((h * calibration.h3) >> 11) + 32768)) >> 10)
+                + 2097152)
+                * calibration.h2
+                + 8192)
+                >> 14);
+        h -= ((((h >> 15) * (h >> 15)) >> 7) * calibration.h1) >> 4;
+        // In 1/1024 %
+        let humidity = (h.clamp(0, 419430400) >> 12) as u32;
+
+        Ok(Some(Climate {
+            temperature_decicelsius: ((centicelsius + centicelsius.signum() * 5) / 10) as i16,
+            humidity_permille: Some((humidity * 10 / 1024) as u16),
+        }))
+    }
+
+    /// The calibration, if a BME280 responded (a BMP280 has no humidity sensor)
+    async fn read_calibration(&mut self) -> Result<Option<Calibration>, i2c::Error> {
+        let mut chip_id = [0u8];
+        self.i2c
+            .write_read_async(ADDRESS, [CHIP_ID_REGISTER], &mut chip_id)
+            .await?;
+        if chip_id[0] != CHIP_ID {
+            warn!("BME280: unexpected chip ID {:?}", chip_id[0]);
+            return Ok(None);
+        }
+        let mut t = [0u8; 6];
+        self.i2c
+            .write_read_async(ADDRESS, [CALIBRATION_T_REGISTER], &mut t)
+            .await?;
+        let mut h1 = [0u8];
+        self.i2c
+            .write_read_async(ADDRESS, [CALIBRATION_H1_REGISTER], &mut h1)
+            .await?;
+        let mut h = [0u8; 7];
+        self.i2c
+            .write_read_async(ADDRESS, [CALIBRATION_H2_REGISTER], &mut h)
+            .await?;
+        Ok(Some(Calibration {
+            t1: u16::from_le_bytes([t[0], t[1]]) as i32,
+            t2: i16::from_le_bytes([t[2], t[3]]) as i32,
+            t3: i16::from_le_bytes([t[4], t[5]]) as i32,
+            h1: h1[0] as i32,
+            h2: i16::from_le_bytes([h[0], h[1]]) as i32,
+            h3: h[2] as i32,
+            // 12 bits each, sharing the nibbles of 0xE5
+            h4: ((h[3] as i8 as i32) << 4) | (h[4] & 0x0F) as i32,
+            h5: ((h[5] as i8 as i32) << 4) | (h[4] >> 4) as i32,
+            h6: h[6] as i8 as i32,
+        }))
+    }
+}
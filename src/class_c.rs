@@ -0,0 +1,147 @@
+use defmt::{info, warn};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use lorawan_device::async_device::{radio, Device, Timings};
+#[cfg(feature = "class-c")]
+use lorawan_device::mac::Response;
+use lorawan_device::{CryptoFactory, RngCore};
+#[cfg(feature = "class-c")]
+use powermeter_payload::CommandAck;
+use powermeter_payload::{FRAGMENTATION_FPORT, MULTICAST_FPORT};
+
+#[cfg(feature = "class-c")]
+use crate::clock::ClockSync;
+#[cfg(feature = "class-c")]
+use crate::commands::{self, Action};
+#[cfg(feature = "class-c")]
+use crate::config::Config;
+use crate::fuota::Fuota;
+use crate::multicast::ClassCSession;
+use crate::watchdog::{self, Task};
+
+// How long to wait before listening again if the radio failed
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+// The largest LoRaWAN frame
+const MAX_FRAME_SIZE: usize = 256;
+
+/// Listens for downlinks on RX2 for the given time and handles them as they arrive. Returns early if a command has to
+/// be acknowledged or followed up on or a firmware update is ready, so the next uplink goes out right away. Returns
+/// what the main loop has to do after that uplink.
+/// Class C keeps the receiver on all the time, so it is only meant for mains powered installations. lorawan-device
+/// sets up the receive window after each uplink, once class C is enabled.
+#[cfg(feature = "class-c")]
+pub async fn listen<R, C, T, G>(
+    device: &mut Device<R, C, T, G>,
+    duration: Duration,
+    clock_sync: &mut ClockSync,
+    fuota: &mut Fuota,
+    config: &mut Config,
+    pending_ack: &mut Option<CommandAck>,
+) -> Action
+where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    let wake_up = Instant::now() + duration;
+    loop {
+        let remaining = wake_up.saturating_duration_since(Instant::now());
+        watchdog::check_in_after(Task::MainLoop, remaining);
+        watchdog::check_in_after(Task::Radio, remaining);
+        let resp = with_timeout(remaining, device.rxc_listen()).await;
+        watchdog::idle(Task::Radio);
+        let resp = match resp {
+            Err(_) => return Action::None,
+            Ok(resp) => resp,
+        };
+        match resp {
+            Ok(Response::DownlinkReceived(_)) => {
+                info!("Class C downlink received");
+                commands::receive(device);
+                let previous_ack = *pending_ack;
+                let action = commands::dispatch_received(clock_sync, fuota, config, pending_ack);
+                if action != Action::None || *pending_ack != previous_ack || fuota.update_ready() {
+                    return action;
+                }
+            }
+            Ok(resp) => info!("Class C listening ended: {:?}", resp),
+            Err(e) => {
+                warn!("Class C listening failed: {:?}", e);
+                // Not to spin if the radio keeps failing
+                Timer::after(remaining.min(RETRY_DELAY)).await;
+            }
+        }
+    }
+}
+
+/// Waits for a multicast session (e.g. of a firmware update) to start and listens on its frequency and data rate until
+/// it ends, or until the firmware update is ready. lorawan-device only knows about the unicast session, so the frames of
+/// the group are received and decrypted here. Only the multicast and fragmentation packages are accepted from the
+/// group, commands are only taken from the device's own session.
+pub async fn listen_session<R, C, T, G>(
+    device: &mut Device<R, C, T, G>,
+    session: ClassCSession,
+    fuota: &mut Fuota,
+) where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    watchdog::check_in_after(
+        Task::MainLoop,
+        session.start.saturating_duration_since(Instant::now()),
+    );
+    Timer::at(session.start).await;
+    info!("Listening to multicast group {:?}", session.group_id);
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    let mut payload = [0u8; MAX_FRAME_SIZE];
+    let mut rx_ready = false;
+    loop {
+        let remaining = session.end.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_ticks(0) {
+            break;
+        }
+        watchdog::check_in_after(Task::MainLoop, remaining);
+        watchdog::check_in_after(Task::Radio, remaining);
+        let radio = device.get_mut_radio();
+        let resp = with_timeout(remaining, async {
+            if !rx_ready {
+                radio.setup_rx(session.rx_config).await?;
+                rx_ready = true;
+            }
+            radio.rx_continuous(&mut frame).await
+        })
+        .await;
+        watchdog::idle(Task::Radio);
+        let size = match resp {
+            Err(_) => break,
+            Ok(Ok((size, _))) => size,
+            Ok(Err(e)) => {
+                warn!("Multicast listening failed: {:?}", e);
+                rx_ready = false;
+                Timer::after(remaining.min(RETRY_DELAY)).await;
+                continue;
+            }
+        };
+        let Some((fport, size)) =
+            fuota.receive_multicast(session.group_id, &mut frame[..size], &mut payload)
+        else {
+            continue;
+        };
+        if fport != MULTICAST_FPORT && fport != FRAGMENTATION_FPORT {
+            warn!("Ignoring multicast downlink on FPort {:?}", fport);
+            continue;
+        }
+        info!("Multicast downlink on FPort {:?}", fport);
+        fuota.handle_downlink(fport, &payload[..size]);
+        if fuota.update_ready() {
+            break;
+        }
+    }
+    // In class A, the receiver is off until the next uplink. In class C, the next uplink sets up RX2 again.
+    #[cfg(not(feature = "class-c"))]
+    if let Err(e) = device.get_mut_radio().low_power().await {
+        warn!("Putting the radio to sleep failed: {:?}", e);
+    }
+}
@@ -0,0 +1,188 @@
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+use lorawan_device::async_device::{radio, Device, SendResponse, Timings};
+use lorawan_device::{CryptoFactory, RngCore};
+use portable_atomic::{AtomicBool, AtomicU64};
+use powermeter_payload::{ClockSyncDownlink, ClockSyncUplink, CLOCK_SYNC_FPORT};
+
+use crate::commands;
+use crate::config::Config;
+use crate::policy::{self, Delivery};
+use crate::{lorawan_region, mac};
+
+// Seconds from the unix epoch to the GPS epoch (1980-01-06), which the network uses
+const GPS_EPOCH: u64 = 315_964_800;
+// GPS time has no leap seconds. UTC has had 18 of them since the GPS epoch (the last one at the end of 2016).
+const LEAP_SECONDS: u64 = 18;
+
+// We synchronize every 128 * 2^periodicity seconds, unless the network asks for something else. 9 is about 18 hours.
+const DEFAULT_PERIODICITY: u8 = 9;
+// How long to wait for an answer before asking again, while the time is still unknown
+const UNSYNCED_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// The GPS time (in seconds) at which embassy_time's Instant was zero. 0 if the time is unknown.
+static GPS_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+// Set when a known time was corrected, until the load profile takes note of it
+static ADJUSTED: AtomicBool = AtomicBool::new(false);
+
+/// The current time since the unix epoch, if it is known
+pub fn utc_now() -> Option<Duration> {
+    gps_now().map(|gps| Duration::from_secs(gps + GPS_EPOCH - LEAP_SECONDS))
+}
+
+/// Whether the clock was corrected since the last call
+pub fn take_adjusted() -> bool {
+    ADJUSTED.swap(false, Ordering::Relaxed)
+}
+
+/// The current time in seconds since the GPS epoch, if it is known
+pub fn gps_now() -> Option<u64> {
+    match GPS_AT_BOOT.load(Ordering::Relaxed) {
+        0 => None,
+        gps_at_boot => Some(gps_at_boot + Instant::now().as_secs()),
+    }
+}
+
+/// Keeps the clock in sync with the network, using the LoRaWAN Application Layer Clock Synchronization package.
+/// The message format is defined in the powermeter-payload crate.
+pub struct ClockSync {
+    last_request: Option<Instant>,
+    token: u8,           // Identifies the request an answer belongs to, 4 bits
+    periodicity: u8,     // Synchronize every 128 * 2^periodicity seconds
+    forced_requests: u8, // The number of requests the network asked for with ForceDeviceResyncReq
+    package_version_requested: bool,
+    periodicity_answer_due: bool,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self {
+            last_request: None,
+            token: 0,
+            periodicity: DEFAULT_PERIODICITY,
+            forced_requests: 0,
+            package_version_requested: false,
+            periodicity_answer_due: false,
+        }
+    }
+}
+
+impl ClockSync {
+    /// Handles a downlink received on the clock synchronization FPort. Answers are sent with the next [`Self::run`].
+    pub fn handle_downlink(&mut self, payload: &[u8]) {
+        for message in ClockSyncDownlink::decode(payload) {
+            match message {
+                Ok(ClockSyncDownlink::AppTimeAns {
+                    time_correction,
+                    token,
+                }) => {
+                    if token != self.token {
+                        warn!("Ignoring the answer to an old clock sync request.");
+                        continue;
+                    }
+                    // While the time is unknown, we sent the uptime as the device time, so the correction is the
+                    // GPS time at boot
+                    let previous = GPS_AT_BOOT.load(Ordering::Relaxed);
+                    let gps_at_boot = previous.wrapping_add_signed(time_correction as i64);
+                    GPS_AT_BOOT.store(gps_at_boot, Ordering::Relaxed);
+                    if previous != 0 && time_correction != 0 {
+                        ADJUSTED.store(true, Ordering::Relaxed);
+                    }
+                    self.token = (self.token + 1) & 0x0F;
+                    info!("Clock corrected by {:?} s", time_correction);
+                }
+                Ok(ClockSyncDownlink::PackageVersionReq) => self.package_version_requested = true,
+                Ok(ClockSyncDownlink::DeviceAppTimePeriodicityReq { periodicity }) => {
+                    info!(
+                        "Synchronizing the clock every {:?} s",
+                        128u32 << periodicity
+                    );
+                    self.periodicity = periodicity;
+                    self.periodicity_answer_due = true;
+                }
+                Ok(ClockSyncDownlink::ForceDeviceResyncReq { nb_transmissions }) => {
+                    self.forced_requests = nb_transmissions;
+                }
+                Err(e) => warn!("Invalid clock sync downlink: {:?}", e),
+            }
+        }
+    }
+
+    /// Sends a clock sync request if it is time for one, together with the answers to the network's requests
+    pub async fn run<R, C, T, G>(&mut self, device: &mut Device<R, C, T, G>, config: &Config)
+    where
+        R: radio::PhyRxTx + Timings,
+        T: radio::Timer,
+        C: CryptoFactory + Default,
+        G: RngCore,
+    {
+        let mut messages: [Option<ClockSyncUplink>; 3] = [None; 3];
+        // The device time is only sent with AppTimeReqs and DeviceAppTimePeriodicityAns, so modulo 2^32 is fine
+        let device_time = gps_now().unwrap_or(Instant::now().as_secs()) as u32;
+        if self.package_version_requested {
+            messages[0] = Some(ClockSyncUplink::PackageVersionAns);
+        }
+        if self.periodicity_answer_due {
+            messages[1] = Some(ClockSyncUplink::DeviceAppTimePeriodicityAns {
+                not_supported: false,
+                device_time,
+            });
+        }
+        if self.request_due() {
+            messages[2] = Some(ClockSyncUplink::AppTimeReq {
+                device_time,
+                token: self.token,
+                // As long as we don't know the time, the network has to answer even if it thinks we are in sync
+                answer_required: gps_now().is_none(),
+            });
+        }
+        if messages.iter().all(Option::is_none) {
+            return;
+        }
+
+        let mut buf = [0u8; 16];
+        let mut size = 0;
+        for message in messages.iter().flatten() {
+            size += message.encode(&mut buf[size..]).unwrap();
+        }
+        if size + mac::pending_fopts_len() > lorawan_region::max_payload(device.get_datarate()) {
+            warn!("Clock sync request does not fit the current data rate.");
+            return;
+        }
+        info!("Sending clock sync request");
+        let resp = policy::send(
+            device,
+            &buf[..size],
+            CLOCK_SYNC_FPORT,
+            Delivery::Unconfirmed,
+            config,
+        )
+        .await;
+        if let Some(resp) = resp {
+            self.package_version_requested = false;
+            self.periodicity_answer_due = false;
+            if messages[2].is_some() {
+                self.last_request = Some(Instant::now());
+                self.forced_requests = self.forced_requests.saturating_sub(1);
+            }
+            // The network usually answers right away
+            if let SendResponse::DownlinkReceived(_) = resp {
+                commands::receive(device);
+            }
+        }
+    }
+
+    fn request_due(&self) -> bool {
+        let Some(last_request) = self.last_request else {
+            return true;
+        };
+        let interval = if gps_now().is_some() {
+            Duration::from_secs(128 << self.periodicity)
+        } else {
+            UNSYNCED_RETRY_INTERVAL
+        };
+        self.forced_requests > 0 || last_request.elapsed() >= interval
+    }
+}
@@ -0,0 +1,356 @@
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use lorawan_device::async_device::{radio, Device, Timings};
+use lorawan_device::{CryptoFactory, Downlink as LorawanDownlink, RngCore};
+use powermeter_payload::{
+    unzigzag, Command, CommandAck, CommandResult, ConfigKey, Downlink, CLOCK_SYNC_FPORT,
+    COMMAND_FPORT, FRAGMENTATION_FPORT, MAX_COUNTER_IMPULSES, MULTICAST_FPORT,
+};
+
+use crate::alarm::TEMPERATURE_HYSTERESIS_DECICELSIUS;
+use crate::battery::BatteryType;
+use crate::clock::ClockSync;
+use crate::config::{
+    Config, ADC_REFERENCE_RANGE_MV, MAX_AIRTIME_PER_DAY, MAX_ALARM_HOLDOFF,
+    MAX_ALARM_TEMPERATURE_DECICELSIUS, MAX_ANTENNA_GAIN_DBI, MAX_BATTERY_OFFSET_MV,
+    MAX_CONFIRMED_RETRIES, MAX_MEASUREMENT_INTERVAL, MAX_RANDOM_SLEEP_VARIATION, MAX_RETRY_BACKOFF,
+    MAX_S0_STUCK_ALARM_H, MAX_TEMPERATURE_OFFSET_DECICELSIUS, MIN_MEASUREMENT_INTERVAL,
+    TEMPERATURE_SLOPE_PERMILLE,
+};
+use crate::fuota::Fuota;
+use crate::{firmware, lorawan_region, S0_CHANNEL_COUNT, S0_COUNTERS};
+
+// S0 meters have somewhere between 100 and 10000 impulses per kWh, this leaves some headroom
+const MAX_S0_IMP_PER_KWH: u64 = 100_000;
+// How many downlinks can be kept between two calls to `dispatch_received`. Each uplink brings at most one.
+const MAX_RECEIVED: usize = 4;
+
+// The downlinks received since the last call to `dispatch_received`
+static RECEIVED: Mutex<ThreadModeRawMutex, RefCell<[Option<LorawanDownlink>; MAX_RECEIVED]>> =
+    Mutex::new(RefCell::new([const { None }; MAX_RECEIVED]));
+
+/// What the main loop has to do after a command was executed, besides acknowledging it
+#[derive(PartialEq)]
+pub enum Action {
+    None,
+    Reboot,
+    SendStatus,
+    // With ABP, there is nothing to join
+    #[cfg(not(feature = "abp"))]
+    Rejoin,
+}
+
+/// Keeps the downlink received with the last uplink until [`dispatch_received`] is called. Whatever the uplink was
+/// (a measurement, the backlog, a clock sync request, ...), the network may have sent a command with the answer.
+pub fn receive<R, C, T, G>(device: &mut Device<R, C, T, G>)
+where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    // Even an empty downlink shows that a new firmware reaches the network
+    firmware::network_answered();
+    // We have received a downlink, but it does not necessarily contain information
+    let Some(downlink) = device.take_downlink() else {
+        info!("Downlink empty!");
+        return;
+    };
+    RECEIVED.lock(|received| {
+        let mut received = received.borrow_mut();
+        match received.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(downlink),
+            None => warn!(
+                "Too many downlinks received, dropping the one on FPort {:?}",
+                downlink.fport
+            ),
+        }
+    });
+}
+
+/// Dispatches the downlinks kept by [`receive`] in the order they arrived. Returns what the main loop has to do, the
+/// last command asking for something wins.
+pub fn dispatch_received(
+    clock_sync: &mut ClockSync,
+    fuota: &mut Fuota,
+    config: &mut Config,
+    pending_ack: &mut Option<CommandAck>,
+) -> Action {
+    let received = RECEIVED.lock(|received| core::mem::take(&mut *received.borrow_mut()));
+    let mut action = Action::None;
+    for downlink in received.into_iter().flatten() {
+        let downlink_action = dispatch(
+            downlink.fport,
+            &downlink.data,
+            clock_sync,
+            fuota,
+            config,
+            pending_ack,
+        );
+        if downlink_action != Action::None {
+            action = downlink_action;
+        }
+    }
+    action
+}
+
+/// Passes a received downlink on to the clock synchronization or firmware update, or executes the command in it. A new
+/// acknowledgement replaces the pending one. Returns what the main loop has to do.
+pub fn dispatch(
+    fport: u8,
+    payload: &[u8],
+    clock_sync: &mut ClockSync,
+    fuota: &mut Fuota,
+    config: &mut Config,
+    pending_ack: &mut Option<CommandAck>,
+) -> Action {
+    if fport == CLOCK_SYNC_FPORT {
+        clock_sync.handle_downlink(payload);
+        return Action::None;
+    }
+    if fport == MULTICAST_FPORT || fport == FRAGMENTATION_FPORT {
+        fuota.handle_downlink(fport, payload);
+        return Action::None;
+    }
+    let (ack, action) = handle_downlink(fport, payload, config);
+    if ack.is_some() {
+        *pending_ack = ack;
+    }
+    action
+}
+
+/// Executes the command contained in a downlink. Returns the acknowledgement to send with the next uplink (unless it
+/// was a legacy counter update) and what else needs to be done.
+fn handle_downlink(fport: u8, payload: &[u8], config: &mut Config) -> (Option<CommandAck>, Action) {
+    match Downlink::decode(fport, payload) {
+        Ok(Downlink::SetCounter { counter, impulses }) => {
+            info!("Setting counter {:?} to {:?}", counter, impulses);
+            S0_COUNTERS[counter].store(impulses, Ordering::Relaxed);
+            (None, Action::None)
+        }
+        Ok(Downlink::Command { tag, command }) => {
+            info!("Received command {:?} with tag {:?}", command, tag);
+            let (result, action) = execute(command, config);
+            (Some(CommandAck { tag, result }), action)
+        }
+        Err(e) => {
+            warn!("Invalid downlink on FPORT {:?}: {:?}", fport, e);
+            // If at least the tag could be read, the backend gets to know what went wrong
+            match payload.first() {
+                Some(tag) if fport == COMMAND_FPORT => (
+                    Some(CommandAck {
+                        tag: *tag,
+                        result: CommandResult::from(e),
+                    }),
+                    Action::None,
+                ),
+                _ => (None, Action::None),
+            }
+        }
+    }
+}
+
+fn execute(command: Command, config: &mut Config) -> (CommandResult, Action) {
+    match command {
+        Command::SetCounter { channel, impulses } => {
+            S0_COUNTERS[channel].store(impulses, Ordering::Relaxed);
+            (CommandResult::Ok, Action::None)
+        }
+        Command::OffsetCounter { channel, offset } => {
+            // The counter task may be incrementing at the same time, so this has to be a single atomic operation
+            let result =
+                S0_COUNTERS[channel].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                    value
+                        .checked_add_signed(offset)
+                        .filter(|value| *value <= MAX_COUNTER_IMPULSES)
+                });
+            match result {
+                Ok(_) => (CommandResult::Ok, Action::None),
+                Err(_) => (CommandResult::OutOfRange, Action::None),
+            }
+        }
+        Command::SetInterval { seconds } => {
+            let interval = Duration::from_secs(seconds as u64);
+            if !(MIN_MEASUREMENT_INTERVAL..=MAX_MEASUREMENT_INTERVAL).contains(&interval) {
+                return (CommandResult::OutOfRange, Action::None);
+            }
+            config.measurement_interval_s = seconds;
+            (CommandResult::Ok, Action::None)
+        }
+        Command::Reboot => (CommandResult::Ok, Action::Reboot),
+        Command::RequestStatus => (CommandResult::Ok, Action::SendStatus),
+        Command::SetConfig { key, value } => match key {
+            ConfigKey::S0ImpulsesPerKwh(channel) => {
+                if value == 0 || value > MAX_S0_IMP_PER_KWH {
+                    return (CommandResult::OutOfRange, Action::None);
+                }
+                config.s0_imp_per_kwh[channel] = value;
+                (CommandResult::Ok, Action::None)
+            }
+            ConfigKey::RandomSleepVariation => {
+                if value > MAX_RANDOM_SLEEP_VARIATION.as_secs() {
+                    return (CommandResult::OutOfRange, Action::None);
+                }
+                config.random_sleep_variation_s = value as u32;
+                (CommandResult::Ok, Action::None)
+            }
+            ConfigKey::AlignToWallClock => match value {
+                0 | 1 => {
+                    config.align_to_wall_clock = value == 1;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::ConfirmedRetries => {
+                if value > MAX_CONFIRMED_RETRIES as u64 {
+                    return (CommandResult::OutOfRange, Action::None);
+                }
+                config.confirmed_retries = value as u8;
+                (CommandResult::Ok, Action::None)
+            }
+            ConfigKey::RetryBackoff => {
+                if value == 0 || value > MAX_RETRY_BACKOFF.as_secs() {
+                    return (CommandResult::OutOfRange, Action::None);
+                }
+                config.retry_backoff_s = value as u32;
+                (CommandResult::Ok, Action::None)
+            }
+            ConfigKey::AirtimeBudget => {
+                if value == 0 || value > MAX_AIRTIME_PER_DAY.as_secs() {
+                    return (CommandResult::OutOfRange, Action::None);
+                }
+                config.airtime_per_day_s = value as u32;
+                (CommandResult::Ok, Action::None)
+            }
+            ConfigKey::BatteryType => match BatteryType::from_config(value) {
+                Some(battery_type) => {
+                    config.battery_type = battery_type;
+                    (CommandResult::Ok, Action::None)
+                }
+                None => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::BatteryOffset => match u16::try_from(value) {
+                Ok(offset) if offset <= MAX_BATTERY_OFFSET_MV => {
+                    config.battery_offset_mv = offset;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::TemperatureOffset => match i16::try_from(unzigzag(value)) {
+                // `abs` overflows on i16::MIN
+                Ok(offset)
+                    if offset.unsigned_abs() <= MAX_TEMPERATURE_OFFSET_DECICELSIUS as u16 =>
+                {
+                    config.temperature_offset_decicelsius = offset;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::TemperatureSlope => match u16::try_from(value) {
+                Ok(slope) if TEMPERATURE_SLOPE_PERMILLE.contains(&slope) => {
+                    config.temperature_slope_permille = slope;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::AntennaGain => match u8::try_from(value) {
+                Ok(gain) if gain <= MAX_ANTENNA_GAIN_DBI => {
+                    config.antenna_gain_dbi = gain;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::MaxEirp => match u8::try_from(value) {
+                Ok(eirp) if eirp <= lorawan_region::MAX_EIRP => {
+                    config.max_eirp_dbm = eirp;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::AdcReference => match u16::try_from(value) {
+                Ok(reference) if ADC_REFERENCE_RANGE_MV.contains(&reference) => {
+                    config.adc_reference_mv = reference;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            // The high threshold has to stay above the low one by more than the hysteresis, or both alarms would be
+            // active at once or flip every cycle
+            ConfigKey::TemperatureHighAlarm => match i16::try_from(unzigzag(value)) {
+                Ok(threshold)
+                    if threshold.unsigned_abs() <= MAX_ALARM_TEMPERATURE_DECICELSIUS as u16
+                        && threshold
+                            > config.temperature_low_alarm_decicelsius
+                                + TEMPERATURE_HYSTERESIS_DECICELSIUS =>
+                {
+                    config.temperature_high_alarm_decicelsius = threshold;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::TemperatureLowAlarm => match i16::try_from(unzigzag(value)) {
+                Ok(threshold)
+                    if threshold.unsigned_abs() <= MAX_ALARM_TEMPERATURE_DECICELSIUS as u16
+                        && threshold + TEMPERATURE_HYSTERESIS_DECICELSIUS
+                            < config.temperature_high_alarm_decicelsius =>
+                {
+                    config.temperature_low_alarm_decicelsius = threshold;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::MeterFailureAlarm => match u8::try_from(value) {
+                Ok(failures) => {
+                    config.meter_failure_alarm = failures;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::BatteryLowAlarm => match u8::try_from(value) {
+                Ok(percent) if percent <= 100 => {
+                    config.battery_low_alarm_percent = percent;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::S0StuckAlarm => match u16::try_from(value) {
+                Ok(hours) if hours <= MAX_S0_STUCK_ALARM_H => {
+                    config.s0_stuck_alarm_h = hours;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::S0RateAlarm => match u32::try_from(value) {
+                Ok(rate) => {
+                    config.s0_rate_alarm_per_h = rate;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::S0AlarmChannels => match u8::try_from(value) {
+                Ok(channels) if channels >> S0_CHANNEL_COUNT == 0 => {
+                    config.s0_alarm_channels = channels;
+                    (CommandResult::Ok, Action::None)
+                }
+                _ => (CommandResult::OutOfRange, Action::None),
+            },
+            ConfigKey::AlarmHoldoff => {
+                if value > MAX_ALARM_HOLDOFF.as_secs() {
+                    return (CommandResult::OutOfRange, Action::None);
+                }
+                config.alarm_holdoff_s = value as u32;
+                (CommandResult::Ok, Action::None)
+            }
+        },
+        #[cfg(not(feature = "abp"))]
+        Command::Rejoin => (CommandResult::Ok, Action::Rejoin),
+        // Joining again would only reset the frame counters, the network server would drop every uplink after that
+        #[cfg(feature = "abp")]
+        Command::Rejoin => (CommandResult::Failed, Action::None),
+    }
+}
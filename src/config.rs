@@ -0,0 +1,166 @@
+use bincode::{Decode, Encode};
+use defmt::info;
+use embassy_time::Duration;
+
+use crate::battery::BatteryType;
+use crate::{
+    clock, lorawan_region, MEASUREMENT_TRANSMIT_INTERVAL, RANDOM_SLEEP_VARIATION, S0_CHANNEL_COUNT,
+    S0_IMP_PER_KWH,
+};
+
+// The range the measurement interval and its random variation can be set to by a command
+pub const MIN_MEASUREMENT_INTERVAL: Duration = Duration::from_secs(10);
+pub const MAX_MEASUREMENT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_RANDOM_SLEEP_VARIATION: Duration = Duration::from_secs(10 * 60);
+
+// How often important uplinks are repeated if they are not acknowledged, and how long to wait before the first repetition
+// (the delay doubles each time)
+const CONFIRMED_RETRIES: u8 = 2;
+const RETRY_BACKOFF: Duration = Duration::from_secs(60);
+pub const MAX_CONFIRMED_RETRIES: u8 = 8;
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+// The Things Network's fair use policy allows 30 seconds of uplink airtime per day and device. A larger budget can be
+// configured for other networks, up to what the duty cycle allows.
+const AIRTIME_PER_DAY: Duration = Duration::from_secs(30);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_AIRTIME_PER_DAY: Duration =
+    Duration::from_secs(DAY.as_secs() / lorawan_region::DUTY_CYCLE_DIVISOR as u64);
+
+// The gain of the antenna attached to the SX1262, in dBi, and the radiated power (EIRP) the transmit power is limited
+// to. The EIRP can only be lowered from the region's maximum.
+pub const ANTENNA_GAIN_DBI: u8 = 2;
+pub const MAX_ANTENNA_GAIN_DBI: u8 = 20;
+
+// Protection diodes and switches between the battery and VSYS drop a few hundred mV at most
+pub const MAX_BATTERY_OFFSET_MV: u16 = 1000;
+
+// The calibration of the internal temperature sensor, reading × slope + offset. The RP2040 datasheet only promises a
+// few degrees, which the offset covers many times over.
+pub const MAX_TEMPERATURE_OFFSET_DECICELSIUS: i16 = 200;
+pub const TEMPERATURE_SLOPE_PERMILLE: core::ops::RangeInclusive<u16> = 500..=1500;
+// The ADC reference (VREF) is the 3.3 V supply on the Pico boards, or an external reference on ADC_VREF. The ADC
+// works from 1.8 V up to its 3.6 V supply.
+pub const ADC_REFERENCE_MV: u16 = 3300;
+pub const ADC_REFERENCE_RANGE_MV: core::ops::RangeInclusive<u16> = 1800..=3600;
+
+// The alarms a new device checks: the temperature outside of what the electronics in a cabinet are usually rated for,
+// and a battery that is about to run out. The meter and S0 alarms depend on what is connected, they start disabled.
+const TEMPERATURE_HIGH_ALARM_DECICELSIUS: i16 = 600;
+const TEMPERATURE_LOW_ALARM_DECICELSIUS: i16 = -200;
+const BATTERY_LOW_ALARM_PERCENT: u8 = 10;
+const ALARM_HOLDOFF: Duration = Duration::from_secs(15 * 60);
+pub const MAX_ALARM_TEMPERATURE_DECICELSIUS: i16 = 1000;
+pub const MAX_S0_STUCK_ALARM_H: u16 = 31 * 24;
+pub const MAX_ALARM_HOLDOFF: Duration = DAY;
+
+/// The settings that can be changed by downlink commands. They are saved to flash together with the counters.
+#[derive(Clone, Encode, Decode)]
+pub struct Config {
+    pub measurement_interval_s: u32,
+    pub random_sleep_variation_s: u32,
+    // Measure at multiples of the interval (e.g. at :00, :15, :30 and :45 for billing periods), once the time is known
+    pub align_to_wall_clock: bool,
+    pub s0_imp_per_kwh: [u64; S0_CHANNEL_COUNT],
+    pub confirmed_retries: u8,
+    pub retry_backoff_s: u32,
+    pub airtime_per_day_s: u32,
+    pub antenna_gain_dbi: u8,
+    pub max_eirp_dbm: u8,
+    pub battery_type: BatteryType,
+    pub battery_offset_mv: u16,
+    pub temperature_offset_decicelsius: i16,
+    pub temperature_slope_permille: u16,
+    pub adc_reference_mv: u16,
+    // The alarm thresholds (see alarm.rs). 0 disables the meter, battery and S0 alarms.
+    pub temperature_high_alarm_decicelsius: i16,
+    pub temperature_low_alarm_decicelsius: i16,
+    pub meter_failure_alarm: u8,
+    pub battery_low_alarm_percent: u8,
+    pub s0_stuck_alarm_h: u16,
+    pub s0_rate_alarm_per_h: u32,
+    pub s0_alarm_channels: u8, // Bit 0 to 5 for channel 0 to 5
+    pub alarm_holdoff_s: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            measurement_interval_s: MEASUREMENT_TRANSMIT_INTERVAL.as_secs() as u32,
+            random_sleep_variation_s: RANDOM_SLEEP_VARIATION.as_secs() as u32,
+            align_to_wall_clock: false,
+            s0_imp_per_kwh: S0_IMP_PER_KWH,
+            confirmed_retries: CONFIRMED_RETRIES,
+            retry_backoff_s: RETRY_BACKOFF.as_secs() as u32,
+            airtime_per_day_s: AIRTIME_PER_DAY.as_secs() as u32,
+            antenna_gain_dbi: ANTENNA_GAIN_DBI,
+            max_eirp_dbm: lorawan_region::MAX_EIRP,
+            battery_type: match cfg!(feature = "battery") {
+                true => BatteryType::LithiumThionylChloride,
+                false => BatteryType::Mains,
+            },
+            battery_offset_mv: 0,
+            temperature_offset_decicelsius: 0,
+            temperature_slope_permille: 1000,
+            adc_reference_mv: ADC_REFERENCE_MV,
+            temperature_high_alarm_decicelsius: TEMPERATURE_HIGH_ALARM_DECICELSIUS,
+            temperature_low_alarm_decicelsius: TEMPERATURE_LOW_ALARM_DECICELSIUS,
+            meter_failure_alarm: 0,
+            battery_low_alarm_percent: BATTERY_LOW_ALARM_PERCENT,
+            s0_stuck_alarm_h: 0,
+            s0_rate_alarm_per_h: 0,
+            s0_alarm_channels: (1 << S0_CHANNEL_COUNT) - 1,
+            alarm_holdoff_s: ALARM_HOLDOFF.as_secs() as u32,
+        }
+    }
+}
+
+impl Config {
+    /// The uplink airtime that may be used over a day
+    pub fn airtime_budget(&self) -> Duration {
+        Duration::from_secs(self.airtime_per_day_s as u64)
+    }
+
+    /// The measurement interval, stretched so that using the given airtime every interval stays within the region's
+    /// duty cycle and the airtime budget
+    pub fn effective_interval(&self, airtime: Duration) -> Duration {
+        let configured = Duration::from_secs(self.measurement_interval_s as u64);
+        let duty_cycle_limit = airtime * lorawan_region::DUTY_CYCLE_DIVISOR;
+        let budget_limit = Duration::from_micros(
+            airtime.as_micros() * DAY.as_secs() / self.airtime_per_day_s.max(1) as u64,
+        );
+        configured.max(duty_cycle_limit).max(budget_limit)
+    }
+
+    /// How long to sleep until the next measurement. `random` selects the random variation that is added, so not all
+    /// devices send at the same time.
+    pub fn sleep_duration(&self, airtime: Duration, random: u32) -> Duration {
+        let interval = self.effective_interval(airtime);
+        if interval > Duration::from_secs(self.measurement_interval_s as u64) {
+            info!(
+                "Interval extended to {:?} s to stay within the airtime limits",
+                interval.as_secs()
+            );
+        }
+        let variation = Duration::from_micros(
+            Duration::from_secs(self.random_sleep_variation_s as u64).as_micros() * random as u64
+                / u32::MAX as u64,
+        );
+
+        if self.align_to_wall_clock {
+            if let Some(now) = clock::utc_now() {
+                // Sleep until the next multiple of the interval since midnight, the random variation is added after
+                // that. A day that is not a multiple of the interval ends with a shorter one, so each day starts at
+                // midnight again instead of drifting.
+                let since_midnight = now.as_micros() % DAY.as_micros();
+                let mut until_boundary =
+                    interval.as_micros() - since_midnight % interval.as_micros();
+                if interval <= DAY {
+                    until_boundary = until_boundary.min(DAY.as_micros() - since_midnight);
+                }
+                return Duration::from_micros(until_boundary) + variation;
+            }
+        }
+        interval + variation
+    }
+}
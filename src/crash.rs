@@ -0,0 +1,240 @@
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::Ordering;
+
+use cortex_m::peripheral::SCB;
+use cortex_m::register::msp;
+use cortex_m_rt::{exception, ExceptionFrame};
+use defmt::{error, Display2Format};
+use embassy_time::Instant;
+use lorawan_device::async_device::{radio, Device, Timings};
+use lorawan_device::{CryptoFactory, RngCore};
+use portable_atomic::AtomicBool;
+use powermeter_payload::crash::hash;
+use powermeter_payload::{
+    Crash, CrashHasher, Diagnostic, ResetReason, Uplink, MAX_CRASH_ADDRESSES,
+};
+
+use crate::config::Config;
+use crate::policy::Delivery;
+use crate::{lorawan_region, mac, network};
+
+// How far up the stack the panic and hard fault handlers look for return addresses, in words
+const MAX_SCAN_WORDS: usize = 256;
+
+// Mark the contents of the retained RAM as written by us, anything else is what the RAM powered up with
+const RUNNING_MAGIC: u32 = 0x5255_4E21;
+const CRASH_MAGIC: u32 = 0x4352_4153;
+
+// Kept in the RAM region of the same name (see memory.x), which is neither initialized on startup nor used by the
+// bootloader, so it survives any reset but a power failure
+#[link_section = ".crash_dump"]
+static mut RUNNING: MaybeUninit<u32> = MaybeUninit::uninit();
+#[link_section = ".crash_dump"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+// A panic while handling a panic (or hard fault) just restarts
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    // From the cortex-m-rt linker script: the code, and the top of the stack
+    static __stext: u32;
+    static __etext: u32;
+    static _stack_start: u32;
+}
+
+/// The crash as saved in RAM
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Record {
+    magic: u32,
+    reason: u32,
+    uptime_s: u32,
+    line: u32,
+    file_hash: u32,
+    message_hash: u32,
+    address_count: u32,
+    addresses: [u32; MAX_CRASH_ADDRESSES],
+    checksum: u32,
+}
+
+impl Record {
+    fn new(reason: ResetReason, crash: &Crash) -> Self {
+        let mut addresses = [0; MAX_CRASH_ADDRESSES];
+        let mut address_count = 0;
+        for (slot, address) in addresses.iter_mut().zip(crash.addresses.iter().flatten()) {
+            *slot = *address;
+            address_count += 1;
+        }
+        let mut record = Self {
+            magic: CRASH_MAGIC,
+            reason: reason.to_byte() as u32,
+            uptime_s: crash.uptime_s,
+            line: crash.line,
+            file_hash: crash.file_hash,
+            message_hash: crash.message_hash,
+            address_count,
+            addresses,
+            checksum: 0,
+        };
+        record.checksum = record.checksum();
+        record
+    }
+
+    fn checksum(&self) -> u32 {
+        [
+            self.reason,
+            self.uptime_s,
+            self.line,
+            self.file_hash,
+            self.message_hash,
+            self.address_count,
+        ]
+        .into_iter()
+        .chain(self.addresses)
+        .fold(CRASH_MAGIC, |sum, word| sum.rotate_left(5) ^ word)
+    }
+
+    /// The crash, if the record is valid. After a power failure, the RAM holds random values.
+    fn crash(&self) -> Option<(ResetReason, Crash)> {
+        if self.magic != CRASH_MAGIC || self.checksum != self.checksum() {
+            return None;
+        }
+        let reason = ResetReason::from_byte(self.reason as u8).ok()?;
+        let mut crash = Crash {
+            uptime_s: self.uptime_s,
+            line: self.line,
+            file_hash: self.file_hash,
+            message_hash: self.message_hash,
+            ..Default::default()
+        };
+        for address in self.addresses.iter().take(self.address_count as usize) {
+            crash.push(*address);
+        }
+        Some((reason, crash))
+    }
+}
+
+/// What the previous run left in RAM
+pub struct Previous {
+    /// Whether the RAM kept its contents since then, it does over anything but a longer power failure
+    pub ram_retained: bool,
+    /// Why and where it crashed, after a panic or hard fault
+    pub crash: Option<(ResetReason, Crash)>,
+}
+
+/// Takes what the previous run left in RAM, and starts recording this one. Called once, at startup.
+pub fn recover() -> Previous {
+    // SAFETY: Only accessed here and in the crash handlers, which don't return. Any contents are valid u32s.
+    unsafe {
+        let ram_retained = addr_of!(RUNNING).cast::<u32>().read_volatile() == RUNNING_MAGIC;
+        let crash = addr_of!(RECORD).cast::<Record>().read_volatile().crash();
+        // Not to report the crash again after the next reset
+        addr_of_mut!(RECORD).cast::<u32>().write_volatile(0);
+        addr_of_mut!(RUNNING)
+            .cast::<u32>()
+            .write_volatile(RUNNING_MAGIC);
+        Previous {
+            ram_retained,
+            crash,
+        }
+    }
+}
+
+/// Sends where the previous firmware crashed, leaving out the outermost addresses if the data rate requires it
+pub async fn send_report<R, C, T, G>(
+    device: &mut Device<R, C, T, G>,
+    mut diagnostic: Diagnostic,
+    config: &Config,
+) where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    let max_payload =
+        lorawan_region::max_payload(device.get_datarate()).saturating_sub(mac::pending_fopts_len());
+    while diagnostic.size() > max_payload && diagnostic.crash.as_mut().is_some_and(Crash::pop) {}
+    network::send_uplink(
+        device,
+        &Uplink::Diagnostic(diagnostic),
+        Delivery::Retried,
+        config,
+    )
+    .await;
+}
+
+/// Replaces panic-probe, which only prints over RTT: the panic is saved for the diagnostic uplink after the restart
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    if !CRASHING.swap(true, Ordering::Relaxed) {
+        let mut crash = Crash {
+            uptime_s: Instant::now().as_secs() as u32,
+            ..Default::default()
+        };
+        if let Some(location) = info.location() {
+            crash.line = location.line();
+            crash.file_hash = hash(location.file());
+        }
+        let mut message = CrashHasher::default();
+        let _ = write!(message, "{}", info.message());
+        crash.message_hash = message.finish();
+        scan_stack(msp::read() as *const u32, &mut crash);
+        save(ResetReason::Panic, &crash);
+        // Only after saving, in case the logger itself panicked
+        error!("{}", Display2Format(info));
+    }
+    SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    if !CRASHING.swap(true, Ordering::Relaxed) {
+        let mut crash = Crash {
+            uptime_s: Instant::now().as_secs() as u32,
+            ..Default::default()
+        };
+        crash.push(frame.pc());
+        crash.push(frame.lr() & !1);
+        // The stack of the code that faulted continues after the exception frame (8 words)
+        scan_stack(
+            (frame as *const ExceptionFrame).cast::<u32>().add(8),
+            &mut crash,
+        );
+        save(ResetReason::HardFault, &crash);
+    }
+    SCB::sys_reset();
+}
+
+/// Adds the words on the stack that look like return addresses: within the code and with the thumb bit set. Without
+/// frame pointers, there is no telling them from stale values left on the stack.
+fn scan_stack(mut word: *const u32, crash: &mut Crash) {
+    // SAFETY: Only the addresses of the linker symbols are used, and the stack is read between the stack pointer and
+    // its top
+    unsafe {
+        let code = addr_of!(__stext) as u32..addr_of!(__etext) as u32;
+        let top = addr_of!(_stack_start);
+        for _ in 0..MAX_SCAN_WORDS {
+            if word >= top {
+                return;
+            }
+            let value = word.read_volatile();
+            if value & 1 == 1 && code.contains(&value) && !crash.push(value & !1) {
+                return;
+            }
+            word = word.add(1);
+        }
+    }
+}
+
+fn save(reason: ResetReason, crash: &Crash) {
+    // SAFETY: Interrupts are off and we restart right after
+    unsafe {
+        addr_of_mut!(RECORD)
+            .cast::<Record>()
+            .write_volatile(Record::new(reason, crash));
+    }
+}
@@ -0,0 +1,168 @@
+use defmt::warn;
+use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::gpio::Pull;
+use embassy_rp::peripherals::{PIN_6, PIO1};
+use embassy_rp::pio::{
+    Common, Config, InterruptHandler, Pio, ShiftConfig, ShiftDirection, StateMachine,
+};
+use embassy_time::Timer;
+
+use crate::temperature::Climate;
+
+bind_interrupts!(struct Irqs {
+    PIO1_IRQ_0 => InterruptHandler<PIO1>;
+});
+
+// The state machine runs at about 2 µs per cycle, the 1-Wire timing is counted in those
+const CYCLE_HZ: u32 = 490_000;
+// The reset pulse, in cycles (about 500 µs)
+const RESET_CYCLES: u32 = 250;
+// ROM command: there is only one device on the bus
+const SKIP_ROM: u8 = 0xCC;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+const SCRATCHPAD_SIZE: usize = 9;
+// A 12-bit conversion takes up to 750 ms
+const CONVERSION_TIME_MS: u64 = 750;
+// The scratchpad holds this until the first conversion, e.g. after the sensor lost power
+const POWER_ON_VALUE: i16 = 0x0550;
+
+/// A DS18B20 on GPIO6, with a 4.7 kΩ pull-up to 3.3 V. The 1-Wire protocol runs on PIO1, PIO0 drives the CYW43.
+pub struct Ds18b20 {
+    // The program stays loaded as long as the sensor is used
+    _common: Common<'static, PIO1>,
+    sm: StateMachine<'static, PIO1, 0>,
+}
+
+impl Ds18b20 {
+    pub fn new(pio: PIO1, pin: PIN_6) -> Self {
+        let Pio {
+            mut common,
+            mut sm0,
+            ..
+        } = Pio::new(pio, Irqs);
+        // Writes: the reset pulse length, the number of bytes - 1, then the bytes. Reads: 0, the number of bytes - 1,
+        // then the bytes come back in the upper byte of each word.
+        let program = pio_proc::pio_asm!(
+            r#"
+                .wrap_target
+                again:
+                    pull block
+                    mov x, osr
+                    jmp !x, read
+                write:
+                    set pindirs, 1
+                    set pins, 0
+                loop1:
+                    jmp x--,loop1
+                    set pindirs, 0 [31]
+                    wait 1 pin 0 [31]
+                    pull block
+                    mov x, osr
+                bytes1:
+                    pull block
+                    set y, 7
+                    set pindirs, 1
+                bit1:
+                    set pins, 0 [1]
+                    out pins,1 [31]
+                    set pins, 1 [20]
+                    jmp y--,bit1
+                    jmp x--,bytes1
+                    set pindirs, 0 [31]
+                    jmp again
+                read:
+                    pull block
+                    mov x, osr
+                bytes2:
+                    set y, 7
+                bit2:
+                    set pindirs, 1
+                    set pins, 0 [1]
+                    set pindirs, 0 [5]
+                    in pins,1 [10]
+                    jmp y--,bit2
+                    jmp x--,bytes2
+                .wrap
+            "#,
+        );
+        let mut pin = common.make_pio_pin(pin);
+        pin.set_pull(Pull::Up);
+        let mut config = Config::default();
+        config.use_program(&common.load_program(&program.program), &[]);
+        config.set_out_pins(&[&pin]);
+        config.set_in_pins(&[&pin]);
+        config.set_set_pins(&[&pin]);
+        config.shift_in = ShiftConfig {
+            auto_fill: true,
+            direction: ShiftDirection::Right,
+            threshold: 8,
+        };
+        // The system clock is lower on battery
+        let divider = u8::try_from(clk_sys_freq() / CYCLE_HZ).unwrap_or(u8::MAX);
+        config.clock_divider = divider.into();
+        sm0.set_config(&config);
+        sm0.set_enable(true);
+        Self {
+            _common: common,
+            sm: sm0,
+        }
+    }
+
+    pub async fn measure(&mut self) -> Option<Climate> {
+        self.write(&[SKIP_ROM, CONVERT_T]).await;
+        Timer::after_millis(CONVERSION_TIME_MS).await;
+        self.write(&[SKIP_ROM, READ_SCRATCHPAD]).await;
+        let mut scratchpad = [0u8; SCRATCHPAD_SIZE];
+        self.read(&mut scratchpad).await;
+        // Without a sensor, the pull-up makes all bytes 0xFF, which fails the CRC as well
+        if crc8(&scratchpad) != 0 {
+            warn!("DS18B20: CRC error");
+            return None;
+        }
+        // In 1/16 °C
+        let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+        if raw == POWER_ON_VALUE {
+            warn!("DS18B20: no conversion");
+            return None;
+        }
+        Some(Climate {
+            temperature_decicelsius: ((raw as i32 * 10 + raw.signum() as i32 * 8) / 16) as i16,
+            humidity_permille: None,
+        })
+    }
+
+    /// Resets the bus and writes the bytes
+    async fn write(&mut self, bytes: &[u8]) {
+        self.sm.tx().wait_push(RESET_CYCLES).await;
+        self.sm.tx().wait_push(bytes.len() as u32 - 1).await;
+        for byte in bytes {
+            self.sm.tx().wait_push(*byte as u32).await;
+        }
+    }
+
+    async fn read(&mut self, bytes: &mut [u8]) {
+        self.sm.tx().wait_push(0).await;
+        self.sm.tx().wait_push(bytes.len() as u32 - 1).await;
+        for byte in bytes.iter_mut() {
+            *byte = (self.sm.rx().wait_pull().await >> 24) as u8;
+        }
+    }
+}
+
+/// The Dallas/Maxim CRC-8, 0 over data followed by its CRC
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |mut crc, byte| {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            byte >>= 1;
+        }
+        crc
+    })
+}
@@ -0,0 +1,162 @@
+use core::sync::atomic::Ordering;
+
+use const_hex::decode_to_array;
+use defmt::{info, warn, Format};
+use embassy_boot::{FirmwareUpdaterError, State};
+use embassy_boot_rp::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig};
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_executor::Spawner;
+use embassy_rp::flash::{ERASE_SIZE, WRITE_SIZE};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::{NorFlash, NorFlashErrorKind, ReadNorFlash};
+use portable_atomic::AtomicBool;
+use powermeter_payload::ResetReason;
+
+use crate::flash_region::{self, BlockingFlash};
+use crate::watchdog;
+
+// Fragments are written at arbitrary offsets
+const _: () = assert!(WRITE_SIZE == 1);
+
+// How long a new firmware has to confirm that it works. Otherwise we restart, and the bootloader falls back to the
+// previous one. Long enough for a few unanswered confirmed uplinks and link checks (repeated hourly) in a row.
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// The size of the ed25519 signature that follows the image in a firmware update
+pub const SIGNATURE_SIZE: usize = 64;
+
+// Set while this firmware was swapped in by the bootloader and has not confirmed that it works yet
+static ON_TRIAL: AtomicBool = AtomicBool::new(false);
+// What this firmware has shown to work so far: the network answered, and something was measured
+static NETWORK_ANSWERED: AtomicBool = AtomicBool::new(false);
+static MEASURED: AtomicBool = AtomicBool::new(false);
+
+type Partition = BlockingPartition<'static, ThreadModeRawMutex, BlockingFlash>;
+
+/// Reading or writing the DFU partition failed
+#[derive(Format)]
+pub struct FlashError;
+
+/// Gives access to the DFU partition (see memory.x), where a new firmware image is stored until the bootloader swaps
+/// it in, and to the state partition the bootloader takes its instructions from. Offsets are relative to the start of
+/// the partition.
+fn with_partitions<T>(f: impl FnOnce(FirmwareUpdaterConfig<Partition, Partition>) -> T) -> T {
+    let flash = flash_region::shared();
+    f(FirmwareUpdaterConfig::from_linkerfile_blocking(
+        flash, flash,
+    ))
+}
+
+/// The largest data block (image and signature) the DFU partition takes
+pub fn capacity() -> usize {
+    // The bootloader needs one sector more than the image to swap it in
+    with_partitions(|config| config.dfu.capacity() - ERASE_SIZE)
+}
+
+/// Erases the sectors needed for a data block of the given size, taking a break after each one so the other tasks
+/// get to run. Erasing the whole partition at once would block them for several seconds.
+pub async fn erase(size: usize) -> Result<(), FlashError> {
+    let end = size.div_ceil(ERASE_SIZE) * ERASE_SIZE;
+    for sector in (0..end).step_by(ERASE_SIZE) {
+        with_partitions(|mut config| {
+            config
+                .dfu
+                .erase(sector as u32, (sector + ERASE_SIZE) as u32)
+        })
+        .map_err(|_| FlashError)?;
+        Timer::after_millis(1).await;
+    }
+    info!("Erased {:?} bytes of the DFU partition", end);
+    Ok(())
+}
+
+/// Writes to the DFU partition, which has to be erased at that place
+pub fn write(offset: usize, data: &[u8]) -> Result<(), FlashError> {
+    with_partitions(|mut config| config.dfu.write(offset as u32, data)).map_err(|_| FlashError)
+}
+
+pub fn read(offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+    with_partitions(|mut config| config.dfu.read(offset as u32, buf)).map_err(|_| FlashError)
+}
+
+fn with_updater<T>(f: impl FnOnce(&mut BlockingFirmwareUpdater<Partition, Partition>) -> T) -> T {
+    with_partitions(|config| {
+        let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+        f(&mut BlockingFirmwareUpdater::new(config, &mut aligned.0))
+    })
+}
+
+/// Checks the signature following the image of the given size in the DFU partition and, if it is valid, marks the
+/// image to be swapped in by the bootloader on the next restart
+pub fn verify_and_mark_updated(image_size: usize) -> Result<(), FirmwareUpdaterError> {
+    // Generated as described in device-config/README.md
+    const PUBLIC_KEY: &str = include_str!("../device-config/FIRMWARE_PUBLIC_KEY");
+
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    read(image_size, &mut signature)
+        .map_err(|_| FirmwareUpdaterError::Flash(NorFlashErrorKind::Other))?;
+    with_updater(|updater| {
+        updater.verify_and_mark_updated(
+            &decode_to_array(PUBLIC_KEY).unwrap(),
+            &signature,
+            image_size as u32,
+        )
+    })
+}
+
+/// If the bootloader has just swapped in this firmware, it is on trial: unless both [`network_answered`] and
+/// [`measured`] are called in time, we restart and the bootloader swaps the previous firmware back in. The same happens
+/// if it crashes before that.
+pub fn start_trial(spawner: Spawner) {
+    if !matches!(with_updater(|updater| updater.get_state()), Ok(State::Swap)) {
+        return;
+    }
+    info!("Running a new firmware, waiting for it to work before keeping it.");
+    ON_TRIAL.store(true, Ordering::Relaxed);
+    spawner.spawn(trial_task()).unwrap();
+}
+
+/// The network answered: a join was accepted, a downlink received or a confirmed uplink acknowledged
+pub fn network_answered() {
+    NETWORK_ANSWERED.store(true, Ordering::Relaxed);
+    confirm_if_working();
+}
+
+/// Whether a new firmware still waits for the network to answer. A restored session is not joined and measurements
+/// go out unconfirmed, so they are confirmed until then.
+pub fn awaiting_network() -> bool {
+    ON_TRIAL.load(Ordering::Relaxed) && !NETWORK_ANSWERED.load(Ordering::Relaxed)
+}
+
+/// The energy meter was read, or an S0 input counted (for installations without a meter)
+pub fn measured() {
+    MEASURED.store(true, Ordering::Relaxed);
+    confirm_if_working();
+}
+
+/// Tells the bootloader to keep the current firmware once it has shown to work
+fn confirm_if_working() {
+    if !ON_TRIAL.load(Ordering::Relaxed)
+        || !NETWORK_ANSWERED.load(Ordering::Relaxed)
+        || !MEASURED.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    match with_updater(|updater| updater.mark_booted()) {
+        Ok(()) => {
+            info!("New firmware confirmed.");
+            ON_TRIAL.store(false, Ordering::Relaxed);
+        }
+        Err(e) => warn!("Confirming the new firmware failed: {:?}", e),
+    }
+}
+
+#[embassy_executor::task]
+async fn trial_task() {
+    Timer::after(TRIAL_TIMEOUT).await;
+    if ON_TRIAL.load(Ordering::Relaxed) {
+        warn!("The new firmware did not confirm in time, falling back to the previous one.");
+        watchdog::restart(ResetReason::TrialFailed);
+    }
+}
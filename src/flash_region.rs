@@ -0,0 +1,187 @@
+use bincode::de::read::Reader;
+use bincode::enc::write::Writer;
+use bincode::error::{DecodeError, EncodeError};
+use core::cell::{Cell, RefCell};
+
+use bincode::{Decode, Encode};
+use defmt::warn;
+use embassy_rp::flash::{Blocking, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use static_cell::StaticCell;
+
+// A slot starts with a sequence number, which is written last, so an interrupted write leaves the previous slot the
+// newest one
+const SEQUENCE_SIZE: usize = 4;
+const ERASED_SEQUENCE: u32 = u32::MAX;
+// The program/erase cycles the flash chip (W25Q16JV) is rated for
+const ERASE_CYCLES: u32 = 100_000;
+
+/// The size of the Pico's flash chip
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+pub type BlockingFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+/// The one driver of the flash, which the regions and the firmware updater share. Every erase and write is blocking,
+/// so one is always finished before the next one starts.
+pub type SharedFlash = Mutex<ThreadModeRawMutex, RefCell<BlockingFlash>>;
+
+static SHARED_FLASH: Mutex<ThreadModeRawMutex, Cell<Option<&'static SharedFlash>>> =
+    Mutex::new(Cell::new(None));
+
+/// Takes over the flash. Has to be called on boot, before anything else reads or writes it.
+pub fn init(flash: FLASH) {
+    static OWNER: StaticCell<SharedFlash> = StaticCell::new();
+    let shared = OWNER.init(Mutex::new(RefCell::new(Flash::new_blocking(flash))));
+    SHARED_FLASH.lock(|shared_flash| shared_flash.set(Some(shared)));
+}
+
+/// The flash taken over by [`init`]
+pub fn shared() -> &'static SharedFlash {
+    SHARED_FLASH
+        .lock(Cell::get)
+        .expect("The flash is used before it was taken over")
+}
+
+/// A flash region of its own (see memory.x) for a record, e.g. the persistent state. The record is saved to the
+/// region's slots in turns, so they wear evenly, and the newest complete one is loaded.
+pub struct FlashRegion {
+    start: u32, // Offsets from the start of the flash, as the linker symbols of memory.x give them
+    end: u32,
+    slot_size: usize,
+}
+
+impl FlashRegion {
+    /// The region between the addresses of the linker symbols, which are offsets from the start of the flash. The
+    /// slot size has to be a multiple of the erase size.
+    pub fn new(start: &u32, end: &u32, slot_size: usize) -> Self {
+        Self {
+            start: start as *const u32 as u32,
+            end: end as *const u32 as u32,
+            slot_size,
+        }
+    }
+
+    /// The largest record a slot takes
+    pub const fn record_capacity(slot_size: usize) -> usize {
+        slot_size - SEQUENCE_SIZE
+    }
+
+    /// The record in the newest slot, if there is one and it can be decoded
+    pub fn load<T: Decode>(&self) -> Option<T> {
+        shared().lock(|flash| {
+            let flash = &mut *flash.borrow_mut();
+            let (slot, _) = self.newest(flash)?;
+            let reader = FlashReader {
+                flash,
+                offset: self.slot_offset(slot) + SEQUENCE_SIZE as u32,
+            };
+            bincode::decode_from_reader(reader, bincode_config())
+                .inspect_err(|_| warn!("A record in flash is unreadable!"))
+                .ok()
+        })
+    }
+
+    /// Writes the record to the slot after the newest one
+    pub fn save<T: Encode>(&self, record: &T) {
+        shared().lock(|flash| {
+            let flash = &mut *flash.borrow_mut();
+            let (slot, sequence) = match self.newest(flash) {
+                Some((slot, sequence)) => ((slot + 1) % self.slot_count(), sequence + 1),
+                None => (0, 0),
+            };
+            let offset = self.slot_offset(slot);
+            if flash
+                .blocking_erase(offset, offset + self.slot_size as u32)
+                .is_err()
+            {
+                warn!("Erasing a flash slot failed!");
+                return;
+            }
+            let writer = FlashWriter {
+                flash: &mut *flash,
+                offset: offset + SEQUENCE_SIZE as u32,
+            };
+            if bincode::encode_into_writer(record, writer, bincode_config()).is_err()
+                || flash
+                    .blocking_write(offset, &sequence.to_le_bytes())
+                    .is_err()
+            {
+                warn!("Writing a record to flash failed!");
+            }
+        })
+    }
+
+    /// How much of the rated program/erase cycles of the slots is used up, 0 when new and 1 when worn out
+    pub fn wear(&self) -> f32 {
+        let saves = shared()
+            .lock(|flash| self.newest(&mut flash.borrow_mut()))
+            .map_or(0, |(_, sequence)| sequence + 1);
+        saves as f32 / self.slot_count() as f32 / ERASE_CYCLES as f32
+    }
+
+    /// The slot with the highest sequence number, and that number
+    fn newest(&self, flash: &mut BlockingFlash) -> Option<(usize, u32)> {
+        let mut newest = None;
+        for slot in 0..self.slot_count() {
+            let mut sequence = [0u8; SEQUENCE_SIZE];
+            if flash
+                .blocking_read(self.slot_offset(slot), &mut sequence)
+                .is_err()
+            {
+                continue;
+            }
+            let sequence = u32::from_le_bytes(sequence);
+            if sequence != ERASED_SEQUENCE
+                && newest.is_none_or(|(_, newest_sequence)| sequence > newest_sequence)
+            {
+                newest = Some((slot, sequence));
+            }
+        }
+        newest
+    }
+
+    fn slot_count(&self) -> usize {
+        (self.end - self.start) as usize / self.slot_size
+    }
+
+    fn slot_offset(&self, slot: usize) -> u32 {
+        self.start + (slot * self.slot_size) as u32
+    }
+}
+
+fn bincode_config() -> impl bincode::config::Config {
+    // Fixed size integers, so the largest encoded size of a record is easy to tell
+    bincode::config::standard().with_fixed_int_encoding()
+}
+
+struct FlashWriter<'a> {
+    flash: &'a mut BlockingFlash,
+    offset: u32,
+}
+
+impl Writer for FlashWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.flash
+            .blocking_write(self.offset, bytes)
+            .map_err(|_| EncodeError::Other("flash write failed"))?;
+        self.offset += bytes.len() as u32;
+        Ok(())
+    }
+}
+
+struct FlashReader<'a> {
+    flash: &'a mut BlockingFlash,
+    offset: u32,
+}
+
+impl Reader for FlashReader<'_> {
+    fn read(&mut self, bytes: &mut [u8]) -> Result<(), DecodeError> {
+        self.flash
+            .blocking_read(self.offset, bytes)
+            .map_err(|_| DecodeError::Other("flash read failed"))?;
+        self.offset += bytes.len() as u32;
+        Ok(())
+    }
+}
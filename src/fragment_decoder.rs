@@ -0,0 +1,280 @@
+use defmt::{info, warn};
+use powermeter_payload::MAX_PAYLOAD_SIZE;
+
+use crate::firmware::{self, FlashError};
+
+// The largest fragment that fits a downlink, after the DataFragment header (command and index)
+pub const MAX_FRAGMENT_SIZE: usize = MAX_PAYLOAD_SIZE - 3;
+// Fragment numbers have 14 bits
+pub const MAX_FRAGMENTS: usize = 1 << 14;
+// How many lost fragments can be recovered. Each one takes a row of the matrix, with a fragment's worth of data.
+const MAX_MISSING: usize = 64;
+const ROW_WORDS: usize = MAX_MISSING / 32;
+
+/// Rebuilds a data block from its fragments, writing them to the DFU partition as they arrive. Lost fragments are
+/// recovered from the coded ones following them, as defined for fragmentation matrix 0 of the LoRaWAN Fragmented Data
+/// Block Transport specification (TS004).
+/// Once the first coded fragment arrives, the fragments missing at that point are the unknowns of a system of linear
+/// equations over GF(2). Every coded fragment, minus the known fragments it contains, is one equation. The equations
+/// are kept in echelon form, indexed by their first unknown, and solved once there are as many as unknowns.
+pub struct FragmentDecoder {
+    fragment_count: usize,
+    fragment_size: usize,
+    received: [u32; MAX_FRAGMENTS / 32], // The uncoded fragments written to flash
+    uncoded_received: usize,
+    total_received: usize, // Coded fragments included, for the status answer
+    missing: [u16; MAX_MISSING], // Sorted, fixed when the first coded fragment arrives
+    missing_count: usize,
+    decoding: bool,
+    too_many_missing: bool,
+    rows: [[u32; ROW_WORDS]; MAX_MISSING],
+    row_data: [[u8; MAX_FRAGMENT_SIZE]; MAX_MISSING],
+    occupied: [bool; MAX_MISSING],
+    rank: usize,
+    complete: bool,
+    line: [u32; MAX_FRAGMENTS / 32], // Scratch space for a line of the parity matrix
+}
+
+impl FragmentDecoder {
+    pub const fn new() -> Self {
+        Self {
+            fragment_count: 0,
+            fragment_size: 0,
+            received: [0; MAX_FRAGMENTS / 32],
+            uncoded_received: 0,
+            total_received: 0,
+            missing: [0; MAX_MISSING],
+            missing_count: 0,
+            decoding: false,
+            too_many_missing: false,
+            rows: [[0; ROW_WORDS]; MAX_MISSING],
+            row_data: [[0; MAX_FRAGMENT_SIZE]; MAX_MISSING],
+            occupied: [false; MAX_MISSING],
+            rank: 0,
+            complete: false,
+            line: [0; MAX_FRAGMENTS / 32],
+        }
+    }
+
+    /// Starts over for a data block of the given number and size of fragments
+    pub fn reset(&mut self, fragment_count: usize, fragment_size: usize) {
+        self.fragment_count = fragment_count;
+        self.fragment_size = fragment_size;
+        self.received.fill(0);
+        self.uncoded_received = 0;
+        self.total_received = 0;
+        self.missing_count = 0;
+        self.decoding = false;
+        self.too_many_missing = false;
+        self.occupied.fill(false);
+        self.rank = 0;
+        self.complete = false;
+    }
+
+    pub fn received(&self) -> usize {
+        self.total_received
+    }
+
+    /// The number of fragments that still have to arrive before the data block is complete
+    pub fn missing(&self) -> usize {
+        if self.complete {
+            0
+        } else if self.decoding && !self.too_many_missing {
+            self.missing_count - self.rank
+        } else {
+            self.fragment_count - self.uncoded_received
+        }
+    }
+
+    /// Whether more fragments were lost than can be recovered. The data block only completes if all of them are sent
+    /// again uncoded.
+    pub fn too_many_missing(&self) -> bool {
+        self.too_many_missing
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Adds fragment `number` (counted from 1, coded ones after the uncoded ones)
+    pub fn add(&mut self, number: usize, data: &[u8]) -> Result<(), FlashError> {
+        if self.complete || number == 0 || data.len() != self.fragment_size {
+            return Ok(());
+        }
+        self.total_received += 1;
+        let index = number - 1;
+        if index < self.fragment_count {
+            self.add_uncoded(index, data)
+        } else {
+            self.add_coded(number - self.fragment_count, data)
+        }
+    }
+
+    fn add_uncoded(&mut self, index: usize, data: &[u8]) -> Result<(), FlashError> {
+        if is_set(&self.received, index) {
+            return Ok(());
+        }
+        firmware::write(index * self.fragment_size, data)?;
+        set(&mut self.received, index);
+        self.uncoded_received += 1;
+        if self.uncoded_received == self.fragment_count {
+            self.complete = true;
+            return Ok(());
+        }
+        // A fragment that was missing when decoding started is an equation with a single unknown
+        if self.decoding && !self.too_many_missing {
+            if let Some(position) = self.missing_position(index) {
+                let mut row = [0; ROW_WORDS];
+                set(&mut row, position);
+                let mut row_data = [0; MAX_FRAGMENT_SIZE];
+                row_data[..data.len()].copy_from_slice(data);
+                self.insert(row, row_data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the coded fragment with the given index (counted from 1 after the uncoded ones)
+    fn add_coded(&mut self, coded_index: usize, data: &[u8]) -> Result<(), FlashError> {
+        if !self.decoding {
+            self.start_decoding();
+        }
+        if self.too_many_missing {
+            return Ok(());
+        }
+
+        self.parity_line(coded_index);
+        let mut row = [0; ROW_WORDS];
+        let mut row_data = [0; MAX_FRAGMENT_SIZE];
+        row_data[..data.len()].copy_from_slice(data);
+        let mut known = [0; MAX_FRAGMENT_SIZE];
+        for index in 0..self.fragment_count {
+            if !is_set(&self.line, index) {
+                continue;
+            }
+            match self.missing_position(index) {
+                Some(position) => set(&mut row, position),
+                // Known fragments are taken out of the equation
+                None => {
+                    firmware::read(index * self.fragment_size, &mut known[..data.len()])?;
+                    xor(&mut row_data, &known);
+                }
+            }
+        }
+        self.insert(row, row_data)
+    }
+
+    fn start_decoding(&mut self) {
+        self.decoding = true;
+        for index in 0..self.fragment_count {
+            if is_set(&self.received, index) {
+                continue;
+            }
+            if self.missing_count == MAX_MISSING {
+                warn!("Too many fragments lost to recover them");
+                self.too_many_missing = true;
+                return;
+            }
+            self.missing[self.missing_count] = index as u16;
+            self.missing_count += 1;
+        }
+        info!("Recovering {:?} lost fragments", self.missing_count);
+    }
+
+    /// The unknown the fragment with the given index is, if it was missing when decoding started
+    fn missing_position(&self, index: usize) -> Option<usize> {
+        self.missing[..self.missing_count]
+            .binary_search(&(index as u16))
+            .ok()
+    }
+
+    /// Reduces the equation by the ones known so far and keeps it if it is not redundant
+    fn insert(
+        &mut self,
+        mut row: [u32; ROW_WORDS],
+        mut row_data: [u8; MAX_FRAGMENT_SIZE],
+    ) -> Result<(), FlashError> {
+        while let Some(pivot) = first_set(&row) {
+            if !self.occupied[pivot] {
+                self.rows[pivot] = row;
+                self.row_data[pivot] = row_data;
+                self.occupied[pivot] = true;
+                self.rank += 1;
+                if self.rank == self.missing_count {
+                    self.solve()?;
+                }
+                return Ok(());
+            }
+            xor_words(&mut row, &self.rows[pivot]);
+            xor(&mut row_data, &self.row_data[pivot]);
+        }
+        Ok(())
+    }
+
+    /// Back substitution, starting with the last unknown. Every row then contains a single one, the missing fragment.
+    fn solve(&mut self) -> Result<(), FlashError> {
+        for pivot in (0..self.missing_count).rev() {
+            let (rows, solved_rows) = self.rows.split_at_mut(pivot + 1);
+            let (data, solved_data) = self.row_data.split_at_mut(pivot + 1);
+            for unknown in pivot + 1..self.missing_count {
+                if is_set(&rows[pivot], unknown) {
+                    xor_words(&mut rows[pivot], &solved_rows[unknown - pivot - 1]);
+                    xor(&mut data[pivot], &solved_data[unknown - pivot - 1]);
+                }
+            }
+            let index = self.missing[pivot] as usize;
+            firmware::write(
+                index * self.fragment_size,
+                &data[pivot][..self.fragment_size],
+            )?;
+            set(&mut self.received, index);
+        }
+        info!("Recovered {:?} lost fragments", self.missing_count);
+        self.complete = true;
+        Ok(())
+    }
+
+    /// Which uncoded fragments the coded fragment with the given index (counted from 1) is the sum of
+    fn parity_line(&mut self, coded_index: usize) {
+        let m = self.fragment_count;
+        self.line[..m.div_ceil(32)].fill(0);
+        let modulus = if m.is_power_of_two() { m + 1 } else { m };
+        let mut x = 1 + 1001 * coded_index as u32;
+        for _ in 0..m / 2 {
+            let mut r = 1 << 16;
+            while r >= m {
+                x = prbs23(x);
+                r = x as usize % modulus;
+            }
+            set(&mut self.line, r);
+        }
+    }
+}
+
+// The pseudo random sequence the parity matrix is built from
+fn prbs23(x: u32) -> u32 {
+    let feedback = (x & 1) ^ ((x >> 5) & 1);
+    (x >> 1) | (feedback << 22)
+}
+
+fn is_set(bits: &[u32], index: usize) -> bool {
+    bits[index / 32] & (1 << (index % 32)) != 0
+}
+
+fn set(bits: &mut [u32], index: usize) {
+    bits[index / 32] |= 1 << (index % 32);
+}
+
+fn first_set(bits: &[u32]) -> Option<usize> {
+    bits.iter()
+        .position(|&word| word != 0)
+        .map(|word| word * 32 + bits[word].trailing_zeros() as usize)
+}
+
+fn xor_words(target: &mut [u32], source: &[u32]) {
+    target.iter_mut().zip(source).for_each(|(t, s)| *t ^= s);
+}
+
+fn xor(target: &mut [u8], source: &[u8]) {
+    target.iter_mut().zip(source).for_each(|(t, s)| *t ^= s);
+}
@@ -0,0 +1,293 @@
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use lorawan_device::async_device::{radio, Device, SendResponse, Timings};
+use lorawan_device::{CryptoFactory, RngCore};
+use powermeter_payload::{
+    FragSessionSetupStatus, FragmentationDownlink, FragmentationUplink, FRAGMENTATION_FPORT,
+    MULTICAST_FPORT,
+};
+
+use crate::commands;
+use crate::config::Config;
+use crate::firmware::{self, SIGNATURE_SIZE};
+use crate::fragment_decoder::{FragmentDecoder, MAX_FRAGMENTS, MAX_FRAGMENT_SIZE};
+use crate::multicast::{ClassCSession, Multicast};
+use crate::policy::{self, Delivery};
+use crate::{lorawan_region, mac};
+
+// The decoder takes about 20 kB, more than we want to have in the main task's arena
+static DECODER: Mutex<ThreadModeRawMutex, RefCell<FragmentDecoder>> =
+    Mutex::new(RefCell::new(FragmentDecoder::new()));
+
+struct FragSession {
+    index: u8,
+    fragment_count: usize,
+    fragment_size: usize,
+    padding: usize,
+    erased: bool, // The DFU partition is ready for the fragments
+}
+
+/// Receives firmware updates over the air, using the LoRaWAN Remote Multicast Setup and Fragmented Data Block
+/// Transport packages. The data block is the new image followed by its signature, it is written to the DFU partition
+/// and swapped in by the bootloader after a restart. The message format is defined in the powermeter-payload crate.
+#[derive(Default)]
+pub struct Fuota {
+    multicast: Multicast,
+    session: Option<FragSession>,
+    answers: [Option<FragmentationUplink>; 4],
+    update_ready: bool,
+}
+
+impl Fuota {
+    /// Handles a downlink received on the multicast setup or fragmentation FPort. Answers are sent with the next
+    /// [`Self::run`].
+    pub fn handle_downlink(&mut self, fport: u8, payload: &[u8]) {
+        if fport == MULTICAST_FPORT {
+            self.multicast.handle_downlink(payload);
+            return;
+        }
+        for message in FragmentationDownlink::decode(payload) {
+            let answer = match message {
+                Ok(FragmentationDownlink::PackageVersionReq) => {
+                    Some(FragmentationUplink::PackageVersionAns)
+                }
+                Ok(FragmentationDownlink::FragSessionSetupReq {
+                    session_index,
+                    fragment_count,
+                    fragment_size,
+                    fragmentation_matrix,
+                    padding,
+                    ..
+                }) => Some(self.setup_session(
+                    session_index,
+                    fragment_count as usize,
+                    fragment_size as usize,
+                    fragmentation_matrix,
+                    padding as usize,
+                )),
+                Ok(FragmentationDownlink::FragSessionStatusReq {
+                    session_index,
+                    participants_only,
+                }) => self.session_status(session_index, participants_only),
+                Ok(FragmentationDownlink::FragSessionDeleteReq { session_index }) => {
+                    let exists = self
+                        .session
+                        .as_ref()
+                        .is_some_and(|session| session.index == session_index);
+                    if exists {
+                        self.session = None;
+                    }
+                    Some(FragmentationUplink::FragSessionDeleteAns {
+                        session_index,
+                        session_does_not_exist: !exists,
+                    })
+                }
+                Ok(FragmentationDownlink::DataFragment {
+                    session_index,
+                    number,
+                    data,
+                }) => {
+                    self.add_fragment(session_index, number as usize, data);
+                    None
+                }
+                Err(e) => {
+                    warn!("Invalid fragmentation downlink: {:?}", e);
+                    None
+                }
+            };
+            if let Some(answer) = answer {
+                match self.answers.iter_mut().find(|answer| answer.is_none()) {
+                    Some(slot) => *slot = Some(answer),
+                    None => warn!("Too many fragmentation requests, dropping the answer."),
+                }
+            }
+        }
+    }
+
+    fn setup_session(
+        &mut self,
+        index: u8,
+        fragment_count: usize,
+        fragment_size: usize,
+        fragmentation_matrix: u8,
+        padding: usize,
+    ) -> FragmentationUplink {
+        let block_size = fragment_count * fragment_size;
+        let status = FragSessionSetupStatus {
+            encoding_unsupported: fragmentation_matrix != 0,
+            not_enough_memory: fragment_count >= MAX_FRAGMENTS
+                || fragment_size > MAX_FRAGMENT_SIZE
+                || block_size > firmware::capacity()
+                || block_size < padding + SIGNATURE_SIZE,
+            // A single session at a time
+            index_unsupported: index != 0,
+            wrong_descriptor: false,
+        };
+        if status.is_ok() {
+            info!(
+                "Fragmentation session for {:?} fragments of {:?} bytes",
+                fragment_count, fragment_size
+            );
+            DECODER.lock(|decoder| decoder.borrow_mut().reset(fragment_count, fragment_size));
+            self.session = Some(FragSession {
+                index,
+                fragment_count,
+                fragment_size,
+                padding,
+                erased: false,
+            });
+        }
+        FragmentationUplink::FragSessionSetupAns {
+            session_index: index,
+            status,
+        }
+    }
+
+    fn session_status(&self, index: u8, participants_only: bool) -> Option<FragmentationUplink> {
+        // Without a session, there is nothing to answer (the specification leaves this open)
+        let session = self
+            .session
+            .as_ref()
+            .filter(|session| session.index == index)?;
+        let (received, missing, too_many_missing) = DECODER.lock(|decoder| {
+            let decoder = decoder.borrow();
+            (
+                decoder.received(),
+                decoder.missing(),
+                decoder.too_many_missing(),
+            )
+        });
+        if participants_only && missing == 0 {
+            return None;
+        }
+        Some(FragmentationUplink::FragSessionStatusAns {
+            session_index: session.index,
+            fragments_received: received.min(MAX_FRAGMENTS - 1) as u16,
+            fragments_missing: missing.min(u8::MAX as usize) as u8,
+            not_enough_matrix_memory: too_many_missing,
+        })
+    }
+
+    fn add_fragment(&mut self, index: u8, number: usize, data: &[u8]) {
+        let Some(session) = self
+            .session
+            .as_ref()
+            .filter(|session| session.index == index)
+        else {
+            return;
+        };
+        if !session.erased {
+            warn!(
+                "Fragment {:?} arrived before the DFU partition was ready.",
+                number
+            );
+            return;
+        }
+        let complete = DECODER.lock(|decoder| {
+            let mut decoder = decoder.borrow_mut();
+            if let Err(e) = decoder.add(number, data) {
+                warn!("Storing fragment {:?} failed: {:?}", number, e);
+            }
+            decoder.is_complete()
+        });
+        if !complete || self.update_ready {
+            return;
+        }
+
+        let image_size =
+            session.fragment_count * session.fragment_size - session.padding - SIGNATURE_SIZE;
+        info!("Firmware update received, checking {:?} bytes", image_size);
+        match firmware::verify_and_mark_updated(image_size) {
+            Ok(()) => {
+                info!("Firmware update verified, it is applied with the next restart.");
+                self.update_ready = true;
+            }
+            Err(e) => warn!("Firmware update rejected: {:?}", e),
+        }
+        // Any further fragments belong to a new session
+        self.session = None;
+    }
+
+    /// Whether a verified update is waiting for the device to restart
+    pub fn update_ready(&self) -> bool {
+        self.update_ready
+    }
+
+    /// The multicast session that starts within the given time or is still running, if any
+    pub fn session_within(&mut self, duration: Duration) -> Option<ClassCSession> {
+        self.multicast.session_within(duration)
+    }
+
+    /// Checks and decrypts a frame received during a multicast session, see [`Multicast::receive`]
+    pub fn receive_multicast(
+        &mut self,
+        group_id: u8,
+        frame: &mut [u8],
+        payload: &mut [u8],
+    ) -> Option<(u8, usize)> {
+        self.multicast.receive(group_id, frame, payload)
+    }
+
+    /// Prepares what the last requests set up and sends the answers to them
+    pub async fn run<R, C, T, G>(&mut self, device: &mut Device<R, C, T, G>, config: &Config)
+    where
+        R: radio::PhyRxTx + Timings,
+        T: radio::Timer,
+        C: CryptoFactory + Default,
+        G: RngCore,
+    {
+        if let Some(session) = self.session.as_mut().filter(|session| !session.erased) {
+            match firmware::erase(session.fragment_count * session.fragment_size).await {
+                Ok(()) => session.erased = true,
+                Err(e) => {
+                    warn!("Erasing the DFU partition failed: {:?}", e);
+                    self.session = None;
+                }
+            }
+        }
+
+        let mut buf = [0u8; 64];
+        let size = self.multicast.encode_answers(&mut buf);
+        self.send_answers(device, config, MULTICAST_FPORT, &buf[..size])
+            .await;
+        let mut size = 0;
+        for answer in self.answers.iter_mut().filter_map(Option::take) {
+            size += answer.encode(&mut buf[size..]).unwrap();
+        }
+        self.send_answers(device, config, FRAGMENTATION_FPORT, &buf[..size])
+            .await;
+    }
+
+    async fn send_answers<R, C, T, G>(
+        &mut self,
+        device: &mut Device<R, C, T, G>,
+        config: &Config,
+        fport: u8,
+        payload: &[u8],
+    ) where
+        R: radio::PhyRxTx + Timings,
+        T: radio::Timer,
+        C: CryptoFactory + Default,
+        G: RngCore,
+    {
+        if payload.is_empty() {
+            return;
+        }
+        if payload.len() + mac::pending_fopts_len()
+            > lorawan_region::max_payload(device.get_datarate())
+        {
+            warn!("FUOTA answers do not fit the current data rate.");
+            return;
+        }
+        info!("Sending FUOTA answers on FPort {:?}", fport);
+        let resp = policy::send(device, payload, fport, Delivery::Unconfirmed, config).await;
+        // The network usually sends the next request right away
+        if let Some(SendResponse::DownlinkReceived(_)) = resp {
+            commands::receive(device);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+use portable_atomic::AtomicU32;
+
+use crate::{mac, policy};
+
+// How often the network is asked whether it still hears us. The answer is a downlink, which TTN limits to 10 per day.
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// How soon an unanswered link check is repeated
+const UNANSWERED_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// After this many confirmed uplinks in a row were not acknowledged, the network probably forgot our session
+#[cfg(not(feature = "abp"))]
+const REJOIN_AFTER_NO_ACKS: u32 = 12;
+// The same if this many link checks in a row were not answered
+#[cfg(not(feature = "abp"))]
+const REJOIN_AFTER_UNANSWERED_CHECKS: u8 = 4;
+
+// The margin (lower byte) and gateway count (second byte) of the last link check answer. Bit 16 marks it as valid.
+const ANSWER_VALID: u32 = 1 << 16;
+static LAST_ANSWER: AtomicU32 = AtomicU32::new(0);
+
+/// The result of the last answered link check
+pub struct LinkCheckAnswer {
+    pub margin_db: u8,
+    pub gateway_count: u8,
+}
+
+pub fn last_answer() -> Option<LinkCheckAnswer> {
+    let answer = LAST_ANSWER.load(Ordering::Relaxed);
+    if answer & ANSWER_VALID == 0 {
+        return None;
+    }
+    Some(LinkCheckAnswer {
+        margin_db: answer as u8,
+        gateway_count: (answer >> 8) as u8,
+    })
+}
+
+/// Asks the network for a LinkCheckAns from time to time and decides when the link is lost for long enough to join
+/// again. The session expiring is not the only way to lose it: the network server may forget the device, or the
+/// device may have been moved out of reach of the gateways that know it.
+#[derive(Default)]
+pub struct LinkMonitor {
+    last_check: Option<Instant>,
+    check_pending: bool, // A LinkCheckReq went out with the last uplink
+    unanswered_checks: u8,
+}
+
+impl LinkMonitor {
+    /// Adds a LinkCheckReq to the next uplink if one is due
+    pub fn before_uplink(&mut self) {
+        let interval = match self.unanswered_checks {
+            0 => LINK_CHECK_INTERVAL,
+            _ => UNANSWERED_RETRY_INTERVAL,
+        };
+        if self
+            .last_check
+            .is_some_and(|last_check| last_check.elapsed() < interval)
+        {
+            return;
+        }
+        info!("Requesting a link check");
+        mac::request_link_check();
+        self.last_check = Some(Instant::now());
+        self.check_pending = true;
+    }
+
+    /// Picks up the answer to a LinkCheckReq sent with the last uplink
+    pub fn after_uplink(&mut self) {
+        // Not sent yet if the uplink was not, e.g. because the airtime budget is used up
+        if !self.check_pending || mac::link_check_queued() {
+            return;
+        }
+        self.check_pending = false;
+        match mac::take_link_check_answer() {
+            Some(answer) => {
+                info!(
+                    "Link check: margin {:?} dB, {:?} gateways",
+                    answer.margin_db, answer.gateway_count
+                );
+                LAST_ANSWER.store(
+                    ANSWER_VALID | (answer.gateway_count as u32) << 8 | answer.margin_db as u32,
+                    Ordering::Relaxed,
+                );
+                self.unanswered_checks = 0;
+            }
+            None => {
+                self.unanswered_checks = self.unanswered_checks.saturating_add(1);
+                warn!(
+                    "Link check not answered ({:?} in a row).",
+                    self.unanswered_checks
+                );
+            }
+        }
+    }
+
+    /// Whether the link has been lost for long enough that the device should join again
+    #[cfg(not(feature = "abp"))]
+    pub fn rejoin_due(&self) -> bool {
+        policy::link_quality().consecutive_no_acks >= REJOIN_AFTER_NO_ACKS
+            || self.unanswered_checks >= REJOIN_AFTER_UNANSWERED_CHECKS
+    }
+
+    /// The device has joined again, the link is checked from scratch
+    pub fn rejoined(&mut self) {
+        policy::reset_consecutive_no_acks();
+        *self = Self::default();
+    }
+}
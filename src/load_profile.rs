@@ -0,0 +1,333 @@
+use bincode::{Decode, Encode};
+use defmt::{info, warn};
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_time::Duration;
+use lorawan_device::async_device::{radio, Device, SendResponse, Timings};
+use lorawan_device::{CryptoFactory, RngCore};
+use powermeter_payload::{
+    Bucket, BucketFlags, LoadProfile, Reading, Uplink, MAX_PAYLOAD_SIZE, UPLINK_FPORT,
+};
+
+use crate::commands;
+use crate::config::Config;
+use crate::flash_region::FlashRegion;
+use crate::policy::{self, Delivery};
+use crate::{clock, lorawan_region, mac, S0_CHANNEL_COUNT};
+
+// The length of the intervals the energy is recorded for
+const INTERVAL: Duration = Duration::from_secs(15 * 60);
+// A measurement taken this close to the start of an interval counts as taken at the start
+const BOUNDARY_TOLERANCE: Duration = Duration::from_secs(60);
+// After a longer gap between measurements, the intervals in between are not estimated but left out
+const MAX_GAP: Duration = Duration::from_secs(24 * 60 * 60);
+// A day of intervals, plus some room for the previous day while it is being sent
+const CAPACITY: usize = 96 + 24;
+// How many load profile messages are sent per measurement cycle at most, so they don't use up the duty cycle at once
+const MAX_MESSAGES_PER_CYCLE: usize = 4;
+const DAY: u32 = 24 * 60 * 60;
+
+// The meter's import and export, then the S0 counters
+const CHANNEL_COUNT: usize = 2 + S0_CHANNEL_COUNT;
+
+// The recorder is saved to the slots of its own flash region (see memory.x)
+const SLOT_SIZE: usize = 2 * ERASE_SIZE;
+// Encoded with fixed size integers, the largest the recorder gets: the two snapshots, the flags, the intervals and
+// their count
+const SNAPSHOT_SIZE: usize = 1 + 4 + CHANNEL_COUNT * (1 + 8);
+const BUCKET_SIZE: usize = 4 + 3 + CHANNEL_COUNT * (1 + 4);
+const MAX_ENCODED_SIZE: usize = 2 * SNAPSHOT_SIZE + 3 + CAPACITY * BUCKET_SIZE + 8;
+const _: () = assert!(MAX_ENCODED_SIZE <= FlashRegion::record_capacity(SLOT_SIZE));
+
+extern "C" {
+    static __load_profile_start: u32;
+    static __load_profile_end: u32;
+}
+
+#[derive(Default, Clone, Copy, Encode, Decode)]
+struct Flags {
+    power_fail: bool,
+    clock_adjusted: bool,
+    estimated: bool,
+}
+
+/// The energy values (in Wh) at a point in time
+#[derive(Default, Clone, Copy, Encode, Decode)]
+struct Snapshot {
+    time: u32,
+    values: [Option<u64>; CHANNEL_COUNT],
+}
+
+#[derive(Default, Clone, Copy, Encode, Decode)]
+struct StoredBucket {
+    start: u32,
+    flags: Flags,
+    values: [Option<u32>; CHANNEL_COUNT],
+}
+
+impl StoredBucket {
+    fn bucket(&self) -> Bucket {
+        let mut counter_wh = [None; S0_CHANNEL_COUNT];
+        counter_wh.copy_from_slice(&self.values[2..]);
+        Bucket {
+            flags: BucketFlags {
+                power_fail: self.flags.power_fail,
+                clock_adjusted: self.flags.clock_adjusted,
+                estimated: self.flags.estimated,
+            },
+            meter_import_wh: self.values[0],
+            meter_export_wh: self.values[1],
+            counter_wh,
+        }
+    }
+}
+
+/// Records the energy used per 15 minute interval (aligned to the wall clock), from the measurements. The intervals
+/// of a day are sent after the day is over. It is saved to flash when an interval closes or was sent, not with the
+/// rest of the persistent state every cycle.
+#[derive(Encode, Decode)]
+pub struct LoadProfileRecorder {
+    last: Option<Snapshot>,           // The last measurement
+    interval_start: Option<Snapshot>, // The values at the start of the current interval
+    flags: Flags,                     // What happened during the current interval so far
+    buckets: [StoredBucket; CAPACITY],
+    len: usize,
+}
+
+impl Default for LoadProfileRecorder {
+    fn default() -> Self {
+        Self {
+            last: None,
+            interval_start: None,
+            flags: Flags::default(),
+            buckets: [StoredBucket::default(); CAPACITY],
+            len: 0,
+        }
+    }
+}
+
+impl LoadProfileRecorder {
+    /// Restores the recorder from the newest slot of its flash region, or starts empty
+    pub fn load() -> Self {
+        region().load().unwrap_or_default()
+    }
+
+    fn save(&self) {
+        region().save(self);
+    }
+
+    /// Marks the current interval as incomplete, to be called after a reset
+    pub fn power_failed(&mut self) {
+        self.flags.power_fail = true;
+    }
+
+    /// Closes the intervals that ended since the last measurement, and saves the recorder if one did
+    pub fn record(&mut self, reading: &Reading) {
+        let interval_start = self.interval_start.map(|start| start.time);
+        self.update(reading);
+        if self.interval_start.map(|start| start.time) != interval_start {
+            self.save();
+        }
+    }
+
+    fn update(&mut self, reading: &Reading) {
+        let mut values = [None; CHANNEL_COUNT];
+        values[0] = reading.meter_import_wh;
+        values[1] = reading.meter_export_wh;
+        values[2..].copy_from_slice(&reading.counter_wh);
+        let current = Snapshot {
+            time: reading.timestamp,
+            values,
+        };
+
+        if clock::take_adjusted() {
+            self.flags.clock_adjusted = true;
+        }
+        let Some(last) = self.last.replace(current) else {
+            return;
+        };
+        if current.time <= last.time {
+            // The clock was set back, the interval is started over
+            self.flags.clock_adjusted = true;
+            self.interval_start = None;
+            return;
+        }
+        if current.time - last.time > MAX_GAP.as_secs() as u32 {
+            self.interval_start = None;
+            return;
+        }
+
+        let interval = INTERVAL.as_secs() as u32;
+        let mut boundary = (last.time / interval + 1) * interval;
+        while boundary <= current.time {
+            let (end, estimated) = interpolate(&last, &current, boundary);
+            if let Some(start) = self
+                .interval_start
+                .filter(|start| start.time + interval == boundary)
+            {
+                let mut bucket = StoredBucket {
+                    start: start.time,
+                    flags: self.flags,
+                    values: [None; CHANNEL_COUNT],
+                };
+                bucket.flags.estimated |= estimated;
+                for (channel, value) in bucket.values.iter_mut().enumerate() {
+                    *value = match (start.values[channel], end.values[channel]) {
+                        // If the counter was set back by a command, the energy used is unknown
+                        (Some(start), Some(end)) => {
+                            end.checked_sub(start).and_then(|wh| u32::try_from(wh).ok())
+                        }
+                        _ => None,
+                    };
+                }
+                self.push(bucket);
+            }
+            self.interval_start = Some(end);
+            self.flags = Flags {
+                estimated,
+                ..Default::default()
+            };
+            boundary += interval;
+        }
+    }
+
+    fn push(&mut self, bucket: StoredBucket) {
+        if self.len == CAPACITY {
+            warn!("Load profile full, dropping the oldest interval.");
+            self.buckets.rotate_left(1);
+            self.len -= 1;
+        }
+        self.buckets[self.len] = bucket;
+        self.len += 1;
+    }
+
+    /// Sends the intervals of the days that are over
+    pub async fn send_completed_days<R, C, T, G>(
+        &mut self,
+        device: &mut Device<R, C, T, G>,
+        config: &Config,
+    ) where
+        R: radio::PhyRxTx + Timings,
+        T: radio::Timer,
+        C: CryptoFactory + Default,
+        G: RngCore,
+    {
+        let Some(now) = clock::utc_now() else {
+            return;
+        };
+        let today = now.as_secs() as u32 / DAY * DAY;
+
+        let len = self.len;
+        self.send_messages(device, config, today).await;
+        if self.len != len {
+            self.save();
+        }
+    }
+
+    async fn send_messages<R, C, T, G>(
+        &mut self,
+        device: &mut Device<R, C, T, G>,
+        config: &Config,
+        today: u32,
+    ) where
+        R: radio::PhyRxTx + Timings,
+        T: radio::Timer,
+        C: CryptoFactory + Default,
+        G: RngCore,
+    {
+        for _ in 0..MAX_MESSAGES_PER_CYCLE {
+            // Leaving room for the MAC commands that go along
+            let max_payload = lorawan_region::max_payload(device.get_datarate())
+                .saturating_sub(mac::pending_fopts_len());
+            let Some((message, count)) = self.next_message(today, max_payload) else {
+                return;
+            };
+
+            let mut buf = [0u8; MAX_PAYLOAD_SIZE];
+            let size = Uplink::LoadProfile(message).encode(&mut buf).unwrap();
+            info!("Sending {:?} load profile intervals", count);
+            match policy::send(
+                device,
+                &buf[..size],
+                UPLINK_FPORT,
+                Delivery::Retried,
+                config,
+            )
+            .await
+            {
+                // Kept for the next cycle
+                None | Some(SendResponse::NoAck) => return,
+                Some(resp) => {
+                    info!("Sending okay: {:?}", resp);
+                    self.buckets.copy_within(count..self.len, 0);
+                    self.len -= count;
+                    if let SendResponse::DownlinkReceived(_) = resp {
+                        commands::receive(device);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The oldest consecutive intervals that started before `before`, as many as fit the payload size. Returns the
+    /// message and the number of intervals in it.
+    fn next_message(&self, before: u32, max_payload: usize) -> Option<(LoadProfile, usize)> {
+        let first = self.buckets[..self.len]
+            .first()
+            .filter(|bucket| bucket.start < before)?;
+        let interval = INTERVAL.as_secs() as u32;
+        let mut message = LoadProfile::new(first.start, (interval / 60) as u8);
+        let mut count = 0;
+        for (index, bucket) in self.buckets[..self.len].iter().enumerate() {
+            if bucket.start >= before || bucket.start != first.start + index as u32 * interval {
+                break;
+            }
+            if !message.push(bucket.bucket()) {
+                break;
+            }
+            if message.size() > max_payload {
+                message.pop();
+                break;
+            }
+            count += 1;
+        }
+        if count == 0 {
+            warn!("Data rate too low to send the load profile!");
+            return None;
+        }
+        Some((message, count))
+    }
+}
+
+/// The values at the given time, between two measurements. Also returns whether they had to be interpolated.
+fn interpolate(before: &Snapshot, after: &Snapshot, time: u32) -> (Snapshot, bool) {
+    let tolerance = BOUNDARY_TOLERANCE.as_secs() as u32;
+    if after.time - time <= tolerance {
+        return (Snapshot { time, ..*after }, false);
+    }
+    if time - before.time <= tolerance {
+        return (Snapshot { time, ..*before }, false);
+    }
+
+    let mut result = Snapshot {
+        time,
+        values: [None; CHANNEL_COUNT],
+    };
+    for (channel, value) in result.values.iter_mut().enumerate() {
+        if let (Some(before_value), Some(after_value)) =
+            (before.values[channel], after.values[channel])
+        {
+            // A counter set back by a command can't be interpolated. In 128 bits, as a counter set forward may have
+            // jumped by nearly the whole range. The share of the difference fits 64 bits again.
+            *value = after_value.checked_sub(before_value).map(|difference| {
+                before_value
+                    + (difference as u128 * (time - before.time) as u128
+                        / (after.time - before.time) as u128) as u64
+            });
+        }
+    }
+    (result, true)
+}
+
+fn region() -> FlashRegion {
+    unsafe { FlashRegion::new(&__load_profile_start, &__load_profile_end, SLOT_SIZE) }
+}
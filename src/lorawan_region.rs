@@ -0,0 +1,282 @@
+use lora_modulation::{Bandwidth, BaseBandModulationParams, CodingRate, SpreadingFactor};
+use lorawan_device::async_device::radio::{RfConfig, RxConfig, RxMode};
+use lorawan_device::async_device::region::{self, DR};
+
+// The region is selected using the region-* cargo features. Exactly one of them needs to be enabled.
+#[cfg(not(any(
+    feature = "region-eu868",
+    feature = "region-us915",
+    feature = "region-au915"
+)))]
+compile_error!("No LoRaWAN region selected. Enable one of the region-* features.");
+#[cfg(any(
+    all(feature = "region-eu868", feature = "region-us915"),
+    all(feature = "region-eu868", feature = "region-au915"),
+    all(feature = "region-us915", feature = "region-au915")
+))]
+compile_error!("Multiple LoRaWAN regions selected. Choose one.");
+
+// US915 and AU915 use the sub-band (group of 8 channels) selected with one of the subband-* features. Without one, it
+// is sub-band 2, which TTN and most private networks use.
+#[cfg(all(
+    feature = "region-eu868",
+    any(
+        feature = "subband-1",
+        feature = "subband-2",
+        feature = "subband-3",
+        feature = "subband-4",
+        feature = "subband-5",
+        feature = "subband-6",
+        feature = "subband-7",
+        feature = "subband-8"
+    )
+))]
+compile_error!("The subband-* features only apply to US915 and AU915.");
+const SUB_BAND_FEATURES: [bool; 8] = [
+    cfg!(feature = "subband-1"),
+    cfg!(feature = "subband-2"),
+    cfg!(feature = "subband-3"),
+    cfg!(feature = "subband-4"),
+    cfg!(feature = "subband-5"),
+    cfg!(feature = "subband-6"),
+    cfg!(feature = "subband-7"),
+    cfg!(feature = "subband-8"),
+];
+const _: () = {
+    let mut selected = 0;
+    let mut i = 0;
+    while i < SUB_BAND_FEATURES.len() {
+        selected += SUB_BAND_FEATURES[i] as usize;
+        i += 1;
+    }
+    assert!(selected <= 1, "Multiple sub-bands selected. Choose one.");
+};
+
+#[cfg(any(feature = "region-us915", feature = "region-au915"))]
+const SUB_BAND: region::Subband = if cfg!(feature = "subband-1") {
+    region::Subband::_1
+} else if cfg!(feature = "subband-3") {
+    region::Subband::_3
+} else if cfg!(feature = "subband-4") {
+    region::Subband::_4
+} else if cfg!(feature = "subband-5") {
+    region::Subband::_5
+} else if cfg!(feature = "subband-6") {
+    region::Subband::_6
+} else if cfg!(feature = "subband-7") {
+    region::Subband::_7
+} else if cfg!(feature = "subband-8") {
+    region::Subband::_8
+} else {
+    region::Subband::_2
+};
+
+// The SX1262 can not transmit with more than 22 dBm
+pub const MAX_RADIO_POWER: u8 = 22;
+
+#[cfg(feature = "region-eu868")]
+mod params {
+    use super::*;
+    pub const MAX_EIRP: u8 = 16;
+    // The TXPower indexes a LinkADRReq may set, each one 2 dB below the previous one
+    pub const MAX_TX_POWER_INDEX: u8 = 7;
+    pub const DEFAULT_DATARATE: DR = DR::_0;
+    // Maximum FRMPayload size per data rate, from the LoRaWAN Regional Parameters (RP002-1.0.4)
+    pub const MAX_PAYLOAD: [usize; 8] = [51, 51, 51, 115, 222, 222, 222, 222];
+    // Spreading factor and bandwidth per data rate. DR7 is FSK, which we don't use.
+    pub const MODULATION: [(u8, u32); 7] = [
+        (12, 125_000),
+        (11, 125_000),
+        (10, 125_000),
+        (9, 125_000),
+        (8, 125_000),
+        (7, 125_000),
+        (7, 250_000),
+    ];
+    // The g1 sub-band all default channels are in allows transmitting 1% of the time
+    pub const DUTY_CYCLE_DIVISOR: u32 = 100;
+
+    // Downlinks use the same data rates as uplinks
+    pub const fn downlink_modulation(index: usize) -> Option<(u8, u32)> {
+        if index < MODULATION.len() {
+            Some(MODULATION[index])
+        } else {
+            None
+        }
+    }
+
+    pub fn configuration() -> region::Configuration {
+        region::Configuration::new(region::Region::EU868)
+    }
+}
+
+#[cfg(feature = "region-us915")]
+mod params {
+    use super::*;
+    pub const MAX_EIRP: u8 = 30;
+    pub const MAX_TX_POWER_INDEX: u8 = 14;
+    // DR0 only allows 11 bytes, so we start on the lowest data rate that fits a measurement
+    pub const DEFAULT_DATARATE: DR = DR::_1;
+    pub const MAX_PAYLOAD: [usize; 5] = [11, 53, 125, 242, 242];
+    pub const MODULATION: [(u8, u32); 5] = [
+        (10, 125_000),
+        (9, 125_000),
+        (8, 125_000),
+        (7, 125_000),
+        (8, 500_000),
+    ];
+    // There is no duty cycle limit, the dwell time is limited by the FCC instead
+    pub const DUTY_CYCLE_DIVISOR: u32 = 1;
+
+    // Downlinks use DR8 (SF12) to DR13 (SF7), all at 500 kHz
+    pub const fn downlink_modulation(index: usize) -> Option<(u8, u32)> {
+        match index {
+            8..=13 => Some((20 - index as u8, 500_000)),
+            _ => None,
+        }
+    }
+
+    pub fn configuration() -> region::Configuration {
+        let mut us915 = region::US915::default();
+        us915.set_join_bias(SUB_BAND);
+        us915.into()
+    }
+}
+
+#[cfg(feature = "region-au915")]
+mod params {
+    use super::*;
+    pub const MAX_EIRP: u8 = 30;
+    pub const MAX_TX_POWER_INDEX: u8 = 10;
+    // Without uplink dwell time restrictions, which the network does not announce for AU915 by default. DR0 is
+    // allowed then and fits a measurement like in EU868.
+    pub const DEFAULT_DATARATE: DR = DR::_0;
+    pub const MAX_PAYLOAD: [usize; 7] = [51, 51, 51, 115, 242, 242, 242];
+    pub const MODULATION: [(u8, u32); 7] = [
+        (12, 125_000),
+        (11, 125_000),
+        (10, 125_000),
+        (9, 125_000),
+        (8, 125_000),
+        (7, 125_000),
+        (8, 500_000),
+    ];
+    pub const DUTY_CYCLE_DIVISOR: u32 = 1;
+
+    // Downlinks use DR8 (SF12) to DR13 (SF7), all at 500 kHz
+    pub const fn downlink_modulation(index: usize) -> Option<(u8, u32)> {
+        match index {
+            8..=13 => Some((20 - index as u8, 500_000)),
+            _ => None,
+        }
+    }
+
+    pub fn configuration() -> region::Configuration {
+        let mut au915 = region::AU915::default();
+        au915.set_join_bias(SUB_BAND);
+        au915.into()
+    }
+}
+
+pub use params::{DEFAULT_DATARATE, DUTY_CYCLE_DIVISOR, MAX_EIRP};
+
+// The data rates in ascending order, to step from one to the next
+const DATARATES: [DR; 8] = [
+    DR::_0,
+    DR::_1,
+    DR::_2,
+    DR::_3,
+    DR::_4,
+    DR::_5,
+    DR::_6,
+    DR::_7,
+];
+
+/// The region's configuration. Adaptive data rate is enabled by the MAC layer (see mac.rs): the network server moves
+/// us to the fastest data rate and lowest transmit power its gateways still receive us with.
+pub fn configuration() -> region::Configuration {
+    params::configuration()
+}
+
+/// The conducted transmit power that radiates the given EIRP with an antenna of the given gain, as far as the radio
+/// allows, in dBm
+pub const fn tx_power(eirp_dbm: i8, antenna_gain_dbi: u8) -> i8 {
+    let power = eirp_dbm - antenna_gain_dbi as i8;
+    if power > MAX_RADIO_POWER as i8 {
+        MAX_RADIO_POWER as i8
+    } else {
+        power
+    }
+}
+
+/// The EIRP for the TXPower index of a LinkADRReq, 2 dB below the region's maximum for each step. `None` if the index
+/// is not defined in this region.
+pub fn adr_eirp(index: u8) -> Option<i8> {
+    if index > params::MAX_TX_POWER_INDEX {
+        return None;
+    }
+    Some(MAX_EIRP as i8 - 2 * index as i8)
+}
+
+/// The largest application payload that can be sent at the given data rate
+pub const fn max_payload(datarate: DR) -> usize {
+    let index = datarate as usize;
+    if index < params::MAX_PAYLOAD.len() {
+        params::MAX_PAYLOAD[index]
+    } else {
+        // Not an uplink data rate in this region
+        0
+    }
+}
+
+/// The spreading factor and bandwidth (in Hz) of the given data rate. Falls back to the slowest one for data rates that
+/// are not used for uplinks in this region.
+pub const fn modulation(datarate: DR) -> (u8, u32) {
+    let index = datarate as usize;
+    if index < params::MODULATION.len() {
+        params::MODULATION[index]
+    } else {
+        params::MODULATION[0]
+    }
+}
+
+/// The next slower data rate, but not slower than the default one (the slowest that fits our messages)
+pub fn slower_datarate(datarate: DR) -> DR {
+    let index = datarate as usize;
+    if index <= DEFAULT_DATARATE as usize || index >= DATARATES.len() {
+        DEFAULT_DATARATE
+    } else {
+        DATARATES[index - 1]
+    }
+}
+
+/// The data rate with the given index, as the network sends it in MAC and package commands
+pub fn datarate(index: u8) -> Option<DR> {
+    DATARATES.get(index as usize).copied()
+}
+
+/// The continuous receive configuration for downlinks on the given frequency and data rate index, e.g. those of a
+/// multicast session. `None` if the data rate is not defined for downlinks in this region.
+pub fn downlink_rx_config(frequency_hz: u32, datarate: u8) -> Option<RxConfig> {
+    let (spreading_factor, bandwidth) = params::downlink_modulation(datarate as usize)?;
+    let spreading_factor = match spreading_factor {
+        7 => SpreadingFactor::_7,
+        8 => SpreadingFactor::_8,
+        9 => SpreadingFactor::_9,
+        10 => SpreadingFactor::_10,
+        11 => SpreadingFactor::_11,
+        _ => SpreadingFactor::_12,
+    };
+    let bandwidth = match bandwidth {
+        500_000 => Bandwidth::_500KHz,
+        250_000 => Bandwidth::_250KHz,
+        _ => Bandwidth::_125KHz,
+    };
+    Some(RxConfig {
+        rf: RfConfig {
+            frequency: frequency_hz,
+            bb: BaseBandModulationParams::new(spreading_factor, bandwidth, CodingRate::_4_5),
+        },
+        mode: RxMode::Continuous,
+    })
+}
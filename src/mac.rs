@@ -0,0 +1,457 @@
+use core::cell::RefCell;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use lorawan::keys::{CryptoFactory, Mac, AES128};
+use lorawan::maccommands::{parse_downlink_mac_commands, DownlinkMacCommand};
+use lorawan::parser::{self, DataHeader, DataPayload, FRMPayload, PhyPayload};
+use lorawan_device::async_device::radio::{PhyRxTx, RxConfig, RxQuality, RxStatus, TxConfig};
+use lorawan_device::async_device::region::DR;
+use lorawan_device::async_device::{Session, Timings};
+use lorawan_device::default_crypto::DefaultFactory;
+
+use crate::config::ANTENNA_GAIN_DBI;
+use crate::link_check::LinkCheckAnswer;
+use crate::{lorawan_region, network};
+
+// The largest LoRaWAN frame
+const MAX_FRAME_SIZE: usize = 256;
+// The MAC header, DevAddr, FCtrl and FCnt, which the FOpts follow
+const FHDR_SIZE: usize = 8;
+const MAX_FOPTS_SIZE: usize = 15;
+const MIC_SIZE: usize = 4;
+const FCTRL_INDEX: usize = 5;
+const FOPTS_LEN_MASK: u8 = 0x0F;
+// Asks the network server to manage our data rate and transmit power (adaptive data rate)
+const FCTRL_ADR: u8 = 0x80;
+// Asks the network server to answer, so we know ADR did not leave us with a data rate the gateways can't hear
+const FCTRL_ADR_ACK_REQ: u8 = 0x40;
+const FCNT_INDEX: usize = 6;
+// The message types of uplinks (unconfirmed and confirmed), in the upper bits of the MAC header
+const MTYPE_MASK: u8 = 0xE0;
+const UNCONFIRMED_DATA_UP: u8 = 0x40;
+const CONFIRMED_DATA_UP: u8 = 0x80;
+// MAC command identifiers
+const LINK_CHECK_REQ: u8 = 0x02;
+const DEV_STATUS_ANS: u8 = 0x06;
+const DEV_STATUS_ANS_SIZE: usize = 3;
+// The battery level of a DevStatusAns when it could not be measured
+pub const BATTERY_LEVEL_UNKNOWN: u8 = 255;
+// The margin of a DevStatusAns is a signed 6-bit value
+const MARGIN_RANGE: core::ops::RangeInclusive<i8> = -32..=31;
+const MARGIN_MASK: u8 = 0x3F;
+// A LinkADRReq leaves the data rate or transmit power as it is with this value
+const ADR_UNCHANGED: u8 = 0x0F;
+// After this many uplinks without a downlink the ADRACKReq bit is set, and after every further ADR_ACK_DELAY ones the
+// transmit power and then the data rate is backed off (LoRaWAN 1.0.4 §4.3.1.1)
+const ADR_ACK_LIMIT: u32 = 64;
+const ADR_ACK_DELAY: u32 = 32;
+
+/// The keys and frame counters of the session the next uplink is sent in
+struct SessionKeys {
+    nwkskey: AES128,
+    appskey: AES128,
+    devaddr: [u8; 4],
+    fcnt_up: u32,
+    fcnt_down: u32,
+}
+
+/// What the MAC commands lorawan-device does not handle itself need to be kept between an uplink and its downlink
+struct State {
+    session: Option<SessionKeys>,
+    link_check_queued: bool,
+    link_check_answer: Option<LinkCheckAnswer>,
+    // The margin of the DevStatusReq to answer with the next uplink
+    dev_status_margin: Option<u8>,
+    battery_level: u8, // As a DevStatusAns reports it
+    // The data rate the last LinkADRReq asked for, until it is applied to the device
+    adr_datarate: Option<DR>,
+    // The EIRP the last LinkADRReq asked for, lorawan-device only knows the maximum one
+    adr_eirp_dbm: Option<i8>,
+    // The uplinks since the last downlink of the session, and whether the data rate is to be lowered because of them
+    adr_ack_cnt: u32,
+    adr_backoff: bool,
+    // The limits of this installation (see config.rs)
+    max_eirp_dbm: u8,
+    antenna_gain_dbi: u8,
+    tx_power_dbm: i8,              // Of the last uplink
+    rx_quality: Option<RxQuality>, // Of the last downlink
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    session: None,
+    link_check_queued: false,
+    link_check_answer: None,
+    dev_status_margin: None,
+    battery_level: BATTERY_LEVEL_UNKNOWN,
+    adr_datarate: None,
+    adr_eirp_dbm: None,
+    adr_ack_cnt: 0,
+    adr_backoff: false,
+    max_eirp_dbm: lorawan_region::MAX_EIRP,
+    antenna_gain_dbi: ANTENNA_GAIN_DBI,
+    tx_power_dbm: lorawan_region::tx_power(lorawan_region::MAX_EIRP as i8, ANTENNA_GAIN_DBI),
+    rx_quality: None,
+}));
+
+/// Hands the session the next uplink is sent in to the MAC layer, so it can add its MAC commands and read the ones in
+/// the downlinks. Has to be called before every uplink.
+pub fn before_send(session: Option<&Session>) {
+    let session = session.map(|session| {
+        let mut devaddr = [0u8; 4];
+        devaddr.copy_from_slice(session.devaddr.as_ref());
+        SessionKeys {
+            nwkskey: *session.newskey.inner(),
+            appskey: *session.appskey.inner(),
+            devaddr,
+            fcnt_up: session.fcnt_up,
+            fcnt_down: session.fcnt_down,
+        }
+    });
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        // A join starts a new session with the frame counter at 0, and the ADR backoff over
+        let same_session = match (&state.session, &session) {
+            (Some(old), Some(new)) => old.devaddr == new.devaddr && old.fcnt_up <= new.fcnt_up,
+            _ => false,
+        };
+        if !same_session {
+            state.adr_ack_cnt = 0;
+        }
+        state.session = session;
+    });
+}
+
+/// Adds a LinkCheckReq to the next uplink
+pub fn request_link_check() {
+    STATE.lock(|state| state.borrow_mut().link_check_queued = true);
+}
+
+/// Whether the requested LinkCheckReq is still waiting for an uplink to go out with
+pub fn link_check_queued() -> bool {
+    STATE.lock(|state| state.borrow().link_check_queued)
+}
+
+/// The size of the MAC commands the next uplink gets in its FOpts. The application payload has to leave room for them,
+/// the regional payload limits count the FOpts as well.
+pub fn pending_fopts_len() -> usize {
+    STATE.lock(|state| state.borrow().pending_fopts_len())
+}
+
+/// The LinkCheckAns received since the last call, if any
+pub fn take_link_check_answer() -> Option<LinkCheckAnswer> {
+    STATE.lock(|state| state.borrow_mut().link_check_answer.take())
+}
+
+/// The data rate a LinkADRReq asked for since the last call, if any. lorawan-device only applies its channel mask.
+pub fn take_adr_datarate() -> Option<DR> {
+    STATE.lock(|state| state.borrow_mut().adr_datarate.take())
+}
+
+/// Whether no downlink arrived for so long that the data rate should be lowered by one step, since the last call
+pub fn take_adr_backoff() -> bool {
+    STATE.lock(|state| core::mem::take(&mut state.borrow_mut().adr_backoff))
+}
+
+/// The radiated power (EIRP) uplinks may use at most and the gain of the antenna, as configured
+pub fn set_radiated_power(max_eirp_dbm: u8, antenna_gain_dbi: u8) {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        state.max_eirp_dbm = max_eirp_dbm;
+        state.antenna_gain_dbi = antenna_gain_dbi;
+    });
+}
+
+/// The battery level the answer to the network's DevStatusReq reports, see [`crate::battery::dev_status_level`]
+pub fn set_battery_level(level: u8) {
+    STATE.lock(|state| state.borrow_mut().battery_level = level);
+}
+
+/// The transmit power of the last uplink, in dBm
+pub fn tx_power_dbm() -> i8 {
+    STATE.lock(|state| state.borrow().tx_power_dbm)
+}
+
+/// How well the last downlink was received
+pub fn last_rx_quality() -> Option<RxQuality> {
+    STATE.lock(|state| state.borrow().rx_quality)
+}
+
+impl State {
+    fn pending_fopts_len(&self) -> usize {
+        let mut len = 0;
+        if self.link_check_queued {
+            len += 1;
+        }
+        if self.dev_status_margin.is_some() {
+            len += DEV_STATUS_ANS_SIZE;
+        }
+        len
+    }
+
+    /// Copies the uplink frame the stack built into `frame`, with the ADR bits set and our MAC commands added to its
+    /// FOpts. Returns the size of the frame.
+    fn complete_uplink(&mut self, buf: &[u8], frame: &mut [u8; MAX_FRAME_SIZE]) -> usize {
+        frame[..buf.len()].copy_from_slice(buf);
+        let Some(session) = self.session.as_ref() else {
+            return buf.len();
+        };
+        // Join requests and frames of another session are sent as they are
+        if buf.len() < FHDR_SIZE + MIC_SIZE
+            || !matches!(buf[0] & MTYPE_MASK, UNCONFIRMED_DATA_UP | CONFIRMED_DATA_UP)
+            || buf[1..5] != session.devaddr
+        {
+            return buf.len();
+        }
+        let fopts_end = FHDR_SIZE + (buf[FCTRL_INDEX] & FOPTS_LEN_MASK) as usize;
+        // MAC commands in the FRMPayload (FPort 0) rule out any in the FOpts
+        if buf.len() > fopts_end + MIC_SIZE && buf[fopts_end] == 0 {
+            return buf.len();
+        }
+
+        let mut added = [0u8; MAX_FOPTS_SIZE];
+        let mut added_len = 0;
+        let room = MAX_FOPTS_SIZE - (fopts_end - FHDR_SIZE);
+        if self.link_check_queued && added_len < room {
+            added[added_len] = LINK_CHECK_REQ;
+            added_len += 1;
+            self.link_check_queued = false;
+        }
+        if let Some(margin) = self.dev_status_margin {
+            if added_len + DEV_STATUS_ANS_SIZE <= room {
+                added[added_len..added_len + DEV_STATUS_ANS_SIZE].copy_from_slice(&[
+                    DEV_STATUS_ANS,
+                    self.battery_level,
+                    margin,
+                ]);
+                added_len += DEV_STATUS_ANS_SIZE;
+                self.dev_status_margin = None;
+            }
+        }
+        if buf.len() + added_len > MAX_FRAME_SIZE {
+            added_len = 0;
+        }
+
+        // The frame carries the lower 16 bits of the frame counter, the session the upper ones. The stack may have
+        // counted past the session it was handed before sending, but never back.
+        let fcnt_low = u16::from_le_bytes([buf[FCNT_INDEX], buf[FCNT_INDEX + 1]]);
+        let mut fcnt = session.fcnt_up & !0xFFFF | fcnt_low as u32;
+        if fcnt < session.fcnt_up {
+            fcnt = fcnt.wrapping_add(0x1_0000);
+        }
+
+        let mut fctrl = buf[FCTRL_INDEX] | FCTRL_ADR;
+        if self.adr_ack_cnt >= ADR_ACK_LIMIT {
+            fctrl |= FCTRL_ADR_ACK_REQ;
+        }
+        self.adr_back_off();
+
+        let size = buf.len() + added_len;
+        frame[fopts_end..fopts_end + added_len].copy_from_slice(&added[..added_len]);
+        frame[fopts_end + added_len..size].copy_from_slice(&buf[fopts_end..]);
+        frame[FCTRL_INDEX] = fctrl + added_len as u8;
+        // The FRMPayload is encrypted independently of the FHDR, only the MIC needs to be calculated again
+        let mic = uplink_mic(&frame[..size - MIC_SIZE], &session.nwkskey, fcnt);
+        frame[size - MIC_SIZE..size].copy_from_slice(&mic);
+        size
+    }
+
+    /// Counts an uplink without a downlink since. The first step of the backoff restores the full transmit power, the
+    /// following ones lower the data rate (which policy.rs applies to the device).
+    fn adr_back_off(&mut self) {
+        self.adr_ack_cnt = self.adr_ack_cnt.saturating_add(1);
+        if self.adr_ack_cnt < ADR_ACK_LIMIT + ADR_ACK_DELAY
+            || (self.adr_ack_cnt - ADR_ACK_LIMIT) % ADR_ACK_DELAY != 0
+        {
+            return;
+        }
+        if self.adr_eirp_dbm.take().is_some() {
+            warn!("No downlink received for a while, ADR: back to the full transmit power.");
+        } else {
+            warn!("No downlink received for a while, ADR: lowering the data rate.");
+            self.adr_backoff = true;
+        }
+    }
+
+    /// Reads the MAC commands lorawan-device ignores from a frame received in RX1 or RX2. In class C, only the quality
+    /// of the reception is kept, lorawan-device does not handle MAC commands there either.
+    fn handle_downlink(&mut self, frame: &[u8], quality: RxQuality, class_c: bool) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        if frame.len() > MAX_FRAME_SIZE {
+            return;
+        }
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let buf = &mut buf[..frame.len()];
+        buf.copy_from_slice(frame);
+        let Ok(PhyPayload::Data(DataPayload::Encrypted(encrypted))) = parser::parse(buf) else {
+            return;
+        };
+        if encrypted.is_uplink() || encrypted.fhdr().dev_addr().as_ref() != session.devaddr {
+            return;
+        }
+        // The same checks as lorawan-device does, which processes the frame afterwards
+        let fcnt = encrypted.fhdr().fcnt() as u32;
+        if !encrypted.validate_mic(&session.nwkskey, fcnt)
+            || (fcnt <= session.fcnt_down && fcnt != 0)
+        {
+            return;
+        }
+        session.fcnt_down = fcnt;
+        let (nwkskey, appskey) = (session.nwkskey, session.appskey);
+        self.rx_quality = Some(quality);
+        if class_c {
+            return;
+        }
+        // The answer to an ADRACKReq, or any other downlink in RX1 or RX2, shows the network still hears us
+        self.adr_ack_cnt = 0;
+        let Ok(decrypted) = encrypted.decrypt(Some(&nwkskey), Some(&appskey), fcnt) else {
+            return;
+        };
+        self.handle_commands(decrypted.fhdr().data());
+        if let FRMPayload::MACCommands(commands) = decrypted.frm_payload() {
+            self.handle_commands(commands.data());
+        }
+    }
+
+    fn handle_commands(&mut self, commands: &[u8]) {
+        for command in parse_downlink_mac_commands(commands) {
+            match command {
+                DownlinkMacCommand::LinkCheckAns(answer) => {
+                    self.link_check_answer = Some(LinkCheckAnswer {
+                        margin_db: answer.margin(),
+                        gateway_count: answer.gateway_count(),
+                    });
+                }
+                // Answered with the next uplink. The margin is the SNR the request was received with.
+                DownlinkMacCommand::DevStatusReq(_) => {
+                    let snr = self.rx_quality.map_or(0, |quality| quality.snr());
+                    let margin = snr.clamp(*MARGIN_RANGE.start(), *MARGIN_RANGE.end());
+                    self.dev_status_margin = Some(margin as u8 & MARGIN_MASK);
+                }
+                // lorawan-device applies the channel mask and acknowledges the request. Values this region does not
+                // define are ignored.
+                DownlinkMacCommand::LinkADRReq(request) => {
+                    let datarate = request.data_rate();
+                    if datarate != ADR_UNCHANGED {
+                        match lorawan_region::datarate(datarate)
+                            .filter(|datarate| lorawan_region::max_payload(*datarate) > 0)
+                        {
+                            Some(datarate) => self.adr_datarate = Some(datarate),
+                            None => warn!("Ignoring ADR data rate DR{:?}", datarate),
+                        }
+                    }
+                    let tx_power = request.tx_power();
+                    if tx_power != ADR_UNCHANGED {
+                        match lorawan_region::adr_eirp(tx_power) {
+                            Some(dbm) => self.adr_eirp_dbm = Some(dbm),
+                            None => warn!("Ignoring ADR transmit power {:?}", tx_power),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The MIC of an uplink frame (without its MIC), as the LoRaWAN specification defines it
+fn uplink_mic(frame: &[u8], nwkskey: &AES128, fcnt: u32) -> [u8; MIC_SIZE] {
+    let mut b0 = [0u8; 16];
+    b0[0] = 0x49;
+    b0[6..10].copy_from_slice(&frame[1..5]);
+    b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    b0[15] = frame.len() as u8;
+    let mut cmac = DefaultFactory.new_mac(nwkskey);
+    cmac.input(&b0);
+    cmac.input(frame);
+    let mut mic = [0u8; MIC_SIZE];
+    mic.copy_from_slice(&cmac.result()[..MIC_SIZE]);
+    mic
+}
+
+/// The radio as lorawan-device sees it. It adds the MAC commands the stack does not support to the uplinks and picks
+/// the answers to them out of the downlinks, before passing them on. It also applies the transmit power the network
+/// asked for.
+pub struct MacRadio<R> {
+    radio: R,
+}
+
+impl<R> MacRadio<R> {
+    pub fn new(radio: R) -> Self {
+        Self { radio }
+    }
+}
+
+impl<R: Timings> Timings for MacRadio<R> {
+    fn get_rx_window_lead_time_ms(&self) -> u32 {
+        self.radio.get_rx_window_lead_time_ms()
+    }
+
+    fn get_rx_window_buffer(&self) -> u32 {
+        self.radio.get_rx_window_buffer()
+    }
+}
+
+impl<R: PhyRxTx> PhyRxTx for MacRadio<R> {
+    type PhyError = R::PhyError;
+
+    const ANTENNA_GAIN: i8 = R::ANTENNA_GAIN;
+    const MAX_RADIO_POWER: u8 = R::MAX_RADIO_POWER;
+
+    async fn tx(&mut self, mut config: TxConfig, buf: &[u8]) -> Result<u32, Self::PhyError> {
+        // The join request is dropped, the stack waits for a join accept in vain
+        if !network::join_request_allowed(buf) {
+            return Ok(0);
+        }
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let size = STATE.lock(|state| {
+            let mut state = state.borrow_mut();
+            let mut eirp = state.max_eirp_dbm as i8;
+            if let Some(dbm) = state.adr_eirp_dbm {
+                eirp = eirp.min(dbm);
+            }
+            config.pw = config
+                .pw
+                .min(lorawan_region::tx_power(eirp, state.antenna_gain_dbi));
+            state.tx_power_dbm = config.pw;
+            state.complete_uplink(buf, &mut frame)
+        });
+        self.radio.tx(config, &frame[..size]).await
+    }
+
+    async fn setup_rx(&mut self, config: RxConfig) -> Result<(), Self::PhyError> {
+        self.radio.setup_rx(config).await
+    }
+
+    async fn rx_continuous(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<(usize, RxQuality), Self::PhyError> {
+        let (size, quality) = self.radio.rx_continuous(buf).await?;
+        STATE.lock(|state| {
+            state
+                .borrow_mut()
+                .handle_downlink(&buf[..size], quality, true)
+        });
+        Ok((size, quality))
+    }
+
+    async fn rx_single(&mut self, buf: &mut [u8]) -> Result<RxStatus, Self::PhyError> {
+        let status = self.radio.rx_single(buf).await?;
+        if let RxStatus::Rx(size, quality) = &status {
+            STATE.lock(|state| {
+                state
+                    .borrow_mut()
+                    .handle_downlink(&buf[..*size], *quality, false)
+            });
+        }
+        Ok(status)
+    }
+
+    async fn low_power(&mut self) -> Result<(), Self::PhyError> {
+        self.radio.low_power().await
+    }
+}
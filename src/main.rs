@@ -1,74 +1,229 @@
 #![no_std]
 #![no_main]
 
+mod airtime;
+mod alarm;
+mod backlog;
+mod battery;
+#[cfg(feature = "sensor-bme280")]
+mod bme280;
+// Not started on battery, the LED stays off
+#[cfg_attr(feature = "battery", allow(dead_code))]
 mod blinky;
+mod class_c;
+mod clock;
+mod commands;
+mod config;
+mod crash;
+#[cfg(feature = "sensor-ds18b20")]
+mod ds18b20;
+mod firmware;
+mod flash_region;
+mod fragment_decoder;
+mod fuota;
 mod iec62056;
-use core::panic;
+mod link_check;
+mod load_profile;
+mod lorawan_region;
+mod mac;
+mod multicast;
+mod network;
+mod policy;
+mod power;
+#[cfg(feature = "sensor-sht3x")]
+mod sht3x;
+mod status;
+mod temperature;
+mod uplink;
+mod watchdog;
 use core::sync::atomic::Ordering;
 
-use bincode::{config, encode_into_slice, Decode, Encode};
+use alarm::AlarmMonitor;
+use backlog::ReadingBuffer;
+use battery::SupplyMonitor;
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+#[cfg(not(feature = "battery"))]
 use blinky::BlinkPeripherals;
-use const_hex::decode_to_array;
-use defmt::{error, info, warn};
+use clock::ClockSync;
+use commands::Action;
+use config::Config as DeviceConfig;
+use defmt::{info, warn};
+use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_rp::adc::Channel as AdcChannel;
-use embassy_rp::adc::{Adc, Async};
+use embassy_rp::adc::Adc;
 use embassy_rp::bind_interrupts;
 use embassy_rp::dma::Channel as DmaChannel;
+use embassy_rp::flash::ERASE_SIZE;
 use embassy_rp::gpio::{Input, Level, Output, Pin, Pull};
-use embassy_rp::peripherals::UART0;
+use embassy_rp::peripherals::{DMA_CH3, FLASH, UART0};
 use embassy_rp::spi::{Config, Spi};
 use embassy_rp::uart::BufferedInterruptHandler;
 use embassy_rp_flash_struct::FlashStorage;
 use embassy_time::{with_timeout, Duration};
 use embassy_time::{Delay, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
+use flash_region::FlashRegion;
+use fuota::Fuota;
 use iec62056::EnergyMeter;
+use link_check::LinkMonitor;
+use load_profile::LoadProfileRecorder;
 use lora_phy::iv::GenericSx126xInterfaceVariant;
 use lora_phy::lorawan_radio::LorawanRadio;
 use lora_phy::sx126x::{self, Sx1262, Sx126x, TcxoCtrlVoltage};
 use lora_phy::LoRa;
-use lorawan_device::async_device::{
-    radio, region, Device, EmbassyTimer, JoinMode, JoinResponse, SendResponse, Timings,
-};
+use lorawan_device::async_device::{radio, Device, EmbassyTimer, SendResponse, Timings};
 use lorawan_device::default_crypto::DefaultFactory as Crypto;
-use lorawan_device::{AppEui, AppKey, CryptoFactory, DevEui, RngCore};
+use lorawan_device::{CryptoFactory, RngCore};
+use mac::MacRadio;
+use network::{join_network, DevNonceRng, StoredSession};
+use policy::Delivery;
 use portable_atomic::AtomicU64;
-use {defmt_rtt as _, panic_probe as _};
-
-// warning: set these appropriately for the region
-const LORAWAN_REGION: region::Region = region::Region::EU868;
-const MAX_TX_POWER: u8 = 14;
-
-// The durations and timeouts used within this struct are all centrally defined here
+use powermeter_payload::{
+    Diagnostic, Measurement, ResetReason, Uplink, MAX_PAYLOAD_SIZE, UPLINK_FPORT,
+};
+#[cfg(feature = "cabinet-sensor")]
+use temperature::CabinetSensor;
+use temperature::Thermometer;
+use uplink::UplinkBuilder;
+use watchdog::Task;
+
+// The durations and timeouts used within this struct are all centrally defined here. The interval and its variation
+// are only defaults, they can be changed by downlink commands (see config.rs).
 const METER_TIMEOUT: Duration = Duration::from_secs(10); // How long to wait for the energy meter's serial port to respond
-const MEASUREMENT_TRANSMIT_INTERVAL: Duration = Duration::from_secs(30); // How long to sleep between sending messages
-const RANDOM_SLEEP_VARIATION: Duration = Duration::from_secs(1); // The MEASUREMENT_TRANSMIT_INTERVAL is randomly appended this value. This reduces simultaneous transmissions
+const MEASUREMENT_TRANSMIT_INTERVAL: Duration = Duration::from_secs(5 * 60); // How long to sleep between sending messages
+const RANDOM_SLEEP_VARIATION: Duration = Duration::from_secs(10); // The MEASUREMENT_TRANSMIT_INTERVAL is randomly appended up to this value. This reduces simultaneous transmissions
 
 // This is the amount of channels used for listening on the S0 bus. 6 is the hightest value we are expecting in our use case
-const S0_CHANNEL_COUNT: usize = 6;
+const S0_CHANNEL_COUNT: usize = powermeter_payload::S0_CHANNEL_COUNT;
 static S0_COUNTERS: [AtomicU64; S0_CHANNEL_COUNT] = [const { AtomicU64::new(0) }; S0_CHANNEL_COUNT];
-const S0_IMP_PER_KWH: [f32; S0_CHANNEL_COUNT] = [800.0; S0_CHANNEL_COUNT];
-
-// We save the counter values to flash, so continue counting up over device resets
-#[derive(Default, Encode, Decode)]
-pub struct CounterValues {
+const S0_IMP_PER_KWH: [u64; S0_CHANNEL_COUNT] = [800; S0_CHANNEL_COUNT];
+
+// We save the counter values, the LoRaWAN session and the configuration to flash, so continue counting up (and don't
+// need to rejoin or be reconfigured) over device resets. The readings not known to have arrived and the load profile
+// are saved on their own (see backlog.rs and load_profile.rs). Each is saved to the slots of its own flash region (see
+// memory.x).
+#[derive(Default, Clone)]
+pub struct PersistentState {
     counts: [u64; S0_CHANNEL_COUNT],
+    session: Option<StoredSession>,
+    dev_nonce: u16, // The DevNonce for the next join request, LoRaWAN 1.0.4 requires it to be increasing
+    dev_nonce_probing: bool, // The last DevNonce the network server accepted is unknown (see network.rs)
+    config: DeviceConfig,
+    // The readings found in the layout that kept them with the rest of the state, until they are saved on their own
+    moved_readings: Option<ReadingBuffer>,
 }
 
-// What will get transmitted over the air
-#[derive(Encode)]
-pub struct Transmission {
-    flash_wear_fraction: f32, // 0 to 1, with 0 being new, 1 being totally worn
-    temperature: f32,         //In degrees celsius
-
-    main_meter_kwh: f32, // From the IEC62056 connection
-    counter_0_kwh: f32,  // From the S0 counters
-    counter_1_kwh: f32,  // From the S0 counters
-    counter_2_kwh: f32,  // From the S0 counters
-    counter_3_kwh: f32,  // From the S0 counters
-    counter_4_kwh: f32,  // From the S0 counters
-    counter_5_kwh: f32,  // From the S0 counters
+// The persistent state starts with this marker and the version of its layout. The first firmware stored only the
+// counter values, starting with a variable length integer (bincode's standard encoding), which never starts with 255.
+const STATE_MARKER: u8 = 0xFF;
+const STATE_VERSION: u8 = 2;
+// The layout that ended with the readings not known to have arrived
+const STATE_VERSION_WITH_READINGS: u8 = 1;
+const STATE_SLOT_SIZE: usize = ERASE_SIZE;
+
+extern "C" {
+    static __state_start: u32;
+    static __state_end: u32;
+}
+
+impl PersistentState {
+    /// Restores the state from the newest slot of its flash region. Firmwares before the region kept it elsewhere, it is
+    /// moved from there on the first boot after the update.
+    async fn load(dma: DMA_CH3) -> Self {
+        if let Some(state) = state_region().load() {
+            return state;
+        }
+        // The flash belongs to the shared driver (see flash_region.rs), which nothing uses yet. This one only reads,
+        // and is gone before anything else uses the flash.
+        let mut legacy_storage: FlashStorage<Self> =
+            FlashStorage::new(unsafe { FLASH::steal() }, dma.degrade());
+        let mut state: Self = legacy_storage.read().await;
+        drop(legacy_storage);
+        info!("Moving the persistent state to its flash region.");
+        if let Some(readings) = state.moved_readings.take() {
+            readings.save();
+        }
+        state.save();
+        state
+    }
+
+    /// Writes the state to the slot after the newest one
+    pub fn save(&self) {
+        state_region().save(self);
+    }
+}
+
+fn state_region() -> FlashRegion {
+    unsafe { FlashRegion::new(&__state_start, &__state_end, STATE_SLOT_SIZE) }
+}
+
+impl Encode for PersistentState {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        STATE_MARKER.encode(encoder)?;
+        STATE_VERSION.encode(encoder)?;
+        self.counts.encode(encoder)?;
+        self.session.encode(encoder)?;
+        self.dev_nonce.encode(encoder)?;
+        self.config.encode(encoder)?;
+        self.dev_nonce_probing.encode(encoder)
+    }
+}
+
+impl Decode for PersistentState {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let first = u8::decode(decoder)?;
+        if first != STATE_MARKER {
+            // The counter values of the first firmware, everything else starts out with the defaults. It drew its
+            // DevNonces at random, the next ones have to find out where to continue.
+            let mut counts = [0; S0_CHANNEL_COUNT];
+            counts[0] = continue_varint(first, decoder)?;
+            for count in &mut counts[1..] {
+                *count = u64::decode(decoder)?;
+            }
+            info!("Converted the counter values of the first firmware.");
+            return Ok(Self {
+                counts,
+                dev_nonce: network::FIRST_PROBED_DEV_NONCE,
+                dev_nonce_probing: true,
+                ..Default::default()
+            });
+        }
+        match u8::decode(decoder)? {
+            version @ (STATE_VERSION | STATE_VERSION_WITH_READINGS) => {
+                let mut state = Self {
+                    counts: Decode::decode(decoder)?,
+                    session: Decode::decode(decoder)?,
+                    dev_nonce: Decode::decode(decoder)?,
+                    config: Decode::decode(decoder)?,
+                    dev_nonce_probing: false,
+                    moved_readings: None,
+                };
+                if version == STATE_VERSION_WITH_READINGS {
+                    info!("Moving the buffered readings to their own flash region.");
+                    state.moved_readings = Some(Decode::decode(decoder)?);
+                } else {
+                    state.dev_nonce_probing = Decode::decode(decoder)?;
+                }
+                Ok(state)
+            }
+            // A later firmware's layout, after falling back to this one
+            _ => Err(DecodeError::Other("unknown persistent state version")),
+        }
+    }
+}
+
+/// The rest of a u64 in bincode's variable length encoding, after its first byte
+fn continue_varint<D: Decoder>(first: u8, decoder: &mut D) -> Result<u64, DecodeError> {
+    Ok(match first {
+        0..=250 => first as u64,
+        251 => u16::from_le_bytes(Decode::decode(decoder)?) as u64,
+        252 => u32::from_le_bytes(Decode::decode(decoder)?) as u64,
+        253 => u64::from_le_bytes(Decode::decode(decoder)?),
+        _ => return Err(DecodeError::Other("invalid variable length integer")),
+    })
 }
 
 bind_interrupts!(struct Irqs {
@@ -79,7 +234,17 @@ bind_interrupts!(struct Irqs {
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     // Initialise Peripherals
-    let p = embassy_rp::init(Default::default());
+    let p = embassy_rp::init(power::config());
+    // All flash access goes through one driver, starting with the bootloader state of a firmware update
+    flash_region::init(p.FLASH);
+    // Reported with the first measurement, where the previous firmware crashed with a diagnostic uplink after it
+    let (reason, crash) = watchdog::start(spawner, p.WATCHDOG);
+    let mut reset_reason = Some(reason);
+    let mut crash_report = crash.map(|crash| Diagnostic {
+        reset_reason: reason,
+        crash: Some(crash),
+    });
+    firmware::start_trial(spawner);
 
     // ---------------- Start the tasks that update the values for the counters whenever they are updated ---------------
     {
@@ -105,6 +270,8 @@ async fn main(spawner: Spawner) {
     }
 
     // ---------------- Initialize the Status blinky --------------------
+    // On battery, the LED stays off
+    #[cfg(not(feature = "battery"))]
     {
         #[cfg(feature = "pico_w")]
         let p = BlinkPeripherals {
@@ -121,10 +288,37 @@ async fn main(spawner: Spawner) {
 
         blinky::init(Duration::from_millis(100), spawner, p).await;
     }
+    #[cfg(all(feature = "battery", feature = "pico_w"))]
+    power::power_off_cyw43(p.PIN_23);
 
-    //---------------------Initialize the ADC to read temperature and battery voltage-------------
+    //---------------------Initialize the ADC to read temperature and supply voltage--------------
     let mut adc = Adc::new(p.ADC, Irqs, embassy_rp::adc::Config::default());
-    let mut temp_chan = AdcChannel::new_temp_sensor(p.ADC_TEMP_SENSOR);
+    #[cfg(not(feature = "cabinet-sensor"))]
+    let mut thermometer = Thermometer::new(p.ADC_TEMP_SENSOR);
+    #[cfg(feature = "sensor-ds18b20")]
+    let mut thermometer = Thermometer::new(p.ADC_TEMP_SENSOR, CabinetSensor::new(p.PIO1, p.PIN_6));
+    #[cfg(any(feature = "sensor-sht3x", feature = "sensor-bme280"))]
+    let mut thermometer = Thermometer::new(
+        p.ADC_TEMP_SENSOR,
+        CabinetSensor::new(p.I2C0, p.PIN_4, p.PIN_5),
+    );
+    // On the Pico W, VSYS can only be measured while the CYW43 is off (see battery.rs)
+    #[cfg(feature = "pico_non_w")]
+    let mut supply_monitor = Some(SupplyMonitor::new(p.PIN_29));
+    #[cfg(all(feature = "pico_w", feature = "battery"))]
+    let mut supply_monitor = Some(SupplyMonitor::new(p.PIN_29, p.PIN_25));
+    #[cfg(all(feature = "pico_w", not(feature = "battery")))]
+    let mut supply_monitor: Option<SupplyMonitor> = None;
+
+    // Load in the saved counter values and LoRaWAN session form flash, if they exist
+    let mut state = PersistentState::load(p.DMA_CH3).await;
+    for (i, counter) in S0_COUNTERS.iter().enumerate().take(S0_CHANNEL_COUNT) {
+        counter.fetch_add(state.counts[i], Ordering::Relaxed);
+    }
+    mac::set_radiated_power(state.config.max_eirp_dbm, state.config.antenna_gain_dbi);
+    let mut readings = ReadingBuffer::load();
+    let mut load_profile = LoadProfileRecorder::load();
+    load_profile.power_failed();
 
     // ---------------- Initialize the LoRa Radio -----------------
     // I'm not able to move this to a separate file bcause of waaay to many generics
@@ -157,239 +351,376 @@ async fn main(spawner: Spawner) {
             .await
             .unwrap();
 
-        let radio: LorawanRadio<_, _, MAX_TX_POWER> = lora.into();
-        let region: region::Configuration = region::Configuration::new(LORAWAN_REGION);
-        let device: Device<_, Crypto, _, _> = Device::new(
-            region,
+        // The transmit power is limited further by the MAC layer, as configured for the installation
+        let radio: LorawanRadio<_, _, { lorawan_region::MAX_RADIO_POWER }> = lora.into();
+        // Handles the MAC commands lorawan-device does not
+        let radio = MacRadio::new(radio);
+        let mut device: Device<_, Crypto, _, _> = Device::new_with_session(
+            lorawan_region::configuration(),
             radio,
             EmbassyTimer::new(),
-            embassy_rp::clocks::RoscRng,
+            DevNonceRng,
+            network::restorable_session(&state.session),
         );
+        device.set_datarate(lorawan_region::DEFAULT_DATARATE);
+        // From the first uplink on, the radio keeps listening on RX2 in between (see class_c.rs)
+        #[cfg(feature = "class-c")]
+        device.enable_class_c();
         device
     };
-    join_network(&mut device).await;
-
-    // Load in the saved counter values form flash, if they exist
-    let mut persistent_storage: FlashStorage<CounterValues> =
-        FlashStorage::new(p.FLASH, p.DMA_CH3.degrade());
-    {
-        let current_value = persistent_storage.read().await;
-        for (i, counter) in S0_COUNTERS.iter().enumerate().take(S0_CHANNEL_COUNT) {
-            let current_value_from_flash = current_value.counts[i];
-            counter.fetch_add(current_value_from_flash, Ordering::Relaxed);
-        }
+    // The supervisor expects the radio to be initialized by now, it only checks in while busy from here on
+    watchdog::idle(Task::Radio);
+    if device.get_session().is_none() {
+        join_network(&mut device, &mut state).await;
+    } else {
+        info!("Restored LoRaWAN session from flash, not joining.");
+    }
+    // Store the session right away, so a reset before the first loop is done doesn't force us to join again
+    if network::update_stored_session(&mut device, &mut state.session) {
+        state.save();
     }
 
     // Initialize the UART energy meter reader
     let mut meter_connection = EnergyMeter::new(p.UART0, Irqs, p.PIN_1, p.PIN_0);
 
+    let mut uplink_builder = UplinkBuilder::default();
+    let mut clock_sync = ClockSync::default();
+    let mut fuota = Fuota::default();
+    let mut link_monitor = LinkMonitor::default();
+    let mut alarm_monitor = AlarmMonitor::default();
+    let mut pending_ack = None; // The acknowledgement for the last command, sent with the next measurement
+    let mut pending_action = Action::None; // What a command received in class C asked for, done after the next uplink
+
     // Loop
     loop {
-        {
+        watchdog::check_in(Task::MainLoop);
+        'measurement: {
             //--------------------------------- Acquire Sensor Data -------------------------------------
             blinky::PERIOD.signal(Duration::from_millis(500));
 
-            // Start the acquisition process for battery data (it runs in the background)
-            let analog_data_future = temperature(&mut temp_chan, &mut adc);
-            let meter_energy = match with_timeout(METER_TIMEOUT, meter_connection.get_data()).await
-            {
+            // Start the acquisition process for the temperature (it runs in the background)
+            let climate_future = thermometer.measure(&mut adc, &state.config);
+            let meter_data = match with_timeout(METER_TIMEOUT, meter_connection.get_data()).await {
                 Err(_) => {
                     warn!("Timeout reading from energy meter!");
                     None
                 }
-                Ok(result) => Some(result.total_in),
+                Ok(result) => Some(result),
+            };
+            let climate = climate_future.await;
+            let supply = match supply_monitor.as_mut() {
+                Some(monitor) => Some(monitor.measure(&mut adc, &state.config).await),
+                None => None,
             };
-            let temperature = analog_data_future.await;
+            mac::set_battery_level(battery::dev_status_level(&state.config, supply));
+            // Commands may have changed the limits since the last cycle
+            mac::set_radiated_power(state.config.max_eirp_dbm, state.config.antenna_gain_dbi);
+            let measured_at = clock::utc_now();
 
-            let mut counter_kwh: [f32; S0_CHANNEL_COUNT] = [0.0; S0_CHANNEL_COUNT];
+            let mut impulses: [u64; S0_CHANNEL_COUNT] = [0; S0_CHANNEL_COUNT];
+            let mut counter_wh: [Option<u64>; S0_CHANNEL_COUNT] = [None; S0_CHANNEL_COUNT];
             for (i, counter) in S0_COUNTERS.iter().enumerate().take(S0_CHANNEL_COUNT) {
                 let current_counter_value = counter.load(Ordering::Relaxed);
-                let current_kwh_value = current_counter_value as f32 / S0_IMP_PER_KWH[i];
-                counter_kwh[i] = current_kwh_value;
+                impulses[i] = current_counter_value;
+                // In 128 bits, a counter that went past the range a command can set is left out instead of wrapping
+                let current_wh_value =
+                    current_counter_value as u128 * 1000 / state.config.s0_imp_per_kwh[i] as u128;
+                counter_wh[i] = u64::try_from(current_wh_value).ok();
             }
 
             //--------------------------------- Prepare and transmit -------------------------------------
             blinky::PERIOD.signal(Duration::from_millis(50));
-            let to_transmit = Transmission {
-                flash_wear_fraction: persistent_storage.exhaustion(),
-                temperature,
-
-                main_meter_kwh: match meter_energy {
-                    None => f32::NAN,
-                    Some(val) => val,
-                },
-                counter_0_kwh: counter_kwh[0],
-                counter_1_kwh: counter_kwh[1],
-                counter_2_kwh: counter_kwh[2],
-                counter_3_kwh: counter_kwh[3],
-                counter_4_kwh: counter_kwh[4],
-                counter_5_kwh: counter_kwh[5],
+            // Alarms go out first, so they don't wait for the measurement's repetitions
+            alarm_monitor.check(&state.config, climate, meter_data.is_some(), supply);
+            alarm_monitor
+                .send(
+                    &mut device,
+                    &state.config,
+                    measured_at.map(|time| time.as_secs() as u32),
+                )
+                .await;
+            let measurement = Measurement {
+                flash_wear: Some((state_region().wear().clamp(0.0, 1.0) * 255.0) as u8),
+                temperature_decicelsius: climate.map(|climate| climate.temperature_decicelsius),
+                meter_import_wh: meter_data.map(|data| (data.total_in * 1000.0) as u64),
+                meter_export_wh: meter_data.map(|data| (data.total_out * 1000.0) as u64),
+                meter_id: meter_data.map(|data| data.meter_id),
+                counter_wh,
+                command_ack: pending_ack,
+                timestamp: measured_at.map(|time| time.as_secs() as u32),
+                reset_reason,
+                supply,
+                humidity_permille: climate.and_then(|climate| climate.humidity_permille),
             };
-            if size_of::<Transmission>() > 49 {
-                panic!("Maximum transmission size for DR0 exceeded!");
-            }
-            let mut transmission_buf = [0u8; size_of::<Transmission>()];
-            let size =
-                encode_into_slice(to_transmit, &mut transmission_buf, config::standard()).unwrap();
-            if size != size_of::<Transmission>() {
-                panic!("Encoding did something unexpected!");
+            // The reading is kept until we know it arrived, so it can be sent again after a network outage
+            if let Some(reading) = measurement.reading() {
+                readings.push(reading);
+                load_profile.record(&reading);
             }
+            let confirmed = readings.confirm_due();
+
+            // Send as much as the current data rate and what is left of the airtime budget allow, leaving room for the
+            // MAC commands that go along
+            link_monitor.before_uplink();
+            let fopts_len = mac::pending_fopts_len();
+            let mut transmission_buf = [0u8; MAX_PAYLOAD_SIZE];
+            let uplink = loop {
+                let datarate = device.get_datarate();
+                let remaining_airtime = airtime::remaining(state.config.airtime_budget());
+                let region_max_payload =
+                    lorawan_region::max_payload(datarate).saturating_sub(fopts_len);
+                let max_payload = region_max_payload.min(
+                    airtime::max_payload_within(datarate, remaining_airtime)
+                        .saturating_sub(fopts_len),
+                );
+                if let Some(uplink) =
+                    uplink_builder.build(measurement.clone(), max_payload, &mut transmission_buf)
+                {
+                    break uplink;
+                }
+                if max_payload < region_max_payload {
+                    // The reading stays buffered and is sent once there is airtime again
+                    warn!("Airtime budget used up, skipping this uplink.");
+                    break 'measurement;
+                }
+                if datarate == lorawan_region::DEFAULT_DATARATE {
+                    break 'measurement;
+                }
+                // If the network has moved us to a data rate that is too low, we fall back to the default one and
+                // build the uplink again
+                device.set_datarate(lorawan_region::DEFAULT_DATARATE);
+            };
 
-            let resp = device.send(&transmission_buf, 1, false).await;
+            if uplink.reset_reason_included {
+                reset_reason = None;
+            }
+            // Command acknowledgements are repeated until they arrive, otherwise the backend sends the command again.
+            // A new firmware asks for an acknowledgement until the network answered, so it is kept in time.
+            let delivery = if uplink.ack_included {
+                Delivery::Retried
+            } else if confirmed || firmware::awaiting_network() {
+                Delivery::Confirmed
+            } else {
+                Delivery::Unconfirmed
+            };
+            let resp = policy::send(
+                &mut device,
+                &transmission_buf[..uplink.size],
+                UPLINK_FPORT,
+                delivery,
+                &state.config,
+            )
+            .await;
+            link_monitor.after_uplink();
+            // A new firmware works once it measures something and the network answers, so the bootloader can keep it. Without a meter connected, an S0 impulse since the last cycle will do.
+            if meter_data.is_some() || impulses != state.counts {
+                firmware::measured();
+            }
+            // The timestamp of the reading, if all of its values were sent
+            let sent_reading = measured_at
+                .filter(|_| uplink.complete)
+                .map(|time| time.as_secs() as u32);
+            // A command received in class C while we slept may have asked for something as well
+            let mut action = core::mem::replace(&mut pending_action, Action::None);
             match resp {
-                Ok(send_resp) => {
+                Some(SendResponse::NoAck) => readings.not_acknowledged(),
+                Some(send_resp) => {
                     info!("Sending okay: {:?}", send_resp);
+                    if uplink.ack_included {
+                        pending_ack = None;
+                    }
+                    if delivery != Delivery::Unconfirmed {
+                        readings.acknowledged(sent_reading);
+                    } else if let Some(timestamp) = sent_reading {
+                        readings.sent_unconfirmed(timestamp);
+                    }
                     match send_resp {
-                        SendResponse::DownlinkReceived(_) => {
-                            // Handle downlink requests
-                            // We have received a donlink, but it does not necessarily contain information
-                            let downlink = device.take_downlink();
-                            match downlink {
-                                None => info!("Downlink empty!"),
-                                Some(data) => {
-                                    // We can update the counter values using the downlink.
-                                    // FPORT-1 is the counter to update
-                                    // The payload should be a 8-byte value
-                                    let counter_to_update = (data.fport - 1) as usize;
-                                    if counter_to_update > S0_CHANNEL_COUNT {
-                                        error!("Invalid FPORT {:?}", counter_to_update);
-                                    } else {
-                                        // The payload should be an 8-byte value
-                                        if data.data.len() != 8 {
-                                            error!("Invalid data len {:?}", data.data.len());
-                                        } else {
-                                            let buf = data.data.into_array().unwrap();
-                                            let new_counter_value = u64::from_le_bytes(buf);
-                                            S0_COUNTERS[counter_to_update]
-                                                .store(new_counter_value, Ordering::Relaxed);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        // If our session expired, we try to rejoin. We set the radio to the lowest data rate first.
-                        SendResponse::NoAck => info!("No Acknowledgement received."),
+                        SendResponse::DownlinkReceived(_) => commands::receive(&mut device),
+                        SendResponse::NoAck => {} // Handled above
                         SendResponse::RxComplete => info!("No data received."),
-                        SendResponse::SessionExpired => join_network(&mut device).await,
+                        // If our session expired, we join again. Afterwards, we start on the default data rate and ADR
+                        // moves us up from there.
+                        #[cfg(not(feature = "abp"))]
+                        SendResponse::SessionExpired => {
+                            join_network(&mut device, &mut state).await;
+                            link_monitor.rejoined();
+                        }
+                        // With ABP, joining again would restart the frame counters, which the network server rejects.
+                        // The device has to be given a new session.
+                        #[cfg(feature = "abp")]
+                        SendResponse::SessionExpired => {
+                            warn!("Session expired, ABP can't join again.")
+                        }
                     }
                 }
-                Err(e) => warn!("Unexpected error! {:?}", e),
+                None => {}
+            }
+            // Handle downlink requests
+            let downlink_action = commands::dispatch_received(
+                &mut clock_sync,
+                &mut fuota,
+                &mut state.config,
+                &mut pending_ack,
+            );
+            if downlink_action != Action::None {
+                action = downlink_action;
+            }
+
+            //--------------------------------- Follow up on commands -------------------------------------
+            match action {
+                Action::None => {}
+                Action::Reboot => {
+                    // The acknowledgement would be lost with the reboot, so it is sent on its own first
+                    let ack_only = Uplink::Measurement(Measurement {
+                        command_ack: pending_ack.take(),
+                        ..Default::default()
+                    });
+                    network::send_uplink(&mut device, &ack_only, Delivery::Retried, &state.config)
+                        .await;
+                    // The counters, this cycle's reading and the commands acknowledged with it are saved first
+                    save_state(&mut state, &readings, &mut device);
+                    info!("Rebooting as requested.");
+                    watchdog::restart(ResetReason::Requested);
+                }
+                Action::SendStatus => {
+                    let status = status::build(&state.config, &network::radio_state(&mut device));
+                    network::send_uplink(
+                        &mut device,
+                        &Uplink::Status(status),
+                        Delivery::Unconfirmed,
+                        &state.config,
+                    )
+                    .await;
+                }
+                #[cfg(not(feature = "abp"))]
+                Action::Rejoin => {
+                    info!("Rejoining as requested.");
+                    join_network(&mut device, &mut state).await;
+                }
             }
+
+            // If the network has not heard us for a while, it probably forgot our session. A single attempt per cycle,
+            // so the measurements go on (and are buffered) while the network is unreachable.
+            // With ABP, joining again would only reset the frame counters, which the network server would reject.
+            #[cfg(not(feature = "abp"))]
+            if link_monitor.rejoin_due() {
+                warn!("Link lost, joining again.");
+                if network::try_join(&mut device, &mut state).await == Ok(true) {
+                    link_monitor.rejoined();
+                }
+            }
+
+            if let Some(diagnostic) = crash_report.take() {
+                crash::send_report(&mut device, diagnostic, &state.config).await;
+            }
+
+            // Only sends something if the clock is due to be synchronized or the network asked for something
+            clock_sync.run(&mut device, &state.config).await;
+
+            // Answers the network's firmware update requests and gets ready for the fragments
+            fuota.run(&mut device, &state.config).await;
+
+            // Send the readings that got lost during a network outage
+            readings.catch_up(&mut device, &state.config).await;
+
+            // Once a day is over, its load profile is sent
+            load_profile
+                .send_completed_days(&mut device, &state.config)
+                .await;
+
+            // Commands received with these uplinks are followed up on after the next measurement
+            pending_action = commands::dispatch_received(
+                &mut clock_sync,
+                &mut fuota,
+                &mut state.config,
+                &mut pending_ack,
+            );
         }
 
         //-------------------- Update the values on the flash memory --------------
-        {
-            let mut counter_values_u64 = [0u64; S0_CHANNEL_COUNT];
-            for (i, counter) in S0_COUNTERS.iter().enumerate().take(S0_CHANNEL_COUNT) {
-                counter_values_u64[i] = counter.load(Ordering::Relaxed);
-            }
-            persistent_storage.write(CounterValues {
-                counts: counter_values_u64,
-            });
+        save_state(&mut state, &readings, &mut device);
+
+        // The bootloader swaps in a verified firmware update on restart
+        if fuota.update_ready() {
+            info!("Restarting into the new firmware.");
+            watchdog::restart(ResetReason::FirmwareUpdate);
         }
 
         // ----------- Sleep -------
         blinky::PERIOD.signal(Duration::from_millis(2000));
 
-        // Calculate a random delay that is added/subtracted from the sleep duration to prevent transmissions from syncing up and talking over eah other
+        // A random delay is added to the sleep duration to prevent transmissions from syncing up and talking over eah other
         let random = embassy_rp::clocks::RoscRng.next_u32();
-        let random_frac = random as f32 / u32::MAX as f32; // Range: 0-1
-        let random_duration =
-            Duration::from_micros((RANDOM_SLEEP_VARIATION.as_micros() as f32 * random_frac) as u64);
-        Timer::after(random_duration + MEASUREMENT_TRANSMIT_INTERVAL).await;
+        // All uplinks of this cycle count, to keep within the duty cycle
+        let sleep_duration = state.config.sleep_duration(airtime::take_cycle(), random);
+        // A multicast session (e.g. of a firmware update) is listened to until it ends, even if that takes longer
+        if let Some(session) = fuota.session_within(sleep_duration) {
+            class_c::listen_session(&mut device, session, &mut fuota).await;
+            continue;
+        }
+        #[cfg(not(feature = "class-c"))]
+        {
+            watchdog::check_in_after(Task::MainLoop, sleep_duration);
+            power::sleep(sleep_duration).await;
+        }
+        // In class C, the radio listens for downlinks in the meantime, so commands are applied right away
+        #[cfg(feature = "class-c")]
+        {
+            let action = class_c::listen(
+                &mut device,
+                sleep_duration,
+                &mut clock_sync,
+                &mut fuota,
+                &mut state.config,
+                &mut pending_ack,
+            )
+            .await;
+            if action != Action::None {
+                pending_action = action;
+            }
+        }
     }
 }
 
-/// Attempt to join the LoRa network, with an exponential backoff in case of join failure
-async fn join_network<R, C, T, G>(device: &mut Device<R, C, T, G>)
-where
+/// Saves the current counter values and LoRaWAN session to flash, along with the rest of the state and the readings
+fn save_state<R, C, T, G>(
+    state: &mut PersistentState,
+    readings: &ReadingBuffer,
+    device: &mut Device<R, C, T, G>,
+) where
     R: radio::PhyRxTx + Timings,
     T: radio::Timer,
     C: CryptoFactory + Default,
     G: RngCore,
 {
-    let mut join_attempt_count = 0;
-    loop {
-        info!(
-            "Joining LoRaWAN network, attempt {:?}",
-            join_attempt_count + 1
-        );
-        // Warning: These values should be unique pre device
-        // These are in the order that can be pasted into chirpstack/ttn, the EUIs will be reversed (to LSB)
-        // since this is what the rust code expects
-        const DEV_EUI: &str = include_str!("../device-config/DEV_EUI");
-        const APP_EUI: &str = include_str!("../device-config/APP_EUI");
-        const APP_KEY: &str = include_str!("../device-config/APP_KEY");
-
-        // The DEV_EUI and APP_EUI need to be reversed before putting them unto the device, since the default byte order differs
-        // The key does not need that, for some reason.
-        let mut dev_eui = decode_to_array(DEV_EUI).unwrap();
-        dev_eui.reverse();
-        let mut app_eui = decode_to_array(APP_EUI).unwrap();
-        app_eui.reverse();
-        let resp = device
-            .join(&JoinMode::OTAA {
-                deveui: DevEui::from(dev_eui),
-                appeui: AppEui::from(app_eui),
-                appkey: AppKey::from(decode_to_array(APP_KEY).unwrap()),
-            })
-            .await;
-
-        let join_success = match resp {
-            Ok(resp) => match resp {
-                JoinResponse::JoinSuccess => {
-                    info!("LoRa join request successfully accepted.");
-                    true
-                }
-                JoinResponse::NoJoinAccept => {
-                    info!("LoRa join request not acknowledged.");
-                    false
-                }
-            },
-            Err(e) => {
-                warn!("LoRa join request failed with unknown error!: {:?}", e);
-                false
-            }
-        };
-
-        if join_success {
-            break;
-        }
-        //Exponential backoff, up to 2048 seconds
-        // Start at 1, then 2, then 4 …
-        Timer::after_secs(2_u64.pow(join_attempt_count)).await;
-        if join_attempt_count < 11 {
-            join_attempt_count += 1
-        }
+    for (i, counter) in S0_COUNTERS.iter().enumerate().take(S0_CHANNEL_COUNT) {
+        state.counts[i] = counter.load(Ordering::Relaxed);
     }
+    network::update_stored_session(device, &mut state.session);
+    state.save();
+    readings.save();
 }
 
 #[embassy_executor::task(pool_size = S0_CHANNEL_COUNT)]
 async fn counter_task(mut input: Input<'static>, counter_index: usize) -> ! {
     let our_counter = &S0_COUNTERS[counter_index];
+    let task = Task::Counter(counter_index);
     // Wait a bit for any startup noise to be settled
     Timer::after(Duration::from_millis(10)).await;
     loop {
-        input.wait_for_high().await;
+        watchdog::check_in(task);
+        // Waking up regularly shows the supervisor we are still running, even without impulses
+        let interval = watchdog::COUNTER_CHECK_IN_INTERVAL;
+        if with_timeout(interval, input.wait_for_high()).await.is_err() {
+            continue;
+        }
         our_counter.fetch_add(1, Ordering::Relaxed);
-        input.wait_for_low().await;
-    }
-}
-
-async fn temperature(temp_chan: &mut AdcChannel<'static>, adc: &mut Adc<'static, Async>) -> f32 {
-    const SAMPLE_COUNT: usize = 10;
-
-    let mut temperature_results: [u16; SAMPLE_COUNT] = [0; SAMPLE_COUNT];
-    for temperature_result in temperature_results.iter_mut() {
-        *temperature_result = adc.read(temp_chan).await.unwrap();
-        //Sampling delay
-        Timer::after_millis(50).await;
+        alarm::impulse(counter_index);
+        // A contact stuck closed is no reason to restart
+        while with_timeout(interval, input.wait_for_low()).await.is_err() {
+            watchdog::check_in(task);
+        }
     }
-    let temperature_result = median(&mut temperature_results);
-    let temperature = convert_to_celsius(temperature_result);
-    info!("temperature: {:?}", temperature);
-
-    temperature
 }
 
 /// Calcualtes the median by sorting the array and taking the middle value
@@ -410,10 +741,10 @@ where
     buf[index_of_middle]
 }
 
-fn convert_to_celsius(raw_temp: u16) -> f32 {
-    // According to chapter 4.9.5. Temperature Sensor in RP2040 datasheet
-    let temp = 27.0 - (raw_temp as f32 * 3.3 / 4096.0 - 0.706) / 0.001721;
-    let sign = if temp < 0.0 { -1.0 } else { 1.0 };
-    let rounded_temp_x10: i16 = ((temp * 10.0) + 0.5 * sign) as i16;
-    (rounded_temp_x10 as f32) / 10.0
+// The ADC's full scale, 12 bits
+const ADC_RANGE: u64 = 4096;
+
+/// The voltage an ADC sample stands for, in µV, with the ADC reference configured for the board
+fn adc_microvolts(raw: u16, config: &DeviceConfig) -> u32 {
+    (raw as u64 * config.adc_reference_mv as u64 * 1000 / ADC_RANGE) as u32
 }
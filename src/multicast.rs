@@ -0,0 +1,269 @@
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+use lorawan::keys::AES128;
+use lorawan::parser::{self, DataHeader, DataPayload, FRMPayload, PhyPayload};
+use lorawan_device::async_device::radio::RxConfig;
+use powermeter_payload::{
+    McClassCSessionStatus, McGroupAddress, MulticastDownlink, MulticastUplink, MAX_MULTICAST_GROUPS,
+};
+
+use crate::{clock, lorawan_region};
+
+/// A time during which the downlinks of a multicast group are sent, which the device receives in class C
+#[derive(Clone, Copy)]
+pub struct ClassCSession {
+    pub group_id: u8,
+    pub start: Instant,
+    pub end: Instant,
+    pub rx_config: RxConfig,
+}
+
+struct Group {
+    address: u32,
+    nwkskey: [u8; 16],
+    appskey: [u8; 16],
+    max_fcount: u32,
+    next_fcount: u32, // The lowest frame counter a downlink may still have
+}
+
+/// The multicast groups and class C sessions set up by the network, using the LoRaWAN Remote Multicast Setup package.
+/// The message format is defined in the powermeter-payload crate.
+#[derive(Default)]
+pub struct Multicast {
+    groups: [Option<Group>; MAX_MULTICAST_GROUPS],
+    session: Option<ClassCSession>,
+    answers: [Option<MulticastUplink>; 4],
+}
+
+impl Multicast {
+    /// Handles a downlink received on the multicast setup FPort. Answers are sent with [`Self::encode_answers`].
+    pub fn handle_downlink(&mut self, payload: &[u8]) {
+        for message in MulticastDownlink::decode(payload) {
+            let answer = match message {
+                Ok(MulticastDownlink::PackageVersionReq) => MulticastUplink::PackageVersionAns,
+                Ok(MulticastDownlink::McGroupStatusReq { group_mask }) => {
+                    let mut groups = [None; MAX_MULTICAST_GROUPS];
+                    for (id, group) in self.groups.iter().enumerate() {
+                        if let Some(group) = group.as_ref().filter(|_| group_mask & (1 << id) != 0)
+                        {
+                            groups[id] = Some(McGroupAddress {
+                                group_id: id as u8,
+                                address: group.address,
+                            });
+                        }
+                    }
+                    MulticastUplink::McGroupStatusAns {
+                        total_groups: self.groups.iter().flatten().count() as u8,
+                        groups,
+                    }
+                }
+                Ok(MulticastDownlink::McGroupSetupReq {
+                    group_id,
+                    address,
+                    encrypted_key,
+                    min_fcount,
+                    max_fcount,
+                }) => {
+                    let group =
+                        group_keys(&encrypted_key, address).map(|(nwkskey, appskey)| Group {
+                            address,
+                            nwkskey,
+                            appskey,
+                            max_fcount,
+                            next_fcount: min_fcount,
+                        });
+                    let id_error = group.is_none();
+                    if group.is_some() {
+                        info!("Joined multicast group {:?}", group_id);
+                        self.groups[group_id as usize] = group;
+                    }
+                    MulticastUplink::McGroupSetupAns { group_id, id_error }
+                }
+                Ok(MulticastDownlink::McGroupDeleteReq { group_id }) => {
+                    let group_undefined = self.groups[group_id as usize].is_none();
+                    self.groups[group_id as usize] = None;
+                    if self
+                        .session
+                        .is_some_and(|session| session.group_id == group_id)
+                    {
+                        self.session = None;
+                    }
+                    MulticastUplink::McGroupDeleteAns {
+                        group_id,
+                        group_undefined,
+                    }
+                }
+                Ok(MulticastDownlink::McClassCSessionReq {
+                    group_id,
+                    session_time,
+                    timeout,
+                    frequency_hz,
+                    datarate,
+                }) => match self.schedule_session(
+                    group_id,
+                    session_time,
+                    timeout,
+                    frequency_hz,
+                    datarate,
+                ) {
+                    Some(answer) => answer,
+                    None => continue,
+                },
+                Err(e) => {
+                    warn!("Invalid multicast setup downlink: {:?}", e);
+                    continue;
+                }
+            };
+            match self.answers.iter_mut().find(|answer| answer.is_none()) {
+                Some(slot) => *slot = Some(answer),
+                None => warn!("Too many multicast setup requests, dropping the answer."),
+            }
+        }
+    }
+
+    /// Returns the answer to the request, if one can be given
+    fn schedule_session(
+        &mut self,
+        group_id: u8,
+        session_time: u32,
+        timeout: u8,
+        frequency_hz: u32,
+        datarate: u8,
+    ) -> Option<MulticastUplink> {
+        let rx_config = lorawan_region::downlink_rx_config(frequency_hz, datarate);
+        let status = McClassCSessionStatus {
+            datarate_error: rx_config.is_none(),
+            frequency_error: false,
+            group_undefined: self.groups[group_id as usize].is_none(),
+        };
+        let (Some(rx_config), true) = (rx_config, status.is_ok()) else {
+            return Some(MulticastUplink::McClassCSessionAns {
+                group_id,
+                status,
+                time_to_start: None,
+            });
+        };
+        // The session time is only meaningful with a synchronized clock. The network repeats the request if it gets
+        // no answer, by then the clock sync will probably have succeeded.
+        let Some(now) = clock::gps_now() else {
+            warn!("Cannot schedule a multicast session before the clock is synchronized.");
+            return None;
+        };
+        // The session time is the GPS time modulo 2^32
+        let time_to_start = session_time.wrapping_sub(now as u32);
+        let time_to_start = if time_to_start > u32::MAX / 2 {
+            0
+        } else {
+            time_to_start
+        };
+        let start = Instant::now() + Duration::from_secs(time_to_start as u64);
+        info!(
+            "Multicast session of group {:?} starts in {:?} s",
+            group_id, time_to_start
+        );
+        self.session = Some(ClassCSession {
+            group_id,
+            start,
+            end: start + Duration::from_secs(1 << timeout),
+            rx_config,
+        });
+        Some(MulticastUplink::McClassCSessionAns {
+            group_id,
+            status,
+            time_to_start: Some(time_to_start),
+        })
+    }
+
+    /// The class C session that starts within the given time or is still running, if any
+    pub fn session_within(&mut self, duration: Duration) -> Option<ClassCSession> {
+        let session = self.session?;
+        if session.end <= Instant::now() {
+            self.session = None;
+            return None;
+        }
+        Some(session).filter(|session| session.start <= Instant::now() + duration)
+    }
+
+    /// Checks and decrypts a frame received during a class C session of the given group. Returns its FPort and the
+    /// size of the application payload written into `payload`, or `None` if it is not a new downlink of the group.
+    pub fn receive(
+        &mut self,
+        group_id: u8,
+        frame: &mut [u8],
+        payload: &mut [u8],
+    ) -> Option<(u8, usize)> {
+        let group = self.groups.get_mut(group_id as usize)?.as_mut()?;
+        let Ok(PhyPayload::Data(DataPayload::Encrypted(encrypted))) = parser::parse(frame) else {
+            return None;
+        };
+        if encrypted.is_uplink()
+            || encrypted.fhdr().dev_addr().as_ref() != group.address.to_le_bytes()
+        {
+            return None;
+        }
+        // Only the lower 16 bits of the frame counter are sent, the upper ones follow from the last downlink
+        let mut fcnt = (group.next_fcount & !0xFFFF) | encrypted.fhdr().fcnt() as u32;
+        if fcnt < group.next_fcount {
+            fcnt = fcnt.wrapping_add(0x1_0000);
+        }
+        let (nwkskey, appskey) = (AES128(group.nwkskey), AES128(group.appskey));
+        if fcnt > group.max_fcount || !encrypted.validate_mic(&nwkskey, fcnt) {
+            return None;
+        }
+        group.next_fcount = fcnt.saturating_add(1);
+        let decrypted = encrypted.decrypt(None, Some(&appskey), fcnt).ok()?;
+        let fport = decrypted.f_port().filter(|fport| *fport != 0)?;
+        let FRMPayload::Data(data) = decrypted.frm_payload() else {
+            return None;
+        };
+        let size = data.len().min(payload.len());
+        payload[..size].copy_from_slice(&data[..size]);
+        Some((fport, size))
+    }
+
+    /// Writes the answers to the requests received since the last call into the buffer, returning the number of bytes
+    /// used
+    pub fn encode_answers(&mut self, buf: &mut [u8]) -> usize {
+        let mut size = 0;
+        for answer in self.answers.iter_mut().filter_map(Option::take) {
+            size += answer.encode(&mut buf[size..]).unwrap();
+        }
+        size
+    }
+}
+
+/// The network and application session keys of a multicast group, derived from its encrypted key and address
+#[cfg(not(feature = "abp"))]
+fn group_keys(encrypted_key: &[u8; 16], address: u32) -> Option<([u8; 16], [u8; 16])> {
+    // For LoRaWAN 1.0.x devices, the multicast root key is derived from the AppKey
+    let root_key = encrypt(&crate::network::app_key(), [0; 16]);
+    let key_encryption_key = encrypt(&root_key, [0; 16]);
+    // The network encrypts the key by decrypting it, so we encrypt to get it back
+    let key = encrypt(&key_encryption_key, *encrypted_key);
+
+    let mut block = [0; 16];
+    block[1..5].copy_from_slice(&address.to_le_bytes());
+    block[0] = 0x01;
+    let appskey = encrypt(&key, block);
+    block[0] = 0x02;
+    let nwkskey = encrypt(&key, block);
+    Some((nwkskey, appskey))
+}
+
+/// Without an AppKey, there is no root key to derive the multicast keys from
+#[cfg(feature = "abp")]
+fn group_keys(_encrypted_key: &[u8; 16], _address: u32) -> Option<([u8; 16], [u8; 16])> {
+    warn!("Multicast groups can only be set up for OTAA devices.");
+    None
+}
+
+#[cfg(not(feature = "abp"))]
+fn encrypt(key: &[u8; 16], mut block: [u8; 16]) -> [u8; 16] {
+    use lorawan::keys::{CryptoFactory, Encrypter};
+    use lorawan_device::default_crypto::DefaultFactory;
+
+    DefaultFactory
+        .new_enc(&AES128(*key))
+        .encrypt_block((&mut block[..]).into());
+    block
+}
@@ -0,0 +1,465 @@
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+
+use bincode::{Decode, Encode};
+use const_hex::decode_to_array;
+use defmt::{error, info, warn, Format};
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use lorawan_device::async_device::{radio, Device, JoinMode, JoinResponse, SendResponse, Timings};
+use lorawan_device::mac::Session;
+#[cfg(not(feature = "abp"))]
+use lorawan_device::{AppEui, AppKey, DevEui};
+use lorawan_device::{AppSKey, CryptoFactory, DevAddr, NewSKey, RngCore};
+use portable_atomic::{AtomicBool, AtomicU32};
+use powermeter_payload::{Uplink, MAX_PAYLOAD_SIZE, UPLINK_FPORT};
+
+use crate::commands;
+use crate::config::Config;
+use crate::flash_region::FlashRegion;
+use crate::policy::{self, Delivery};
+use crate::watchdog::{self, Task};
+use crate::{firmware, lorawan_region, mac, PersistentState};
+
+// The uplink frame counter is reserved this far ahead of the one actually used, in its own flash region (see memory.x).
+// This way we do not need to write the flash for every uplink, but still never reuse a frame counter (which the
+// network server would drop) after a reset.
+const FCNT_UP_WRITE_AHEAD: u32 = 32;
+// Before an uplink, a new reservation is written once fewer frame counters than this are left
+const FCNT_UP_MIN_HEADROOM: u32 = FCNT_UP_WRITE_AHEAD / 2;
+const FCNT_RESERVATION_SLOT_SIZE: usize = ERASE_SIZE;
+const _: () = assert!(
+    core::mem::size_of::<FcntReservation>()
+        <= FlashRegion::record_capacity(FCNT_RESERVATION_SLOT_SIZE)
+);
+
+extern "C" {
+    static __frame_counter_start: u32;
+    static __frame_counter_end: u32;
+}
+
+/// The first uplink frame counter of a session that is guaranteed to be unused
+#[derive(Clone, Copy, Encode, Decode)]
+struct FcntReservation {
+    devaddr: [u8; 4],
+    fcnt_up: u32,
+}
+
+// The reservation last written to flash
+static FCNT_RESERVATION: Mutex<ThreadModeRawMutex, RefCell<Option<FcntReservation>>> =
+    Mutex::new(RefCell::new(None));
+
+// The DevNonce to use for the next join request. Bit 16 marks that the lower 16 bits are valid and not yet used.
+const DEV_NONCE_PENDING: u32 = 1 << 16;
+static NEXT_DEV_NONCE: AtomicU32 = AtomicU32::new(0);
+// The DevNonce of the join request being sent, bit 16 set while there is one, and whether the stack built a join
+// request with another one, which is not sent
+static JOIN_DEV_NONCE: AtomicU32 = AtomicU32::new(0);
+static DEV_NONCE_MISMATCH: AtomicBool = AtomicBool::new(false);
+// A join request: the MAC header, JoinEUI, DevEUI, DevNonce (little endian) and MIC
+const JOIN_REQUEST_SIZE: usize = 23;
+const JOIN_REQUEST_DEV_NONCE: usize = 17;
+const MTYPE_MASK: u8 = 0xE0;
+const MTYPE_JOIN_REQUEST: u8 = 0x00;
+
+// The first firmware drew its DevNonces at random, so any of them may be the last one the network server accepted.
+// Until a join is accepted, the DevNonce goes up in steps this large from the top of the first step on, so the 16th
+// try is above all of them. Afterwards it counts up by one from the accepted one.
+const DEV_NONCE_PROBE_STEP: u16 = 0x1000;
+pub const FIRST_PROBED_DEV_NONCE: u16 = DEV_NONCE_PROBE_STEP - 1;
+
+/// The random number generator handed to the LoRaWAN stack.
+/// lorawan-device takes the DevNonce of a join request from the first random number it draws for that join. Since
+/// LoRaWAN 1.0.4 network servers reject DevNonces that are not increasing, we hand out our persisted counter
+/// at that point instead of a random value. All other requests are answered by the ring oscillator.
+///
+/// This relies on lorawan-device 0.12.2 (pinned in Cargo.toml): `Mac::join_otaa` calls `Otaa::prepare_buffer`, which
+/// draws the DevNonce with `next_u32` before `create_tx_config` draws the channel. In case another version draws
+/// something else first, [`join_request_allowed`] keeps the join request from being sent.
+pub struct DevNonceRng;
+
+impl RngCore for DevNonceRng {
+    fn next_u32(&mut self) -> u32 {
+        let pending = NEXT_DEV_NONCE.swap(0, Ordering::Relaxed);
+        if pending & DEV_NONCE_PENDING != 0 {
+            pending & 0xFFFF
+        } else {
+            RoscRng.next_u32()
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RoscRng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RoscRng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        RoscRng.try_fill_bytes(dest)
+    }
+}
+
+/// Whether the frame is not a join request, or one with the DevNonce persisted for it. Any other DevNonce could be one
+/// that was used before, which the network server rejects, or skip ahead of the persisted one.
+pub fn join_request_allowed(frame: &[u8]) -> bool {
+    if frame.len() != JOIN_REQUEST_SIZE || frame[0] & MTYPE_MASK != MTYPE_JOIN_REQUEST {
+        return true;
+    }
+    let expected = JOIN_DEV_NONCE.load(Ordering::Relaxed);
+    let dev_nonce = u16::from_le_bytes([
+        frame[JOIN_REQUEST_DEV_NONCE],
+        frame[JOIN_REQUEST_DEV_NONCE + 1],
+    ]);
+    if expected == DEV_NONCE_PENDING | dev_nonce as u32 {
+        return true;
+    }
+    DEV_NONCE_MISMATCH.store(true, Ordering::Relaxed);
+    false
+}
+
+/// The stack built a join request with another DevNonce than the persisted one, so it was not sent
+#[derive(Format, PartialEq)]
+pub struct DevNonceMismatch;
+
+/// The parts of a LoRaWAN session that need to survive a reboot, so we don't have to join again
+#[derive(Clone, Encode, Decode)]
+pub struct StoredSession {
+    nwkskey: [u8; 16],
+    appskey: [u8; 16],
+    devaddr: [u8; 4],
+    fcnt_up: u32, // Not the last one used, but the first one that is guaranteed to be unused
+    fcnt_down: u32,
+}
+
+impl StoredSession {
+    fn from_session(session: &Session) -> Self {
+        let mut devaddr = [0u8; 4];
+        devaddr.copy_from_slice(session.devaddr.as_ref());
+        Self {
+            nwkskey: session.newskey.inner().0,
+            appskey: session.appskey.inner().0,
+            devaddr,
+            fcnt_up: session.fcnt_up.saturating_add(FCNT_UP_WRITE_AHEAD),
+            fcnt_down: session.fcnt_down,
+        }
+    }
+
+    /// Turns the stored values back into a session the device can use. The uplink frame counter continues from the
+    /// reserved value, skipping the ones that might have been used before the reset.
+    pub fn restore(&self) -> Session {
+        let mut session = Session::new(
+            NewSKey::from(self.nwkskey),
+            AppSKey::from(self.appskey),
+            DevAddr::from(self.devaddr),
+        );
+        session.fcnt_up = self.fcnt_up;
+        session.fcnt_down = self.fcnt_down;
+        session
+    }
+}
+
+fn fcnt_reservation_region() -> FlashRegion {
+    unsafe {
+        FlashRegion::new(
+            &__frame_counter_start,
+            &__frame_counter_end,
+            FCNT_RESERVATION_SLOT_SIZE,
+        )
+    }
+}
+
+/// Writes a new frame counter reservation to flash if the uplink about to be sent would use up most of the current one,
+/// so no frame counter is used again after a reset. Has to be called before every uplink.
+pub fn reserve_fcnt_up(session: Option<&Session>) {
+    let Some(session) = session else {
+        return;
+    };
+    let mut devaddr = [0u8; 4];
+    devaddr.copy_from_slice(session.devaddr.as_ref());
+    let reserved = FCNT_RESERVATION.lock(|reservation| *reservation.borrow());
+    if reserved.is_some_and(|reserved| {
+        reserved.devaddr == devaddr
+            && reserved.fcnt_up.saturating_sub(session.fcnt_up) >= FCNT_UP_MIN_HEADROOM
+    }) {
+        return;
+    }
+    let reservation = FcntReservation {
+        devaddr,
+        fcnt_up: session.fcnt_up.saturating_add(FCNT_UP_WRITE_AHEAD),
+    };
+    fcnt_reservation_region().save(&reservation);
+    FCNT_RESERVATION.lock(|reserved| *reserved.borrow_mut() = Some(reservation));
+}
+
+/// Compares the device's current session with the stored one and updates the stored one if necessary.
+/// Returns true if it has changed and should be written to flash.
+pub fn update_stored_session<R, C, T, G>(
+    device: &mut Device<R, C, T, G>,
+    stored: &mut Option<StoredSession>,
+) -> bool
+where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    match device.get_session() {
+        None => stored.take().is_some(),
+        Some(session) => {
+            let needs_update = match stored {
+                None => true,
+                // A new session (after a rejoin) or the reserved frame counters are used up
+                Some(stored) => {
+                    stored.devaddr != session.devaddr.as_ref() || session.fcnt_up >= stored.fcnt_up
+                }
+            };
+            if needs_update {
+                *stored = Some(StoredSession::from_session(session));
+            }
+            needs_update
+        }
+    }
+}
+
+/// Sends a message outside of the regular measurements, e.g. a status report. Downlinks received in response are kept
+/// for [`commands::dispatch_received`].
+pub async fn send_uplink<R, C, T, G>(
+    device: &mut Device<R, C, T, G>,
+    uplink: &Uplink,
+    delivery: Delivery,
+    config: &Config,
+) where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    let mut buf = [0u8; MAX_PAYLOAD_SIZE];
+    let size = uplink.encode(&mut buf).unwrap();
+    if size + mac::pending_fopts_len() > lorawan_region::max_payload(device.get_datarate()) {
+        warn!("Uplink does not fit the current data rate, dropping it.");
+        return;
+    }
+    if let Some(resp) = policy::send(device, &buf[..size], UPLINK_FPORT, delivery, config).await {
+        info!("Sending okay: {:?}", resp);
+        if let SendResponse::DownlinkReceived(_) = resp {
+            commands::receive(device);
+        }
+    }
+}
+
+/// The radio settings currently used for uplinks and how well the last downlink was received, for the status message
+pub struct RadioState {
+    pub datarate: u8,
+    pub tx_power_dbm: i8,
+    pub rssi_dbm: Option<i16>,
+    pub snr_db: Option<i8>,
+}
+
+pub fn radio_state<R, C, T, G>(device: &mut Device<R, C, T, G>) -> RadioState
+where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    let last_downlink = mac::last_rx_quality();
+    RadioState {
+        datarate: device.get_datarate() as u8,
+        tx_power_dbm: mac::tx_power_dbm(),
+        rssi_dbm: last_downlink.map(|quality| quality.rssi()),
+        snr_db: last_downlink.map(|quality| quality.snr()),
+    }
+}
+
+/// The session that should be used on boot instead of joining, if any
+pub fn restorable_session(stored: &Option<StoredSession>) -> Option<Session> {
+    let stored = stored.as_ref()?;
+    // With ABP, the keys might have been changed in the device config since the session was stored. In this case,
+    // the old session is useless.
+    #[cfg(feature = "abp")]
+    {
+        let (nwkskey, appskey, devaddr) = abp_keys();
+        if stored.nwkskey != nwkskey || stored.appskey != appskey || stored.devaddr != devaddr {
+            info!("ABP keys changed, discarding the stored session.");
+            return None;
+        }
+    }
+    let mut session = stored.restore();
+    // The uplinks since the session was stored are covered by the frame counter reservation
+    let reservation = fcnt_reservation_region().load::<FcntReservation>();
+    if let Some(reservation) =
+        reservation.filter(|reservation| reservation.devaddr == stored.devaddr)
+    {
+        session.fcnt_up = session.fcnt_up.max(reservation.fcnt_up);
+    }
+    FCNT_RESERVATION.lock(|reserved| *reserved.borrow_mut() = reservation);
+    Some(session)
+}
+
+/// Attempt to join the LoRa network, with an exponential backoff in case of join failure.
+/// Every join request uses a new DevNonce, which is written to flash before the request is sent.
+pub async fn join_network<R, C, T, G>(device: &mut Device<R, C, T, G>, state: &mut PersistentState)
+where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    let mut join_attempt_count = 0;
+    loop {
+        info!(
+            "Joining LoRaWAN network, attempt {:?}",
+            join_attempt_count + 1
+        );
+        match try_join(device, state).await {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(DevNonceMismatch) => {
+                error!("The LoRaWAN stack chose the DevNonce itself, check the lorawan-device version!")
+            }
+        }
+        //Exponential backoff, up to 2048 seconds
+        // Start at 1, then 2, then 4 …
+        let backoff = Duration::from_secs(2_u64.pow(join_attempt_count));
+        watchdog::check_in_after(Task::MainLoop, backoff);
+        Timer::after(backoff).await;
+        if join_attempt_count < 11 {
+            join_attempt_count += 1
+        }
+    }
+}
+
+/// A single join request. Returns whether it was accepted.
+pub async fn try_join<R, C, T, G>(
+    device: &mut Device<R, C, T, G>,
+    state: &mut PersistentState,
+) -> Result<bool, DevNonceMismatch>
+where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    let join_mode = join_mode(state);
+    watchdog::check_in(Task::Radio);
+    let resp = device.join(&join_mode).await;
+    watchdog::idle(Task::Radio);
+    // In case the stack did not draw a random number, the nonce must not end up somewhere else
+    NEXT_DEV_NONCE.store(0, Ordering::Relaxed);
+    let dev_nonce = JOIN_DEV_NONCE.swap(0, Ordering::Relaxed);
+    if DEV_NONCE_MISMATCH.swap(false, Ordering::Relaxed) {
+        return Err(DevNonceMismatch);
+    }
+
+    let join_success = match resp {
+        Ok(resp) => match resp {
+            JoinResponse::JoinSuccess => {
+                info!("LoRa join request successfully accepted.");
+                true
+            }
+            JoinResponse::NoJoinAccept => {
+                info!("LoRa join request not acknowledged.");
+                false
+            }
+        },
+        Err(e) => {
+            warn!("LoRa join request failed with unknown error!: {:?}", e);
+            false
+        }
+    };
+
+    if join_success {
+        if state.dev_nonce_probing && dev_nonce & DEV_NONCE_PENDING != 0 {
+            info!("DevNonce found, counting up from here.");
+            state.dev_nonce = (dev_nonce as u16).wrapping_add(1);
+            state.dev_nonce_probing = false;
+            state.save();
+        }
+        firmware::network_answered();
+        // The join accept may have changed the data rate, so we start on the one known to fit our messages
+        device.set_datarate(lorawan_region::DEFAULT_DATARATE);
+    }
+    Ok(join_success)
+}
+
+/// Over-the-air activation with the keys from the device config. Every join request uses a new DevNonce.
+#[cfg(not(feature = "abp"))]
+fn join_mode(state: &mut PersistentState) -> JoinMode {
+    // Warning: These values should be unique pre device
+    // These are in the order that can be pasted into chirpstack/ttn, the EUIs will be reversed (to LSB)
+    // since this is what the rust code expects
+    const DEV_EUI: &str = include_str!("../device-config/DEV_EUI");
+    const APP_EUI: &str = include_str!("../device-config/APP_EUI");
+
+    // The DEV_EUI and APP_EUI need to be reversed before putting them unto the device, since the default byte order differs
+    // The key does not need that, for some reason.
+    let mut dev_eui = decode_to_array(DEV_EUI).unwrap();
+    dev_eui.reverse();
+    let mut app_eui = decode_to_array(APP_EUI).unwrap();
+    app_eui.reverse();
+
+    // The DevNonce must never be reused, so it is persisted before it goes over the air
+    let dev_nonce = state.dev_nonce;
+    if state.dev_nonce_probing {
+        // After the last probe, it wraps around to the first one
+        state.dev_nonce = dev_nonce.wrapping_add(DEV_NONCE_PROBE_STEP);
+    } else {
+        state.dev_nonce = dev_nonce.wrapping_add(1);
+        if state.dev_nonce == 0 {
+            warn!("DevNonces exhausted, the device needs a new APP_KEY to be accepted by the network server!");
+        }
+    }
+    state.save();
+    NEXT_DEV_NONCE.store(DEV_NONCE_PENDING | dev_nonce as u32, Ordering::Relaxed);
+    JOIN_DEV_NONCE.store(DEV_NONCE_PENDING | dev_nonce as u32, Ordering::Relaxed);
+    info!("Using DevNonce {:?}", dev_nonce);
+
+    JoinMode::OTAA {
+        deveui: DevEui::from(dev_eui),
+        appeui: AppEui::from(app_eui),
+        appkey: AppKey::from(app_key()),
+    }
+}
+
+/// The root key of the device from the device config, which the multicast keys are derived from as well
+#[cfg(not(feature = "abp"))]
+pub fn app_key() -> [u8; 16] {
+    const APP_KEY: &str = include_str!("../device-config/APP_KEY");
+    decode_to_array(APP_KEY).unwrap()
+}
+
+/// Activation by personalization with the session keys from the device config.
+/// This starts the frame counters at zero, so it should only be used if there is no stored session to continue.
+#[cfg(feature = "abp")]
+fn join_mode(_state: &mut PersistentState) -> JoinMode {
+    let (nwkskey, appskey, devaddr) = abp_keys();
+    JoinMode::ABP {
+        newskey: NewSKey::from(nwkskey),
+        appskey: AppSKey::from(appskey),
+        devaddr: DevAddr::from(devaddr),
+    }
+}
+
+/// The ABP session keys and device address from the device config, in the byte order the stack expects
+#[cfg(feature = "abp")]
+fn abp_keys() -> ([u8; 16], [u8; 16], [u8; 4]) {
+    // Warning: These values should be unique pre device
+    // These are in the order that can be pasted into chirpstack/ttn, the address will be reversed (to LSB)
+    const DEV_ADDR: &str = include_str!("../device-config/DEV_ADDR");
+    const NWK_S_KEY: &str = include_str!("../device-config/NWK_S_KEY");
+    const APP_S_KEY: &str = include_str!("../device-config/APP_S_KEY");
+
+    let mut devaddr = decode_to_array(DEV_ADDR).unwrap();
+    devaddr.reverse();
+    (
+        decode_to_array(NWK_S_KEY).unwrap(),
+        decode_to_array(APP_S_KEY).unwrap(),
+        devaddr,
+    )
+}
@@ -0,0 +1,169 @@
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant, Timer};
+use lorawan_device::async_device::{radio, Device, SendResponse, Timings};
+use lorawan_device::{CryptoFactory, RngCore};
+use portable_atomic::AtomicU32;
+
+use crate::config::Config;
+use crate::watchdog::{self, Task};
+use crate::{airtime, firmware, lorawan_region, mac, network};
+
+// After this many confirmed uplinks in a row were not acknowledged, the data rate is lowered by one step. ADR may have
+// raised it further than the link allows by now, e.g. because a gateway went offline.
+const DATARATE_STEPDOWN_AFTER: u32 = 3;
+
+// Link quality, counted since boot
+static CONFIRMED_UPLINKS: AtomicU32 = AtomicU32::new(0);
+static UNACKNOWLEDGED_UPLINKS: AtomicU32 = AtomicU32::new(0);
+static CONSECUTIVE_NO_ACKS: AtomicU32 = AtomicU32::new(0);
+
+/// How an uplink is sent. Which messages are sent how:
+///   measurement        unconfirmed, confirmed when the buffered readings need an acknowledgement, retried when it
+///                      carries a command acknowledgement
+///   backlog            confirmed, the readings stay buffered if it is not acknowledged
+///   load profile       retried
+///   diagnostic         retried
+///   alarm              retried, the alarms are sent again after the holdoff if it is not acknowledged
+///   status, clock sync unconfirmed
+#[derive(Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Unconfirmed,
+    /// Confirmed, but not repeated if the acknowledgement is missing
+    Confirmed,
+    /// Confirmed and repeated (with increasing delays) until it is acknowledged, as often as configured
+    Retried,
+}
+
+/// The confirmed uplinks sent since boot and how many of them were not acknowledged
+pub struct LinkQuality {
+    pub confirmed_uplinks: u32,
+    pub unacknowledged_uplinks: u32,
+    #[cfg(not(feature = "abp"))]
+    pub consecutive_no_acks: u32,
+}
+
+pub fn link_quality() -> LinkQuality {
+    LinkQuality {
+        confirmed_uplinks: CONFIRMED_UPLINKS.load(Ordering::Relaxed),
+        unacknowledged_uplinks: UNACKNOWLEDGED_UPLINKS.load(Ordering::Relaxed),
+        #[cfg(not(feature = "abp"))]
+        consecutive_no_acks: CONSECUTIVE_NO_ACKS.load(Ordering::Relaxed),
+    }
+}
+
+/// Starts counting consecutive missing acknowledgements over, e.g. after a rejoin
+pub fn reset_consecutive_no_acks() {
+    CONSECUTIVE_NO_ACKS.store(0, Ordering::Relaxed);
+}
+
+/// Sends the uplink as the delivery requires. Returns the response to the last attempt, or `None` if sending failed or
+/// the airtime budget does not allow it.
+///
+/// Each repetition is a new uplink with a new frame counter, not a retransmission of the same frame as LoRaWAN 1.0.4
+/// §4.3.1.1 specifies. The network server sees them as separate messages (the backend has to ignore duplicates), and
+/// each missing acknowledgement counts on its own towards lowering the data rate.
+///
+/// The repetitions stop before the next measurement is due, since the main loop does nothing else while waiting for
+/// them. The caller then gets `NoAck` as if all of them had gone unanswered.
+pub async fn send<R, C, T, G>(
+    device: &mut Device<R, C, T, G>,
+    payload: &[u8],
+    fport: u8,
+    delivery: Delivery,
+    config: &Config,
+) -> Option<SendResponse>
+where
+    R: radio::PhyRxTx + Timings,
+    T: radio::Timer,
+    C: CryptoFactory + Default,
+    G: RngCore,
+{
+    let confirmed = delivery != Delivery::Unconfirmed;
+    let retries = match delivery {
+        Delivery::Retried => config.confirmed_retries,
+        _ => 0,
+    };
+
+    let next_measurement =
+        Instant::now() + Duration::from_secs(config.measurement_interval_s as u64);
+    let mut backoff = Duration::from_secs(config.retry_backoff_s as u64);
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            if Instant::now() + backoff > next_measurement {
+                warn!("Next measurement due before the next repetition, not retrying.");
+                break;
+            }
+            info!("Retrying in {:?} s", backoff.as_secs());
+            watchdog::check_in_after(Task::MainLoop, backoff);
+            Timer::after(backoff).await;
+            backoff *= 2;
+            // The data rate may have been lowered since the message was built. Like the airtime, with the MAC commands
+            // that go along.
+            if payload.len() + mac::pending_fopts_len()
+                > lorawan_region::max_payload(device.get_datarate())
+            {
+                warn!("Uplink does not fit the current data rate any more, not retrying.");
+                break;
+            }
+        }
+        let airtime = airtime::time_on_air(
+            device.get_datarate(),
+            payload.len() + mac::pending_fopts_len(),
+        );
+        if airtime > airtime::remaining(config.airtime_budget()) {
+            warn!("Airtime budget used up, not sending.");
+            if attempt == 0 {
+                return None;
+            }
+            break;
+        }
+        airtime::record(airtime);
+        network::reserve_fcnt_up(device.get_session());
+        mac::before_send(device.get_session());
+        watchdog::check_in(Task::Radio);
+        let resp = device.send(payload, fport, confirmed).await;
+        watchdog::idle(Task::Radio);
+        // lorawan-device only applies the channel mask of a LinkADRReq
+        if let Some(datarate) = mac::take_adr_datarate() {
+            info!("ADR: data rate DR{:?}", datarate as u8);
+            device.set_datarate(datarate);
+        }
+        if mac::take_adr_backoff() {
+            let datarate = lorawan_region::slower_datarate(device.get_datarate());
+            info!("ADR backoff: data rate DR{:?}", datarate as u8);
+            device.set_datarate(datarate);
+        }
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!("Unexpected error! {:?}", e);
+                return None;
+            }
+        };
+        if !confirmed {
+            return Some(resp);
+        }
+
+        CONFIRMED_UPLINKS.add(1, Ordering::Relaxed);
+        match resp {
+            SendResponse::NoAck => {
+                UNACKNOWLEDGED_UPLINKS.add(1, Ordering::Relaxed);
+                let consecutive = CONSECUTIVE_NO_ACKS.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("No acknowledgement received ({:?} in a row).", consecutive);
+                if consecutive.is_multiple_of(DATARATE_STEPDOWN_AFTER) {
+                    let datarate = lorawan_region::slower_datarate(device.get_datarate());
+                    info!("Lowering the data rate to DR{:?}", datarate as u8);
+                    device.set_datarate(datarate);
+                }
+            }
+            resp => {
+                CONSECUTIVE_NO_ACKS.store(0, Ordering::Relaxed);
+                firmware::network_answered();
+                return Some(resp);
+            }
+        }
+    }
+    Some(SendResponse::NoAck)
+}
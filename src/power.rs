@@ -0,0 +1,100 @@
+#[cfg(feature = "battery")]
+use cortex_m::Peripherals as CorePeripherals;
+use embassy_rp::config::Config;
+#[cfg(all(feature = "battery", feature = "pico_w"))]
+use embassy_rp::gpio::{Level, Output};
+#[cfg(feature = "battery")]
+use embassy_rp::pac;
+#[cfg(all(feature = "battery", feature = "pico_w"))]
+use embassy_rp::peripherals::PIN_23;
+use embassy_time::{Duration, Timer};
+
+// Class C keeps the receiver on (about 5 mA), which empties a battery within weeks
+#[cfg(all(feature = "battery", feature = "class-c"))]
+compile_error!("The battery and class-c features can't be combined. Choose one.");
+
+// On battery, the system clock is divided down from the 125 MHz of the PLL. Plenty for reading the meter and running
+// the LoRaWAN stack, the serial port and SPI derive their baud rates from whatever the clock ends up at.
+#[cfg(feature = "battery")]
+const SYS_CLOCK_DIVIDER: u32 = 4;
+
+/// The clock configuration, reduced when running on battery
+pub fn config() -> Config {
+    #[allow(unused_mut)]
+    let mut config = Config::default();
+    #[cfg(feature = "battery")]
+    {
+        config.clocks.sys_clk.div_int = SYS_CLOCK_DIVIDER;
+    }
+    config
+}
+
+/// Keeps the CYW43 powered off. On the Pico W, it only drives the LED, which is not worth the power on battery.
+#[cfg(all(feature = "battery", feature = "pico_w"))]
+pub fn power_off_cyw43(wl_on: PIN_23) {
+    // Held low until the next reset
+    core::mem::forget(Output::new(wl_on, Level::Low));
+}
+
+/// Waits between two measurement cycles. On battery, the processor sleeps in the meantime with the clocks of all
+/// peripherals stopped, except those of the timer (which wakes us up), the GPIOs (so the S0 counters' edge interrupts
+/// still wake their tasks) and the watchdog. The LoRaWAN stack has put the radio to sleep after the last receive window
+/// already.
+pub async fn sleep(duration: Duration) {
+    #[cfg(feature = "battery")]
+    let _deep_sleep = DeepSleep::enter();
+    Timer::after(duration).await;
+}
+
+/// While it exists, the processor stops the clocks not needed to wake up whenever the executor waits for an interrupt.
+/// The other tasks still run as usual in between, with all clocks running.
+#[cfg(feature = "battery")]
+struct DeepSleep {
+    sleep_en0: pac::clocks::regs::SleepEn0,
+    sleep_en1: pac::clocks::regs::SleepEn1,
+}
+
+#[cfg(feature = "battery")]
+impl DeepSleep {
+    fn enter() -> Self {
+        let clocks = pac::CLOCKS;
+        let previous = Self {
+            sleep_en0: clocks.sleep_en0().read(),
+            sleep_en1: clocks.sleep_en1().read(),
+        };
+        clocks.sleep_en0().write(|w| {
+            w.0 = 0;
+            w.set_clk_sys_sram0(true);
+            w.set_clk_sys_sram1(true);
+            w.set_clk_sys_sram2(true);
+            w.set_clk_sys_sram3(true);
+            w.set_clk_sys_busfabric(true);
+            w.set_clk_sys_clocks(true);
+            w.set_clk_sys_io(true);
+            w.set_clk_sys_pads(true);
+            w.set_clk_sys_pll_sys(true);
+            w.set_clk_sys_vreg_and_chip_reset(true);
+        });
+        clocks.sleep_en1().write(|w| {
+            w.0 = 0;
+            w.set_clk_sys_sram4(true);
+            w.set_clk_sys_sram5(true);
+            w.set_clk_sys_timer(true);
+            w.set_clk_sys_watchdog(true);
+            w.set_clk_sys_xosc(true);
+        });
+        // SAFETY: Only the sleep configuration is changed, nothing else uses the system control block
+        unsafe { CorePeripherals::steal() }.SCB.set_sleepdeep();
+        previous
+    }
+}
+
+#[cfg(feature = "battery")]
+impl Drop for DeepSleep {
+    fn drop(&mut self) {
+        // SAFETY: As above
+        unsafe { CorePeripherals::steal() }.SCB.clear_sleepdeep();
+        pac::CLOCKS.sleep_en0().write_value(self.sleep_en0);
+        pac::CLOCKS.sleep_en1().write_value(self.sleep_en1);
+    }
+}
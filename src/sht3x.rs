@@ -0,0 +1,70 @@
+use defmt::warn;
+use embassy_rp::bind_interrupts;
+use embassy_rp::i2c::{self, Async, I2c, InterruptHandler};
+use embassy_rp::peripherals::{I2C0, PIN_4, PIN_5};
+use embassy_time::Timer;
+
+use crate::temperature::Climate;
+
+bind_interrupts!(struct Irqs {
+    I2C0_IRQ => InterruptHandler<I2C0>;
+});
+
+// With the ADDR pin low
+const ADDRESS: u16 = 0x44;
+// Single shot, high repeatability, without clock stretching
+const MEASURE: [u8; 2] = [0x24, 0x00];
+const MEASUREMENT_TIME_MS: u64 = 16;
+const FULL_SCALE: i32 = 65535;
+
+/// A Sensirion SHT30, SHT31 or SHT35 on I2C0, SDA on GPIO4 and SCL on GPIO5
+pub struct Sht3x {
+    i2c: I2c<'static, I2C0, Async>,
+}
+
+impl Sht3x {
+    pub fn new(i2c: I2C0, sda: PIN_4, scl: PIN_5) -> Self {
+        Self {
+            i2c: I2c::new_async(i2c, scl, sda, Irqs, i2c::Config::default()),
+        }
+    }
+
+    pub async fn measure(&mut self) -> Option<Climate> {
+        if let Err(error) = self.i2c.write_async(ADDRESS, MEASURE).await {
+            warn!("SHT3x: {:?}", error);
+            return None;
+        }
+        Timer::after_millis(MEASUREMENT_TIME_MS).await;
+        // Temperature and humidity, each followed by its CRC
+        let mut data = [0u8; 6];
+        if let Err(error) = self.i2c.read_async(ADDRESS, &mut data).await {
+            warn!("SHT3x: {:?}", error);
+            return None;
+        }
+        if crc8(&data[0..2]) != data[2] || crc8(&data[3..5]) != data[5] {
+            warn!("SHT3x: CRC error");
+            return None;
+        }
+        let temperature = u16::from_be_bytes([data[0], data[1]]) as i32;
+        let humidity = u16::from_be_bytes([data[3], data[4]]) as i32;
+        // From the datasheet: T = -45 °C + 175 °C * raw / (2^16 - 1), RH = 100 % * raw / (2^16 - 1)
+        Some(Climate {
+            temperature_decicelsius: (-450 + 1750 * temperature / FULL_SCALE) as i16,
+            humidity_permille: Some((1000 * humidity / FULL_SCALE) as u16),
+        })
+    }
+}
+
+/// The Sensirion CRC-8 (polynomial 0x31, starting at 0xFF)
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0xFF, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x31,
+            };
+        }
+        crc
+    })
+}
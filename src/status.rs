@@ -0,0 +1,43 @@
+use embassy_time::Instant;
+use powermeter_payload::Status;
+
+use crate::config::Config;
+use crate::network::RadioState;
+use crate::{airtime, link_check, policy};
+
+const FIRMWARE_VERSION: [u8; 3] = [
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+/// Collects the information about the device for the status uplink
+pub fn build(config: &Config, radio: &RadioState) -> Status {
+    let link_quality = policy::link_quality();
+    let link_check = link_check::last_answer();
+    Status {
+        firmware_version: Some(FIRMWARE_VERSION),
+        uptime_s: Some(Instant::now().as_secs()),
+        measurement_interval_s: Some(config.measurement_interval_s),
+        confirmed_uplinks: Some(link_quality.confirmed_uplinks),
+        unacknowledged_uplinks: Some(link_quality.unacknowledged_uplinks),
+        link_margin_db: link_check.as_ref().map(|answer| answer.margin_db),
+        gateway_count: link_check.map(|answer| answer.gateway_count),
+        datarate: Some(radio.datarate),
+        tx_power_dbm: Some(radio.tx_power_dbm),
+        rssi_dbm: radio.rssi_dbm,
+        snr_db: radio.snr_db,
+        airtime_last_day_ms: Some(airtime::used_last_day().as_millis() as u32),
+    }
+}
+
+const fn parse_version(part: &str) -> u8 {
+    let bytes = part.as_bytes();
+    let mut value: u8 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
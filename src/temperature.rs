@@ -0,0 +1,137 @@
+#[cfg(feature = "cabinet-sensor")]
+use defmt::warn;
+use defmt::{info, Format};
+use embassy_rp::adc::{Adc, Async, Channel as AdcChannel};
+use embassy_rp::peripherals::ADC_TEMP_SENSOR;
+use embassy_time::Timer;
+#[cfg(feature = "cabinet-sensor")]
+use embassy_time::{with_timeout, Duration};
+
+#[cfg(feature = "sensor-bme280")]
+pub use crate::bme280::Bme280 as CabinetSensor;
+use crate::config::Config;
+#[cfg(feature = "sensor-ds18b20")]
+pub use crate::ds18b20::Ds18b20 as CabinetSensor;
+#[cfg(feature = "sensor-sht3x")]
+pub use crate::sht3x::Sht3x as CabinetSensor;
+use crate::{adc_microvolts, median};
+
+#[cfg(any(
+    all(feature = "sensor-ds18b20", feature = "sensor-sht3x"),
+    all(feature = "sensor-ds18b20", feature = "sensor-bme280"),
+    all(feature = "sensor-sht3x", feature = "sensor-bme280")
+))]
+compile_error!("Multiple cabinet sensors selected. Choose one of the sensor-* features.");
+#[cfg(all(
+    feature = "cabinet-sensor",
+    not(any(
+        feature = "sensor-ds18b20",
+        feature = "sensor-sht3x",
+        feature = "sensor-bme280"
+    ))
+))]
+compile_error!(
+    "The cabinet-sensor feature is enabled by the sensor-* features. Enable one of those instead."
+);
+
+const SAMPLE_COUNT: usize = 10;
+// According to chapter 4.9.5 Temperature Sensor in the RP2040 datasheet: 706 mV at 27 °C, falling by 1.721 mV per °C
+const SENSOR_REFERENCE_DECICELSIUS: i64 = 270;
+const SENSOR_MICROVOLTS_AT_REFERENCE: i64 = 706_000;
+const SENSOR_NANOVOLTS_PER_DECICELSIUS: i64 = 172_100;
+// The DS18B20 takes the longest, 750 ms for a conversion
+#[cfg(feature = "cabinet-sensor")]
+const CABINET_SENSOR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The temperature and, if the sensor measures it, the relative humidity
+#[derive(Clone, Copy, Format)]
+pub struct Climate {
+    pub temperature_decicelsius: i16,
+    pub humidity_permille: Option<u16>,
+}
+
+/// Measures the temperature the measurements report. With an external sensor (one of the sensor-* features), that is
+/// the cabinet's. Otherwise it is the RP2040's own, calibrated by the configured offset and slope.
+pub struct Thermometer {
+    internal: AdcChannel<'static>,
+    #[cfg(feature = "cabinet-sensor")]
+    cabinet: CabinetSensor,
+}
+
+impl Thermometer {
+    #[cfg(not(feature = "cabinet-sensor"))]
+    pub fn new(internal: ADC_TEMP_SENSOR) -> Self {
+        Self {
+            internal: AdcChannel::new_temp_sensor(internal),
+        }
+    }
+
+    #[cfg(feature = "cabinet-sensor")]
+    pub fn new(internal: ADC_TEMP_SENSOR, cabinet: CabinetSensor) -> Self {
+        Self {
+            internal: AdcChannel::new_temp_sensor(internal),
+            cabinet,
+        }
+    }
+
+    /// The values to report, if the sensor responded
+    pub async fn measure(
+        &mut self,
+        adc: &mut Adc<'static, Async>,
+        config: &Config,
+    ) -> Option<Climate> {
+        let internal = self.internal_decicelsius(adc, config).await;
+        info!("internal temperature: {:?} dC", internal);
+
+        // A cabinet sensor that does not respond is reported by leaving the values out. The chip's temperature would
+        // be mistaken for the cabinet's.
+        #[cfg(feature = "cabinet-sensor")]
+        {
+            let climate = with_timeout(CABINET_SENSOR_TIMEOUT, self.cabinet.measure())
+                .await
+                .ok()
+                .flatten();
+            match climate {
+                Some(climate) => info!("cabinet: {:?}", climate),
+                None => warn!("The cabinet sensor did not respond"),
+            }
+            climate
+        }
+        #[cfg(not(feature = "cabinet-sensor"))]
+        Some(Climate {
+            temperature_decicelsius: internal,
+            humidity_permille: None,
+        })
+    }
+
+    /// The calibrated temperature of the RP2040, in 0.1 °C. The sensor's voltage is converted with the configured ADC
+    /// reference, the datasheet assumes exactly 3.3 V.
+    async fn internal_decicelsius(
+        &mut self,
+        adc: &mut Adc<'static, Async>,
+        config: &Config,
+    ) -> i16 {
+        let mut samples = [0u16; SAMPLE_COUNT];
+        for sample in samples.iter_mut() {
+            *sample = adc.read(&mut self.internal).await.unwrap();
+            //Sampling delay
+            Timer::after_millis(50).await;
+        }
+        let microvolts = adc_microvolts(median(&mut samples), config) as i64;
+        let uncalibrated = SENSOR_REFERENCE_DECICELSIUS
+            - divide_rounded(
+                (microvolts - SENSOR_MICROVOLTS_AT_REFERENCE) * 1000,
+                SENSOR_NANOVOLTS_PER_DECICELSIUS,
+            );
+        let calibrated = divide_rounded(
+            uncalibrated * config.temperature_slope_permille as i64,
+            1000,
+        ) + config.temperature_offset_decicelsius as i64;
+        calibrated.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+}
+
+/// Divides, rounding to the nearest integer instead of towards zero
+fn divide_rounded(dividend: i64, divisor: i64) -> i64 {
+    (dividend + dividend.signum() * divisor / 2) / divisor
+}
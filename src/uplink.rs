@@ -0,0 +1,69 @@
+use defmt::{info, warn};
+use powermeter_payload::{Measurement, Uplink, MAX_PAYLOAD_SIZE};
+
+/// The measurement as it is going to be sent
+pub struct BuiltUplink {
+    pub size: usize,
+    pub ack_included: bool, // Whether the command acknowledgement made it in
+    pub complete: bool,     // Whether all values fit
+    pub reset_reason_included: bool,
+}
+
+/// Builds the uplinks from the measurements, leaving out what does not fit the payload size the data rate allows.
+/// The message format itself is defined in the powermeter-payload crate.
+#[derive(Default)]
+pub struct UplinkBuilder {
+    next_field: usize, // If not everything fit last time, we start with the fields that were left out
+}
+
+impl UplinkBuilder {
+    /// Encodes the measurement into the buffer. If not all fields fit, the ones left out are sent first in the next
+    /// uplink.
+    pub fn build(
+        &mut self,
+        mut measurement: Measurement,
+        max_payload: usize,
+        buf: &mut [u8; MAX_PAYLOAD_SIZE],
+    ) -> Option<BuiltUplink> {
+        // Choose the fields to send, starting with the ones left out last time
+        let mut presence: u16 = 0;
+        let mut size = Measurement::HEADER_SIZE;
+        let mut left_out = None;
+        for offset in 0..Measurement::FIELD_COUNT {
+            let index = (self.next_field + offset) % Measurement::FIELD_COUNT;
+            let field_size = measurement.field_size(index);
+            if field_size == 0 {
+                continue;
+            }
+            if size + field_size > max_payload {
+                left_out = Some(index);
+                break;
+            }
+            presence |= 1 << index;
+            size += field_size;
+        }
+        if presence == 0 {
+            warn!("Data rate too low to send anything!");
+            return None;
+        }
+        match left_out {
+            Some(index) => {
+                info!("Not all values fit the data rate, continuing next time.");
+                self.next_field = index;
+            }
+            None => self.next_field = 0,
+        }
+
+        measurement.retain(presence);
+        let ack_included = measurement.command_ack.is_some();
+        let reset_reason_included = measurement.reset_reason.is_some();
+        let size = Uplink::Measurement(measurement).encode(buf).unwrap();
+        info!("Sending measurement ({:?} bytes)", size);
+        Some(BuiltUplink {
+            size,
+            ack_included,
+            complete: left_out.is_none(),
+            reset_reason_included,
+        })
+    }
+}
@@ -0,0 +1,178 @@
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn, Format};
+use embassy_executor::Spawner;
+use embassy_rp::pac;
+use embassy_rp::peripherals::WATCHDOG;
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::AtomicU64;
+use powermeter_payload::{Crash, ResetReason, S0_CHANNEL_COUNT};
+
+use crate::crash;
+
+// The bootloader leaves the watchdog running with this timeout, which is about the longest the RP2040 supports
+const TIMEOUT: Duration = Duration::from_secs(8);
+const FEED_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long each task may take between check-ins, on top of any waiting it announced. The main loop does a lot between
+// two check-ins: reading the meter, sending several uplinks, erasing the DFU partition.
+const MAIN_LOOP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// Sending an uplink and receiving in both windows takes less than 15 s, even at the lowest data rate
+const RADIO_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the counter tasks check in while there are no impulses
+pub const COUNTER_CHECK_IN_INTERVAL: Duration = Duration::from_secs(10);
+const COUNTER_TIMEOUT: Duration = Duration::from_secs(30);
+// Time for the main task to initialize the radio and flash before it checks in for the first time
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The reason for a restart we initiated ourselves is left in the watchdog scratch registers, which survive a reset
+// (but not a power cycle). The first one marks the second one as valid.
+const SCRATCH_MAGIC: u32 = 0x5245_5354;
+const SCRATCH_MAGIC_INDEX: usize = 0;
+const SCRATCH_REASON_INDEX: usize = 1;
+
+const TASK_COUNT: usize = 2 + S0_CHANNEL_COUNT;
+// Never expected to check in, e.g. while the radio is idle
+const NO_DEADLINE: u64 = u64::MAX;
+// When each task has to check in next, in ticks since boot
+static DEADLINES: [AtomicU64; TASK_COUNT] =
+    [const { AtomicU64::new(STARTUP_TIMEOUT.as_ticks()) }; TASK_COUNT];
+
+// Shared between the supervisor, which feeds it, and whoever restarts the device
+static WATCHDOG: Mutex<CriticalSectionRawMutex, RefCell<Option<Watchdog>>> =
+    Mutex::new(RefCell::new(None));
+
+/// The parts of the firmware the supervisor waits for before feeding the watchdog
+#[derive(Clone, Copy, Format)]
+pub enum Task {
+    MainLoop,
+    /// Only while the radio is busy, it is [`idle`] otherwise
+    Radio,
+    Counter(usize),
+}
+
+impl Task {
+    fn index(self) -> usize {
+        match self {
+            Task::MainLoop => 0,
+            Task::Radio => 1,
+            Task::Counter(channel) => 2 + channel,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            0 => Task::MainLoop,
+            1 => Task::Radio,
+            index => Task::Counter(index - 2),
+        }
+    }
+
+    fn timeout(self) -> Duration {
+        match self {
+            Task::MainLoop => MAIN_LOOP_TIMEOUT,
+            Task::Radio => RADIO_TIMEOUT,
+            Task::Counter(_) => COUNTER_TIMEOUT,
+        }
+    }
+
+    fn stall_reason(self) -> ResetReason {
+        match self {
+            Task::MainLoop => ResetReason::MainLoopStalled,
+            Task::Radio => ResetReason::RadioStalled,
+            Task::Counter(_) => ResetReason::CounterStalled,
+        }
+    }
+}
+
+/// Takes over the watchdog the bootloader started and feeds it as long as all tasks check in on time. If one of them
+/// hangs (or the executor stops), the device restarts, and a firmware update on trial is rolled back. Returns why the
+/// device started this time, and where it crashed if that was the reason.
+pub fn start(spawner: Spawner, watchdog: WATCHDOG) -> (ResetReason, Option<Crash>) {
+    let previous = crash::recover();
+    // Whether the watchdog reset the chip, either because it was not fed or on purpose, and whether the supply voltage
+    // was (re)applied or dropped below the brown-out threshold. embassy-rp does not expose them.
+    let hardware_reason = pac::WATCHDOG.reason().read();
+    let watchdog_reset = hardware_reason.timer() || hardware_reason.force();
+    let power_reset = pac::VREG_AND_CHIP_RESET.chip_reset().read().had_por();
+    let mut watchdog = Watchdog::new(watchdog);
+    watchdog.start(TIMEOUT);
+    let recorded = (watchdog.get_scratch(SCRATCH_MAGIC_INDEX) == SCRATCH_MAGIC)
+        .then(|| ResetReason::from_byte(watchdog.get_scratch(SCRATCH_REASON_INDEX) as u8).ok())
+        .flatten();
+    let (crash_reason, crash) = previous.crash.unzip();
+    let reason = match (crash_reason.or(recorded), watchdog_reset) {
+        (Some(reason), _) => reason,
+        (None, true) => ResetReason::Watchdog,
+        // The supply dropped, but not for long enough to clear the RAM
+        (None, false) if power_reset && previous.ram_retained => ResetReason::BrownOut,
+        // Also the reset pin
+        (None, false) => ResetReason::PowerOn,
+    };
+    // Not to report it again after a reset that is not recorded
+    watchdog.set_scratch(SCRATCH_MAGIC_INDEX, 0);
+    info!("Reset reason: {:?}", reason);
+
+    WATCHDOG.lock(|cell| cell.replace(Some(watchdog)));
+    spawner.spawn(supervisor_task()).unwrap();
+    (reason, crash)
+}
+
+/// Tells the supervisor the task is alive. It has to check in again within its timeout.
+pub fn check_in(task: Task) {
+    check_in_after(task, Duration::from_secs(0));
+}
+
+/// Like [`check_in`], for a task that is about to wait for the given time
+pub fn check_in_after(task: Task, wait: Duration) {
+    let deadline = Instant::now() + wait + task.timeout();
+    DEADLINES[task.index()].store(deadline.as_ticks(), Ordering::Relaxed);
+}
+
+/// The task does not need to check in until its next [`check_in`]
+pub fn idle(task: Task) {
+    DEADLINES[task.index()].store(NO_DEADLINE, Ordering::Relaxed);
+}
+
+/// Restarts the device, reporting the reason with the first measurement afterwards
+pub fn restart(reason: ResetReason) -> ! {
+    record(reason);
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+fn record(reason: ResetReason) {
+    with_watchdog(|watchdog| {
+        watchdog.set_scratch(SCRATCH_REASON_INDEX, reason.to_byte() as u32);
+        watchdog.set_scratch(SCRATCH_MAGIC_INDEX, SCRATCH_MAGIC);
+    });
+}
+
+fn with_watchdog(f: impl FnOnce(&mut Watchdog)) {
+    WATCHDOG.lock(|cell| {
+        if let Some(watchdog) = cell.borrow_mut().as_mut() {
+            f(watchdog);
+        }
+    });
+}
+
+#[embassy_executor::task]
+async fn supervisor_task() -> ! {
+    loop {
+        let now = Instant::now().as_ticks();
+        let stalled = DEADLINES
+            .iter()
+            .position(|deadline| deadline.load(Ordering::Relaxed) < now)
+            .map(Task::from_index);
+        if let Some(task) = stalled {
+            warn!("{:?} stopped checking in, restarting.", task);
+            record(task.stall_reason());
+            with_watchdog(Watchdog::trigger_reset);
+        }
+        with_watchdog(Watchdog::feed);
+        Timer::after(FEED_INTERVAL).await;
+    }
+}